@@ -0,0 +1,162 @@
+//! Benchmarks of the InMemory, JsonFile and CachedJsonFile queues, run with `cargo bench --bench queues`.
+//!
+//! `enqueue_dequeue` measures an enqueue followed by a dequeue on a queue which already holds the given number of elements,
+//! i.e. the steady-state cost of a submission and an assignment. `bulk_load` measures loading the given number of elements
+//! of mixed priorities into an empty queue, either one by one with `enqueue` or at once with `enqueue_many`.
+//! The elements are small jobs of about 100 bytes of JSON.
+//!
+//! Results on a single-core x86-64 VM (median times, bench profile, i.e. release with LTO):
+//!
+//! | enqueue_dequeue | 100 elements | 1000 elements | 10000 elements |
+//! |-----------------|-------------:|--------------:|---------------:|
+//! | InMemory        |      0.28 µs |        1.7 µs |          16 µs |
+//! | JsonFile        |       1.0 ms |        3.7 ms |          49 ms |
+//! | CachedJsonFile  |      0.38 ms |        1.1 ms |          14 ms |
+//!
+//! JsonFile reads, deserializes, serializes and writes the whole file on every operation, and CachedJsonFile still
+//! serializes and writes it, so the cost of both grows linearly with the length of the queue, reaching tens of milliseconds
+//! per job at 10000 elements. The InMemory queue grows linearly as well, but only because an element of the lowest priority
+//! is inserted behind all others, which takes a scan of the queue.
+//!
+//! | bulk_load                    | 100 elements | 1000 elements |
+//! |------------------------------|-------------:|--------------:|
+//! | InMemory, enqueue            |        12 µs |        674 µs |
+//! | InMemory, enqueue_many       |       5.2 µs |         48 µs |
+//! | JsonFile, enqueue            |        42 ms |        1.15 s |
+//! | JsonFile, enqueue_many       |       129 µs |        726 µs |
+//! | CachedJsonFile, enqueue      |        25 ms |        439 ms |
+//! | CachedJsonFile, enqueue_many |       104 µs |        566 µs |
+//!
+//! Since `enqueue_many` serializes and writes the queue once rather than once per element, bulk-loading 1000 elements
+//! into a file queue with it is three orders of magnitude faster than enqueueing them one by one.
+//! Before `enqueue_many` merged the elements into the queue in a single pass (see `merge_by_priority`), it inserted them
+//! one by one, which took 12.6 µs and 1.22 ms on the InMemory queue, 159 µs and 1.73 ms on the JsonFile queue,
+//! and 125 µs and 1.84 ms on the CachedJsonFile queue for 100 and 1000 elements, respectively.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use serde::{Deserialize, Serialize};
+use std::hint::black_box;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+#[path = "../src/queue/mod.rs"]
+#[allow(dead_code, unused_imports)]
+mod queue;
+
+use queue::{CachedJsonFileQueue, InMemoryQueue, JsonFileQueue, Queue, QueueItem, DEFAULT_READ_ATTEMPTS};
+
+/// The queue lengths at which single operations are measured.
+const QUEUE_LENGTHS: [usize; 3] = [100, 1_000, 10_000];
+
+/// The numbers of elements which are bulk-loaded into an empty queue.
+const BULK_SIZES: [usize; 2] = [100, 1_000];
+
+/// An element resembling a small job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Element {
+    id: usize,
+    data: String,
+    priority: u8,
+}
+
+impl QueueItem for Element {
+    fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// Returns the given number of elements, with three different priorities.
+fn elements(count: usize) -> Vec<Element> {
+    (0..count).map(|id| Element { id, data: format!("{{\"drink\": \"mojito\", \"customer\": \"{id}\"}}"), priority: (id % 3) as u8 }).collect()
+}
+
+/// The queue implementations which are compared.
+#[derive(Debug, Clone, Copy)]
+enum Backend {
+    InMemory,
+    JsonFile,
+    CachedJsonFile,
+}
+
+impl Backend {
+    const ALL: [Backend; 3] = [Backend::InMemory, Backend::JsonFile, Backend::CachedJsonFile];
+
+    /// Creates an empty queue of this implementation, stored in a new file in the given directory if it is file-backed.
+    async fn create(self, dir: &Path) -> Queue<Element> {
+        let file = dir.join(format!("{}.json", uuid::Uuid::new_v4()));
+        match self {
+            Backend::InMemory => Box::new(InMemoryQueue::new()),
+            Backend::JsonFile => Box::new(JsonFileQueue::new(file)),
+            Backend::CachedJsonFile => Box::new(CachedJsonFileQueue::new(file, DEFAULT_READ_ATTEMPTS).await.unwrap()),
+        }
+    }
+}
+
+fn enqueue_dequeue(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    let mut group = c.benchmark_group("enqueue_dequeue");
+    group.sample_size(10);
+    for backend in Backend::ALL {
+        for length in QUEUE_LENGTHS {
+            group.bench_function(BenchmarkId::new(format!("{backend:?}"), length), |b| {
+                b.to_async(&runtime).iter_custom(|iters| async move {
+                    let mut queue = backend.create(dir).await;
+                    queue.enqueue_many(elements(length)).await.unwrap();
+                    let start = Instant::now();
+                    for _ in 0..iters {
+                        queue.enqueue(Element { id: length, data: "{}".to_owned(), priority: 0 }).await.unwrap();
+                        black_box(queue.dequeue().await.unwrap());
+                    }
+                    start.elapsed()
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bulk_load(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    let mut group = c.benchmark_group("bulk_load");
+    group.sample_size(10);
+    for backend in Backend::ALL {
+        for size in BULK_SIZES {
+            group.bench_function(BenchmarkId::new(format!("{backend:?}/enqueue"), size), |b| {
+                b.to_async(&runtime).iter_custom(|iters| async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let (mut queue, elements) = (backend.create(dir).await, elements(size));
+                        let start = Instant::now();
+                        for element in elements {
+                            queue.enqueue(element).await.unwrap();
+                        }
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+            });
+            group.bench_function(BenchmarkId::new(format!("{backend:?}/enqueue_many"), size), |b| {
+                b.to_async(&runtime).iter_custom(|iters| async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let (mut queue, elements) = (backend.create(dir).await, elements(size));
+                        let start = Instant::now();
+                        queue.enqueue_many(elements).await.unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, enqueue_dequeue, bulk_load);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+//! Captures the build information reported by `GET /version`:
+//! the commit the service is built from, if built from a git checkout, and the time of the build.
+
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT_HASH={}", commit.trim());
+    }
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    // Rebuilt whenever the checked out commit or the sources change, so that both values stay current
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+}
@@ -0,0 +1,60 @@
+//! Maintenance operations on the queues.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use crate::queue::{Queue, QueueItem, QueueResult};
+use crate::AppState;
+use crate::error::ApiError;
+
+/// The response to a compaction request.
+#[derive(Debug, Serialize)]
+pub enum CompactResponse {
+    /// All queues were compacted. The total number of bytes reclaimed is provided.
+    Compacted { reclaimed_bytes: u64 },
+}
+
+/// POST /admin/compact
+/// Compacts the storage of all queues, i.e. the job and worker queues including those of the topics,
+/// the assigned and scheduled jobs, and the dead-letter queue, so that it only holds the current elements.
+/// This matters for the JsonlFile mode, whose files accumulate a line for every removed or updated element
+/// until they are compacted automatically; the other modes hold no such data, and reclaim nothing.
+/// Responds with 200 OK and the total number of bytes reclaimed, e.g. `{"Compacted": {"reclaimed_bytes": 5120}}`.
+/// If a queue could not be compacted, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+#[rustfmt::skip]
+pub async fn compact_queues(
+    State(state): State<AppState>
+) -> Result<(StatusCode, Json<CompactResponse>), ApiError> {
+    match compact_all(&state).await {
+        Ok(reclaimed_bytes) => {
+            info!(reclaimed_bytes, "Compacted queues");
+            Ok((StatusCode::OK, Json(CompactResponse::Compacted { reclaimed_bytes })))
+        },
+        Err(err) => {
+            // The queues compacted before the failing one stay compacted
+            error!("Failed to compact queues: '{err}'");
+            Err(err.into())
+        },
+    }
+}
+
+/// Compacts all queues one after another, and returns the total number of bytes reclaimed.
+/// Each queue is only locked while it is compacted.
+async fn compact_all(state: &AppState) -> QueueResult<u64> {
+    let mut reclaimed = compact(&state.job_queue).await? + compact(&state.worker_queue).await?;
+    for topic_state in state.topic_states().await {
+        reclaimed += compact(&topic_state.job_queue).await? + compact(&topic_state.worker_queue).await?;
+    }
+    reclaimed += compact(&state.assigned_jobs).await?;
+    reclaimed += compact(&state.scheduled_jobs).await?;
+    reclaimed += compact(&state.dead_letter_jobs).await?;
+    Ok(reclaimed)
+}
+
+/// Compacts the given queue, and returns the number of bytes reclaimed.
+async fn compact<T: QueueItem>(queue: &Mutex<Queue<T>>) -> QueueResult<u64> {
+    queue.lock().await.compact().await
+}
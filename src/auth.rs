@@ -0,0 +1,64 @@
+//! API key authentication.
+
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+use tracing::info;
+use crate::error::{ApiError, ErrorCode};
+
+/// Middleware which only lets requests through that provide one of the given API keys
+/// as a bearer token in the Authorization header, i.e. `Authorization: Bearer <key>`.
+/// Other requests are rejected with 401 Unauthorized and "Unauthorized".
+pub async fn require_api_key(
+    State(api_keys): State<Arc<[String]>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .is_some_and(|key| api_keys.iter().any(|api_key| api_key == key));
+    if !authorized {
+        info!("Rejecting unauthenticated request to {}", request.uri());
+        let err = ApiError::new(StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "A valid API key is required");
+        return ([(header::WWW_AUTHENTICATE, "Bearer")], err).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::serve;
+    use axum::{middleware, routing::get, Router};
+
+    #[tokio::test]
+    async fn only_requests_with_a_configured_api_key_are_let_through() {
+        let api_keys: Arc<[String]> = vec!["first".to_owned(), "second".to_owned()].into();
+        let routes = Router::new()
+            .route("/jobs", get(|| async { "jobs" }))
+            .route_layer(middleware::from_fn_with_state(api_keys, require_api_key));
+        let url = format!("{}jobs", serve(routes).await);
+        let client = reqwest::Client::new();
+
+        for authorization in [None, Some("Bearer third"), Some("Basic second"), Some("second")] {
+            let mut request = client.get(&url);
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let response = request.send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{authorization:?}");
+            assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "Unauthorized");
+        }
+        for key in ["first", "second"] {
+            let response = client.get(&url).bearer_auth(key).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.text().await.unwrap(), "jobs");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Restrictions on the hosts which workers may use in their callback URL.
+
+use ipnet::IpNet;
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::lookup_host;
+use tracing::info;
+
+/// A host name or a range of IP addresses in the callback allowlist or denylist.
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    /// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8`. A single address stands for itself.
+    Network(IpNet),
+    /// A host name, which matches the name itself and all of its subdomains.
+    Name(String),
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Ok(network) = pattern.parse::<IpNet>() {
+            return Ok(Self::Network(network));
+        }
+        if let Ok(address) = pattern.parse::<IpAddr>() {
+            return Ok(Self::Network(IpNet::from(address)));
+        }
+        if pattern.is_empty() || pattern.contains(['/', ':']) {
+            return Err(format!("'{pattern}' is neither a host name nor an IP address or range"));
+        }
+        Ok(Self::Name(pattern.trim_end_matches('.').to_ascii_lowercase()))
+    }
+}
+
+impl HostPattern {
+    /// Returns whether the given host name is this name or one of its subdomains.
+    fn matches_name(&self, host: &str) -> bool {
+        match self {
+            Self::Name(name) => host == name || host.strip_suffix(name.as_str()).is_some_and(|sub| sub.ends_with('.')),
+            Self::Network(_) => false,
+        }
+    }
+
+    /// Returns whether the given address lies in this range.
+    fn matches_address(&self, address: IpAddr) -> bool {
+        match self {
+            Self::Network(network) => network.contains(&address),
+            Self::Name(_) => false,
+        }
+    }
+}
+
+/// The allowlist and denylist of hosts which callback URLs may point to.
+/// Because the service sends job data to whichever URL a worker registers with, these lists keep workers
+/// from making the service send requests to internal services, such as cloud metadata endpoints.
+#[derive(Debug, Default)]
+pub struct CallbackFilter {
+    allow: Vec<HostPattern>,
+    deny: Vec<HostPattern>,
+    /// The address the service listens on, used to reject callback URLs which point back at the service.
+    own_address: Option<SocketAddr>,
+}
+
+impl CallbackFilter {
+    /// Creates a new CallbackFilter. If `allow` is empty, every host which is not denied is allowed.
+    pub fn new(allow: Vec<HostPattern>, deny: Vec<HostPattern>) -> Self {
+        Self { allow, deny, own_address: None }
+    }
+
+    /// Sets the address the service listens on, so that callback URLs pointing at it can be detected.
+    pub fn with_own_address(mut self, address: SocketAddr) -> Self {
+        self.own_address = Some(address);
+        self
+    }
+
+    /// Returns whether the given URL points at the service itself, i.e. whether its port is the one the service listens on
+    /// and its host is the address the service is bound to. If the service is bound to all interfaces,
+    /// loopback hosts such as `localhost` count as well; other addresses of the machine's interfaces are not detected.
+    /// Host names are only resolved if the port matches. If such a name cannot be resolved, the URL is not considered to point at the service.
+    pub async fn points_to_service(&self, url: &Url) -> bool {
+        let Some(own_address) = self.own_address else {
+            return false;
+        };
+        if url.port_or_known_default() != Some(own_address.port()) {
+            return false;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+        let Some(addresses) = resolve(&host, own_address.port()).await else {
+            return false;
+        };
+        let own_ip = own_address.ip().to_canonical();
+        addresses.iter().any(|&address| {
+            address == own_ip || (own_ip.is_unspecified() && (address.is_loopback() || address.is_unspecified()))
+        })
+    }
+
+    /// Returns whether callbacks may be sent to the host of the given URL.
+    /// A host name is allowed if it matches an allowed name, or if every address it resolves to is in an allowed range;
+    /// it is denied if it matches a denied name, or if any address it resolves to is in a denied range.
+    /// Host names are only resolved if their addresses need to be checked against a range. If such a name cannot be resolved, it is denied.
+    pub async fn allows(&self, url: &Url) -> bool {
+        if self.allow.is_empty() && self.deny.is_empty() {
+            return true;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        // IPv6 addresses are enclosed in brackets in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_ascii_lowercase();
+        if self.deny.iter().any(|pattern| pattern.matches_name(&host)) {
+            return false;
+        }
+        let allowed_by_name = self.allow.is_empty() || self.allow.iter().any(|pattern| pattern.matches_name(&host));
+        let is_range = |pattern: &HostPattern| matches!(pattern, HostPattern::Network(_));
+        let needs_addresses = self.deny.iter().any(is_range) || (!allowed_by_name && self.allow.iter().any(is_range));
+        if !needs_addresses {
+            return allowed_by_name;
+        }
+        let Some(addresses) = resolve(&host, url.port_or_known_default().unwrap_or(80)).await else {
+            return false;
+        };
+        if addresses.iter().any(|&address| self.deny.iter().any(|pattern| pattern.matches_address(address))) {
+            return false;
+        }
+        allowed_by_name
+            || addresses.iter().all(|&address| self.allow.iter().any(|pattern| pattern.matches_address(address)))
+    }
+}
+
+/// Returns the addresses of the given host, which may already be an IP address.
+/// IPv4 addresses mapped into IPv6 are converted back, so that they match IPv4 ranges.
+/// Returns None if the host cannot be resolved to any address.
+async fn resolve(host: &str, port: u16) -> Option<Vec<IpAddr>> {
+    if let Ok(address) = host.parse::<IpAddr>() {
+        return Some(vec![address.to_canonical()]);
+    }
+    let addresses: Vec<IpAddr> = lookup_host((host, port))
+        .await
+        .inspect_err(|err| info!("Failed to resolve callback host {host}: {err}"))
+        .ok()?
+        .map(|address| address.ip().to_canonical())
+        .collect();
+    (!addresses.is_empty()).then_some(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a filter with the given comma-separated allowlist and denylist.
+    fn filter(allow: &str, deny: &str) -> CallbackFilter {
+        let patterns = |list: &str| list.split(',').filter(|pattern| !pattern.is_empty()).map(|pattern| pattern.parse().unwrap()).collect();
+        CallbackFilter::new(patterns(allow), patterns(deny))
+    }
+
+    /// Returns whether the filter allows callbacks to the given URL.
+    async fn allows(filter: &CallbackFilter, url: &str) -> bool {
+        filter.allows(&Url::parse(url).unwrap()).await
+    }
+
+    #[test]
+    fn patterns_are_ranges_or_names() {
+        assert!(matches!("10.0.0.0/8".parse(), Ok(HostPattern::Network(network)) if network.prefix_len() == 8));
+        assert!(matches!("169.254.169.254".parse(), Ok(HostPattern::Network(network)) if network.prefix_len() == 32));
+        assert!(matches!("fd00::/8".parse(), Ok(HostPattern::Network(_))));
+        assert!(matches!("Workers.Example.".parse(), Ok(HostPattern::Name(name)) if name == "workers.example"));
+        for invalid in ["", "10.0.0.0/33", "example.com/jobs", "example.com:80"] {
+            assert!(invalid.parse::<HostPattern>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_ranges_block_metadata_and_private_addresses() {
+        let filter = filter("", "169.254.0.0/16,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,127.0.0.0/8,::1,fc00::/7");
+        let denied = [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.1.2.3:8080/jobs",
+            "http://172.31.255.255/",
+            "https://192.168.0.10/",
+            "http://127.0.0.1:9000/",
+            "http://[::1]:9000/",
+            "http://[fd12::1]/",
+            // IPv4 addresses mapped into IPv6 are checked as IPv4 addresses
+            "http://[::ffff:169.254.169.254]/",
+            // Other notations of the same address are normalized when the URL is parsed
+            "http://2852039166/",
+            "http://0xa9.0xfe.0xa9.0xfe/",
+        ];
+        for url in denied {
+            assert!(!allows(&filter, url).await, "{url}");
+        }
+        for url in ["http://192.0.2.1/jobs", "http://172.32.0.1/", "http://[2001:db8::1]/"] {
+            assert!(allows(&filter, url).await, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn allowlists_admit_only_listed_hosts() {
+        let filter = filter("workers.example,192.0.2.0/24", "blocked.workers.example");
+        for url in ["http://workers.example/", "https://gpu.workers.example:8443/jobs", "http://192.0.2.7/"] {
+            assert!(allows(&filter, url).await, "{url}");
+        }
+        for url in ["http://blocked.workers.example/", "http://otherworkers.example/", "http://198.51.100.1/"] {
+            assert!(!allows(&filter, url).await, "{url}");
+        }
+        // Without any lists, every host is allowed
+        assert!(allows(&CallbackFilter::default(), "http://169.254.169.254/").await);
+    }
+}
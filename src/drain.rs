@@ -0,0 +1,64 @@
+//! Draining the job queue to the workers before shutting down.
+
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::iter;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{error, info};
+use crate::AppState;
+use crate::error::{ApiError, ErrorCode};
+
+/// The interval at which the job queues are checked for being empty while draining.
+const DRAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns true if the service is draining, i.e. it received a shutdown signal with `--drain-on-shutdown`.
+pub fn is_draining(state: &AppState) -> bool {
+    state.draining.load(Ordering::Relaxed)
+}
+
+/// Middleware which rejects job submissions with 503 Service Unavailable and "Draining" while the service is draining,
+/// so that the job queue only gets shorter until the service shuts down.
+pub async fn reject_while_draining(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if is_draining(&state) {
+        info!("Rejecting submission to {} while draining", request.uri());
+        let message = "The service is shutting down and only dispatches the jobs which are already queued";
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::Draining, message).into_response();
+    }
+    next.run(request).await
+}
+
+/// Stops accepting submissions, and completes once every queued job was dispatched to a worker,
+/// i.e. once the global job queue and the job queues of all topics are empty.
+/// Workers keep registering as usual in the meantime, so they pull the queued jobs.
+/// Scheduled and in-flight jobs are not waited for; they stay persisted for the next start.
+pub async fn drain(state: &AppState) {
+    state.draining.store(true, Ordering::Relaxed);
+    let mut ticker = tokio::time::interval(DRAIN_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let mut pending = Vec::new();
+        for job_state in iter::once(state.clone()).chain(state.topic_states().await) {
+            let name = job_state.topic.as_deref().unwrap_or("global").to_owned();
+            match job_state.job_queue.lock().await.is_empty().await {
+                Ok(true) => {},
+                Ok(false) => pending.push(name),
+                Err(err) => {
+                    error!("Failed to read the {name} job queue while draining: '{err}'");
+                    pending.push(name);
+                },
+            }
+        }
+        if pending.is_empty() {
+            info!("Job queues drained");
+            return;
+        }
+        info!("Draining, jobs left in the {} job queue(s)...", pending.join(", "));
+    }
+}
@@ -0,0 +1,104 @@
+//! The envelope shared by the error responses of all endpoints, e.g.
+//! `{"error": {"code": "QueueFull", "message": "The job queue is full"}}`,
+//! so that clients can handle the errors of every endpoint the same way.
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use crate::queue::QueueError;
+use crate::worker::CallbackHeaderError;
+
+/// The kind of error which made a request fail, reported as the `code` of an [`ApiError`].
+/// Clients should rely on the code rather than on the message, which is meant for humans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ErrorCode {
+    /// The topic name of the request is invalid.
+    InvalidTopic,
+    /// The submitted job is invalid, e.g. because it does not satisfy the configured JSON Schema.
+    Invalid,
+    /// The submitted job has the id of a known job.
+    DuplicateId,
+    /// The requested job or worker does not exist.
+    NotFound,
+    /// The queue which would have to hold the job or worker is full.
+    QueueFull,
+    /// No worker was available for a job submitted with `mode=nowait`.
+    NoWorkerAvailable,
+    /// Too many workers failed to accept the job, and it was moved to the dead-letter queue.
+    DeadLettered,
+    /// The result callback URL of a job could not be reached or responded with a non-2xx code.
+    CallbackFailed,
+    /// A queue could not be read or persisted.
+    PersistenceFailed,
+    /// The request lacks a valid API key.
+    Unauthorized,
+    /// The client exceeded its rate limit.
+    RateLimited,
+    /// The service is draining the job queue before shutting down, and accepts no further jobs.
+    Draining,
+    /// The service runs with `--read-only`, and rejects every request which would change the queues.
+    ReadOnly,
+    /// The callback URL or another header of a worker request is invalid.
+    /// The code is that of the [`CallbackHeaderError`], e.g. `Missing` or `UnsupportedScheme`.
+    #[serde(untagged)]
+    Callback(CallbackHeaderError),
+}
+
+/// The body of an error response: the kind of error and a human-readable description of it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorDetails {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// An error response of any endpoint, serialized as `{"error": {"code": ..., "message": ...}}`
+/// and sent with its status code.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    error: ErrorDetails,
+}
+
+impl ApiError {
+    /// Creates a new ApiError with the given status code, error code and message.
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { status, error: ErrorDetails { code, message: message.into() } }
+    }
+
+    /// Returns the kind of this error.
+    pub fn code(&self) -> ErrorCode {
+        self.error.code
+    }
+
+    /// Creates a 404 Not Found error with the given message.
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// An invalid worker request is rejected with 400 Bad Request, and the code of the [`CallbackHeaderError`].
+impl From<CallbackHeaderError> for ApiError {
+    fn from(err: CallbackHeaderError) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, ErrorCode::Callback(err), err.to_string())
+    }
+}
+
+/// A failed queue operation is reported with the status of [`QueueError::status_code`]:
+/// a full queue as "QueueFull", and every other error as "PersistenceFailed".
+/// The details of the error are only logged, not sent to the client.
+impl From<QueueError> for ApiError {
+    fn from(err: QueueError) -> Self {
+        match err {
+            QueueError::Full => Self::new(err.status_code(), ErrorCode::QueueFull, "The queue is full"),
+            err => Self::new(err.status_code(), ErrorCode::PersistenceFailed, "The queue could not be read or persisted"),
+        }
+    }
+}
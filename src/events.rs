@@ -0,0 +1,69 @@
+//! Live job events for dashboards, streamed via Server-Sent Events.
+
+use axum::extract::State;
+use axum::response::sse::{self, KeepAlive, Sse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tracing::warn;
+use uuid::Uuid;
+use crate::AppState;
+
+/// The number of events buffered for each subscriber.
+/// Subscribers which fall further behind miss the oldest events instead of slowing down the handlers.
+pub const CAPACITY: usize = 1024;
+
+/// Something that happened to a job.
+#[derive(Debug, Clone, Serialize)]
+pub enum JobEvent {
+    /// The job was submitted.
+    Submitted { id: Uuid },
+    /// The job was assigned to the worker with the given callback URL.
+    Assigned { id: Uuid, callback_url: String },
+    /// No worker was available, and the job was queued at the given position.
+    Queued { id: Uuid, position: usize },
+    /// The worker with the given callback URL failed to accept the job.
+    DispatchFailed { id: Uuid, callback_url: String },
+    /// The job waited in the job queue longer than its time-to-live, and was discarded.
+    Expired { id: Uuid },
+}
+
+/// A job event along with the time at which it happened.
+#[derive(Debug, Clone, Serialize)]
+pub struct TimedEvent {
+    pub event: JobEvent,
+    pub at: DateTime<Utc>,
+}
+
+/// Creates the channel through which events are published.
+pub fn channel() -> broadcast::Sender<TimedEvent> {
+    broadcast::channel(CAPACITY).0
+}
+
+/// Publishes an event to all subscribers of [`events`].
+/// Never blocks: if there are no subscribers, the event is dropped.
+pub fn publish(state: &AppState, event: JobEvent) {
+    let _ = state.events.send(TimedEvent { event, at: Utc::now() });
+}
+
+/// GET /events
+/// Streams an event each time a job is submitted, assigned, or queued, or a worker fails to accept it.
+/// Each event is sent as a Server-Sent Event whose data is a JSON object like
+/// `{"event": {"Queued": {"id": <id>, "position": 3}}, "at": <time>}`.
+/// A subscriber which falls more than [`CAPACITY`] events behind skips the events it missed.
+/// # Panics
+/// This function panics if the serialization impl for TimedEvent fails.
+pub async fn events(State(state): State<AppState>) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(|event| match event {
+        Ok(event) => Some(Ok(sse::Event::default().json_data(event).unwrap())),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            warn!("Event subscriber is lagging behind, skipping {missed} event(s)");
+            None
+        },
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,97 @@
+//! Liveness and readiness probes, and the build information for verifying which build is deployed.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use tracing::error;
+use crate::{drain, AppState};
+
+/// The response to a liveness probe.
+#[derive(Debug, Serialize)]
+pub enum HealthResponse {
+    /// The service is running and able to respond to requests.
+    Healthy,
+}
+
+/// The response to a readiness probe.
+#[derive(Debug, Serialize)]
+pub enum ReadinessResponse {
+    /// All queues are usable, so the service can accept traffic.
+    Ready,
+    /// At least one of the queues is not usable. The unusable queues are listed.
+    Unavailable(Vec<&'static str>),
+    /// The service is draining the job queue before shutting down, and no longer accepts submissions.
+    Draining,
+}
+
+/// The build information of the running service.
+#[derive(Debug, Serialize)]
+pub struct VersionResponse {
+    /// The version of the crate, e.g. `1.0.0`.
+    pub version: &'static str,
+    /// The hash of the git commit the service was built from, or `None` if it was not built from a git checkout.
+    pub commit: Option<&'static str>,
+    /// The time of the build in RFC 3339 format, e.g. `2025-03-01T12:00:00Z`.
+    pub built_at: &'static str,
+}
+
+/// GET /health
+/// Liveness probe: responds with 200 OK and "Healthy" as long as the service is running.
+pub async fn health() -> (StatusCode, Json<HealthResponse>) {
+    (StatusCode::OK, Json(HealthResponse::Healthy))
+}
+
+/// GET /version
+/// Responds with 200 OK and the version, commit hash and build time of the running service,
+/// e.g. `{"version": "1.0.0", "commit": "c5c0223...", "built_at": "2025-03-01T12:00:00Z"}`.
+pub async fn version() -> (StatusCode, Json<VersionResponse>) {
+    (StatusCode::OK, Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("GIT_COMMIT_HASH"),
+        built_at: env!("BUILD_TIMESTAMP"),
+    }))
+}
+
+/// GET /ready
+/// Readiness probe: verifies that the storage backing all queues is usable,
+/// i.e. the job and worker queues, the assigned and scheduled jobs, and the dead-letter queue,
+/// e.g. that the queue files are writable or that the database responds.
+/// Responds with 200 OK and "Ready" if all queues are usable,
+/// otherwise with 503 Service Unavailable and the names of the unusable queues.
+/// While the service is draining before shutting down, it responds with 503 Service Unavailable and "Draining",
+/// so that load balancers stop sending submissions.
+#[rustfmt::skip]
+pub async fn ready(
+    State(state): State<AppState>
+) -> (StatusCode, Json<ReadinessResponse>) {
+    if drain::is_draining(&state) {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(ReadinessResponse::Draining));
+    }
+    let mut unavailable = Vec::new();
+    if let Err(err) = state.job_queue.lock().await.check().await {
+        error!("Readiness check failed for the job queue: '{err}'");
+        unavailable.push("jobs");
+    }
+    if let Err(err) = state.worker_queue.lock().await.check().await {
+        error!("Readiness check failed for the worker queue: '{err}'");
+        unavailable.push("workers");
+    }
+    if let Err(err) = state.assigned_jobs.lock().await.check().await {
+        error!("Readiness check failed for the assigned jobs: '{err}'");
+        unavailable.push("assigned_jobs");
+    }
+    if let Err(err) = state.scheduled_jobs.lock().await.check().await {
+        error!("Readiness check failed for the scheduled jobs: '{err}'");
+        unavailable.push("scheduled_jobs");
+    }
+    if let Err(err) = state.dead_letter_jobs.lock().await.check().await {
+        error!("Readiness check failed for the dead-letter queue: '{err}'");
+        unavailable.push("dead_letter_jobs");
+    }
+    if unavailable.is_empty() {
+        (StatusCode::OK, Json(ReadinessResponse::Ready))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(ReadinessResponse::Unavailable(unavailable)))
+    }
+}
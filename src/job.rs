@@ -1,48 +1,385 @@
 //! Job submission and processing.
 
-use axum::extract::State;
-use axum::http::StatusCode;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
 use axum::Json;
-use chrono::{DateTime, Utc};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, TimeDelta, Utc};
+use derive_more::{Display, FromStr};
+use metrics::{counter, histogram};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{error, info};
+use std::collections::{BTreeMap, HashSet};
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+use reqwest::Url;
 use uuid::Uuid;
-use crate::AppState;
-use crate::worker::Worker;
+use crate::{AppState, ClearQueueResponse, ListQuery};
+use crate::error::{ApiError, ErrorCode};
+use crate::queue::{Identifiable, Predicate, QueueError, QueueItem, QueueResult};
+use crate::events::{self, JobEvent};
+use crate::telemetry::{self, Stats};
+use crate::worker::{self, JobOffer, Worker};
+use crate::topic;
 
 /// A job to be processed by a worker.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Job {
     /// The unique identifier of the job.
     pub id: Uuid,
     /// The data to be processed. This can be any JSON value.
+    /// If the job was submitted with raw data, this is a string containing the data in base64.
     pub data: Value,
+    /// The content type of the data if the job was submitted with raw data, e.g. `text/plain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
     /// The time at which the job was submitted.
     pub submitted_at: DateTime<Utc>,
+    /// The priority of the job. Jobs with a higher priority are dispatched first.
+    #[serde(default = "Job::default_priority")]
+    pub priority: u8,
+    /// The tags a worker must have to be assigned this job.
+    #[serde(default)]
+    pub required_tags: Vec<String>,
+    /// The URL to which the result of the job is sent once the worker reports it.
+    #[serde(default)]
+    pub result_callback_url: Option<String>,
+    /// The time before which the job must not be dispatched.
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    /// The number of seconds the job may wait to be dispatched before it is discarded, see [`Job::is_stale`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// The number of workers which failed to accept the job so far.
+    #[serde(default)]
+    pub dispatch_attempts: u32,
+    /// The time at which the job was assigned to a worker, while it is in-flight.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assigned_at: Option<DateTime<Utc>>,
+    /// The callback URL of the worker to which the job was assigned, while it is in-flight,
+    /// so that the job can be dispatched again as soon as the worker deregisters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assigned_to: Option<String>,
+    /// The topic to which the job was submitted, or None if it was submitted to the global job queue.
+    /// The job is only dispatched to workers which registered for the same topic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
 }
 
 impl Job {
+    /// The priority of jobs which were submitted without one.
+    pub const DEFAULT_PRIORITY: u8 = 128;
+
     /// Creates a new job with the given data.
+    /// If the data is an object with an `id` string which is a valid UUID, it is used as the job's id instead of a generated one.
+    /// If the data is an object with a `priority` field between 0 and 255, it is used as the job's priority.
+    /// If the data is an object with a `required_tags` array, its strings are used as the job's required tags.
+    /// If the data is an object with a `result_callback_url` string, the job's result is sent there once it was checked,
+    /// see [`check_result_callback_url`].
+    /// If the data is an object with a `not_before` string which is an RFC 3339 timestamp, the job is not dispatched before then.
+    /// If the data is an object with a positive integer `ttl` field, the job is discarded if it waits longer than that many seconds.
     pub fn new(data: Value) -> Self {
+        let id = data.get("id")
+            .and_then(Value::as_str)
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .unwrap_or_else(Uuid::new_v4);
+        let priority = data.get("priority")
+            .and_then(Value::as_u64)
+            .and_then(|priority| u8::try_from(priority).ok())
+            .unwrap_or(Self::DEFAULT_PRIORITY);
+        let required_tags = data.get("required_tags")
+            .and_then(Value::as_array)
+            .map(|tags| tags.iter().filter_map(Value::as_str).map(String::from).collect())
+            .unwrap_or_default();
+        let result_callback_url = data.get("result_callback_url")
+            .and_then(Value::as_str)
+            .map(String::from);
+        let not_before = data.get("not_before")
+            .and_then(Value::as_str)
+            .and_then(|not_before| DateTime::parse_from_rfc3339(not_before).ok())
+            .map(|not_before| not_before.to_utc());
+        let ttl = data.get("ttl")
+            .and_then(Value::as_u64)
+            .filter(|&ttl| ttl > 0);
         Self {
-            id: Uuid::new_v4(),
+            id,
             data,
+            content_type: None,
             submitted_at: Utc::now(),
+            priority,
+            required_tags,
+            result_callback_url,
+            not_before,
+            ttl,
+            dispatch_attempts: 0,
+            assigned_at: None,
+            assigned_to: None,
+            topic: None,
         }
     }
+
+    /// Creates a new job with the given raw data of the given content type, and the given options.
+    /// The data is stored as a base64 string, so that it survives being serialized as JSON.
+    pub fn new_raw(data: &[u8], content_type: String, options: RawJobQuery) -> Self {
+        Self {
+            id: options.id.unwrap_or_else(Uuid::new_v4),
+            data: Value::String(BASE64_STANDARD.encode(data)),
+            content_type: Some(content_type),
+            submitted_at: Utc::now(),
+            priority: options.priority.unwrap_or(Self::DEFAULT_PRIORITY),
+            required_tags: options.required_tags
+                .map(|tags| tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            result_callback_url: options.result_callback_url,
+            not_before: options.not_before,
+            ttl: options.ttl.filter(|&ttl| ttl > 0),
+            dispatch_attempts: 0,
+            assigned_at: None,
+            assigned_to: None,
+            topic: None,
+        }
+    }
+
+    /// Returns true if the job may be dispatched now, i.e. it has no `not_before` time or that time has passed.
+    pub fn is_ready(&self) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= Utc::now())
+    }
+
+    fn default_priority() -> u8 {
+        Self::DEFAULT_PRIORITY
+    }
+
+    /// Returns the size in bytes of the job as it is sent to a worker, i.e. serialized as JSON.
+    pub fn size(&self) -> u64 {
+        serde_json::to_vec(self).map_or(0, |json| json.len() as u64)
+    }
+
+    /// Returns how long ago the job was submitted.
+    pub fn age(&self) -> TimeDelta {
+        Utc::now().signed_duration_since(self.submitted_at)
+    }
+
+    /// Returns true if the job waited to be dispatched for longer than its TTL or the given maximum age, whichever is shorter.
+    /// The time a scheduled job waited for its `not_before` time does not count.
+    pub fn is_stale(&self, max_age: Option<Duration>) -> bool {
+        let Some(ttl) = self.ttl.map(Duration::from_secs).into_iter().chain(max_age).min() else {
+            return false;
+        };
+        let scheduled = self.not_before.map_or(TimeDelta::zero(), |not_before| (not_before - self.submitted_at).max(TimeDelta::zero()));
+        (self.age() - scheduled).to_std().is_ok_and(|waited| waited > ttl)
+    }
+
+    /// Returns true if the job was assigned to a worker longer than the given visibility timeout ago.
+    pub fn is_assignment_expired(&self, timeout: Duration) -> bool {
+        self.assigned_at.is_some_and(|assigned_at| {
+            Utc::now().signed_duration_since(assigned_at).to_std().is_ok_and(|age| age > timeout)
+        })
+    }
+
+    /// Remembers the job as in-flight until the worker with the given callback URL reports its result,
+    /// so that it can be dispatched again if the service crashes, the visibility timeout passes, or the worker deregisters in the meantime.
+    /// Returns an error if the job could not be persisted.
+    pub async fn track_assignment(&mut self, state: &AppState, callback_url: &str) -> QueueResult<()> {
+        self.assigned_at = Some(Utc::now());
+        self.assigned_to = Some(callback_url.to_owned());
+        state.assigned_jobs.lock().await.enqueue(self.clone()).await.map(|_| ())
+    }
+
+    /// Records that the job entered the given state, so that it is reported by [`job_status`].
+    pub async fn record_state(&self, state: &AppState, job_state: JobState) {
+        let status = JobStatus { state: job_state, submitted_at: self.submitted_at, updated_at: Utc::now() };
+        state.job_statuses.lock().await.insert(self.id, status);
+    }
+
+    /// Forgets a job which was tracked but could not be assigned after all.
+    /// Only this assignment is forgotten, so an earlier assignment of the same job which timed out stays tracked until it is requeued.
+    pub async fn untrack_assignment(&mut self, state: &AppState) {
+        let (id, assigned_at) = (self.id, self.assigned_at.take());
+        self.assigned_to = None;
+        if let Err(err) = state.assigned_jobs.lock().await.retain(&|job: &Job| job.id != id || job.assigned_at != assigned_at).await {
+            error!("Failed to remove job {id} from assigned jobs: '{err}'");
+        }
+    }
+}
+
+impl QueueItem for Job {
+    fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+impl Identifiable for Job {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+/// The options of a job submitted with raw data, given as query parameters. See [`submit_raw_job`].
+#[derive(Debug, Deserialize)]
+pub struct RawJobQuery {
+    /// The id of the job, instead of a generated one.
+    pub id: Option<Uuid>,
+    /// The priority of the job between 0 and 255.
+    pub priority: Option<u8>,
+    /// A comma-separated list of the tags a worker must have to be assigned the job.
+    pub required_tags: Option<String>,
+    /// The URL to which the result of the job is sent once the worker reports it.
+    pub result_callback_url: Option<String>,
+    /// The time before which the job must not be dispatched.
+    pub not_before: Option<DateTime<Utc>>,
+    /// The number of seconds the job may wait to be dispatched before it is discarded.
+    pub ttl: Option<u64>,
+    /// What happens to the job if no worker is immediately available.
+    #[serde(default)]
+    pub mode: SubmitMode,
+    /// The number of seconds to wait for a worker to register if no worker is immediately available.
+    pub wait: Option<u64>,
+}
+
+/// The query parameters of a job submission. See [`submit_job`].
+#[derive(Debug, Deserialize)]
+pub struct SubmitQuery {
+    /// What happens to the job if no worker is immediately available.
+    #[serde(default)]
+    pub mode: SubmitMode,
+    /// The number of seconds to wait for a worker to register if no worker is immediately available.
+    pub wait: Option<u64>,
+}
+
+/// What happens to a submitted job if no worker is immediately available, chosen with the `mode` query parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubmitMode {
+    /// The job is queued until a worker is available.
+    #[default]
+    Queue,
+    /// The job is rejected, so that the client does not have to wait for a worker.
+    NoWait,
 }
 
+/// The header containing the id of the job sent to a worker's callback URL.
+const JOB_ID_HEADER: &str = "x-job-id";
+
+/// The longest time a submission may wait for a worker to register, see [`submit_job`].
+const MAX_SUBMIT_WAIT: Duration = Duration::from_secs(60);
+
 /// The response to a job submission request.
-/// If a worker was immediately available, the response is Assigned.
+/// If a worker was immediately available, the response is Dispatching.
 /// If no workers were available, the response is Queued with the job's position in the queue.
+/// Whenever the job was accepted, the response contains its id, with which it can later be cancelled.
 #[derive(Debug, Serialize)]
 pub enum SubmitJobResponse {
     /// A worker was assigned the job, and it is being processed.
-    Assigned,
+    /// The callback URL of the worker is provided, reduced to its origin if worker URLs are redacted.
+    Assigned { id: Uuid, callback_url: String },
+    /// A worker was taken from the worker queue for the job, and the job is being sent to it in the background.
+    /// The callback URL of the worker is provided, reduced to its origin if worker URLs are redacted.
+    Dispatching { id: Uuid, callback_url: String },
     /// No workers were available, and the job has been queued.
     /// The job's position in the queue is provided.
-    Queued { position: usize },
+    Queued { id: Uuid, position: usize },
+    /// The job must not be dispatched yet, and has been scheduled.
+    Scheduled { id: Uuid },
+}
+
+/// The outcome of a job submission: the response if the job was accepted, or the error it was rejected with.
+/// See [`submit_job`] for the possible responses and errors.
+type SubmitResult = Result<(StatusCode, Json<SubmitJobResponse>), ApiError>;
+
+/// The response to one of the jobs of a batch submission, see [`submit_jobs`]:
+/// the response to the job if it was accepted, or the error it was rejected with.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BatchJobResponse {
+    Accepted(SubmitJobResponse),
+    Rejected(ApiError),
+}
+
+impl From<SubmitResult> for BatchJobResponse {
+    fn from(result: SubmitResult) -> Self {
+        match result {
+            Ok((_, Json(response))) => Self::Accepted(response),
+            Err(err) => Self::Rejected(err),
+        }
+    }
+}
+
+/// The error with which a job is rejected because the job queue is full.
+fn job_queue_full() -> ApiError {
+    ApiError::new(StatusCode::TOO_MANY_REQUESTS, ErrorCode::QueueFull, "The job queue is full")
+}
+
+/// The error with which a job is rejected because its data is invalid, listing every violation.
+fn invalid_job(errors: &[String]) -> ApiError {
+    ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::Invalid, format!("The job is invalid: {}", errors.join("; ")))
+}
+
+/// The error with which a job is rejected because its id belongs to a known job.
+fn duplicate_id(id: Uuid) -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, ErrorCode::DuplicateId, format!("A job with the id {id} is already known"))
+}
+
+/// The state of a job, as reported by [`job_status`].
+/// A job starts out Submitted, and becomes Scheduled, Queued or Assigned once it was scheduled, queued or accepted by a worker.
+/// Once its result is reported, it is Completed. Failed, DeadLettered, Expired and Cancelled jobs are no longer dispatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum JobState {
+    /// The job was submitted and is being offered to the workers.
+    Submitted,
+    /// The job must not be dispatched before its `not_before` time.
+    Scheduled,
+    /// The job waits in the job queue for a worker.
+    Queued,
+    /// A worker accepted the job, and its result has not been reported yet.
+    Assigned,
+    /// The worker reported the result of the job.
+    Completed,
+    /// The job was rejected because the job queue was full, no worker was available in the NoWait mode,
+    /// or it could not be persisted. Its id may be used again.
+    Failed,
+    /// Too many workers failed to accept the job, and it was moved to the dead-letter queue.
+    DeadLettered,
+    /// The job waited in the job queue longer than its time-to-live, and was discarded.
+    Expired,
+    /// The job was cancelled or cleared from the job queue before it was assigned.
+    Cancelled,
+}
+
+impl JobState {
+    /// Returns true if the job is no longer dispatched and its state no longer changes by itself,
+    /// i.e. if it is Completed, Failed, DeadLettered, Expired or Cancelled.
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Completed | Self::Failed | Self::DeadLettered | Self::Expired | Self::Cancelled)
+    }
+}
+
+/// The current state of a job, along with the time at which it was submitted and the time at which it entered its state.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub state: JobState,
+    pub submitted_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Moves a job which too many workers failed to accept to the dead-letter queue, where it is no longer dispatched.
+/// Responds with 502 Bad Gateway and "DeadLettered", or with 500 Internal Server Error and "PersistenceFailed"
+/// if the job could not be persisted to the dead-letter queue.
+async fn dead_letter(state: &AppState, job: Job) -> SubmitResult {
+    let job_id = job.id;
+    error!(%job_id, dispatch_attempts = job.dispatch_attempts, "Job was rejected by too many workers, moving it to the dead-letter queue...");
+    if let Err(err) = state.dead_letter_jobs.lock().await.enqueue(job.clone()).await {
+        error!(%job_id, "Failed to persist job to dead-letter queue: '{err}'");
+        job.record_state(state, JobState::Failed).await;
+        return Err(err.into());
+    }
+    job.record_state(state, JobState::DeadLettered).await;
+    counter!(telemetry::JOBS_DEAD_LETTERED).increment(1);
+    let message = format!("Too many workers failed to accept the job {job_id}, it was moved to the dead-letter queue");
+    Err(ApiError::new(StatusCode::BAD_GATEWAY, ErrorCode::DeadLettered, message))
 }
 
 /// An asynchronous response sent to a worker.
@@ -55,46 +392,1715 @@ pub enum AsynchronousWorkerResponse<'a> {
     Job(&'a Job)
 }
 
+/// What happens to a worker which failed to accept a job, chosen via the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+pub enum FailedWorkerPolicy {
+    /// The worker is queued again at the back of the worker queue if it failed for a transient reason,
+    /// and discarded otherwise. See [`DispatchFailure`].
+    Classify,
+    /// The worker is always queued again at the back of the worker queue, so that a flaky worker
+    /// cannot intercept job after job at the front. Workers which stopped responding are only removed by the worker TTL.
+    Requeue,
+    /// The worker is always discarded and must register again.
+    Discard,
+}
+
+/// Why a worker failed to accept a job, which decides whether the worker is queued again or discarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DispatchFailure {
+    /// The failure says nothing about whether the worker is ready, so the worker is queued again.
+    /// This is the case if the request timed out, or if the worker responded with
+    /// 408 Request Timeout, 429 Too Many Requests or a 5xx code, i.e. it is only busy or briefly unavailable.
+    Transient,
+    /// The worker will not accept jobs at its callback URL, so it is discarded.
+    /// This is the case if the connection failed, e.g. because nothing listens at the callback URL anymore,
+    /// or if the worker responded with any other non-2xx code, e.g. 404 Not Found or a redirect which was not followed.
+    Permanent,
+}
+
+impl DispatchFailure {
+    /// Classifies an error which occurred while sending a job to a worker.
+    fn of_error(err: &reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Transient
+        } else {
+            Self::Permanent
+        }
+    }
+
+    /// Classifies a non-2xx status code with which a worker responded to a job.
+    fn of_status(status: StatusCode) -> Self {
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::REQUEST_TIMEOUT {
+            Self::Transient
+        } else {
+            Self::Permanent
+        }
+    }
+}
+
+/// Sends the job to the worker at the given callback URL.
+/// Failed attempts are retried with exponential backoff, up to the configured number of attempts.
+/// The id of the job is sent in the `X-Job-Id` header, along with the configured callback headers and User-Agent,
+/// and with the `otel` feature, the trace context of the current span in the `traceparent` header.
+/// No lock is held while sending or waiting, so other requests can use the queues in the meantime.
+/// Returns Ok if the worker accepted the job with a 2xx status code,
+/// or the classification of the last failure otherwise.
+/// Workers connected via WebSocket are sent the job over their connection instead, see [`send_job_over_websocket`].
+async fn send_job(state: &AppState, worker: &Worker, job: &Job) -> Result<(), DispatchFailure> {
+    if let Some(connection) = worker.websocket_connection() {
+        return send_job_over_websocket(state, connection, job).await;
+    }
+    let callback_url = &worker.callback_url;
+    let mut backoff = state.callback_backoff;
+    let mut attempt = 1;
+    loop {
+        let request = state.http_client
+            .put(callback_url)
+            .headers(HeaderMap::clone(&state.callback_headers))
+            .header(JOB_ID_HEADER, job.id.to_string());
+        #[cfg(feature = "otel")]
+        let request = request.headers(crate::otel::context_headers());
+        let sent = {
+            // The permit is released once the worker responded, and not held during the backoff
+            let _permit = match &state.callback_permits {
+                Some(permits) => permits.acquire().await.ok(),
+                None => None,
+            };
+            request.json(&AsynchronousWorkerResponse::Job(job)).send().await
+        };
+        let (failure, message) = match sent {
+            // Something went wrong while sending the request (connection refused, timeout, etc.)
+            Err(err) => (DispatchFailure::of_error(&err), format!("Failed to send job to worker: '{err}'")),
+            Ok(response) if !response.status().is_success() => {
+                let status = response.status();
+                (DispatchFailure::of_status(status), format!("Worker responded to job assignment with non-2xx code ({status})"))
+            },
+            Ok(_) => return Ok(()),
+        };
+        counter!(telemetry::CALLBACK_FAILURES).increment(1);
+        Stats::count(&state.stats.callback_failures, 1);
+        if attempt == state.callback_attempts {
+            error!(job_id = %job.id, callback_url, "{message} (attempt {attempt}/{})", state.callback_attempts);
+            return Err(failure);
+        }
+        warn!(job_id = %job.id, callback_url, "{message} (attempt {attempt}/{}), retrying in {backoff:?}...", state.callback_attempts);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Offers the job to the worker connected via the given WebSocket connection, and waits for the worker to acknowledge it.
+/// Returns Ok if the worker acknowledged the job within the callback timeout.
+/// If the worker did not respond in time, the failure is transient; if the worker has disconnected, it is permanent.
+async fn send_job_over_websocket(state: &AppState, connection: Uuid, job: &Job) -> Result<(), DispatchFailure> {
+    let Some(sender) = state.websocket_workers.lock().await.get(&connection).cloned() else {
+        error!(job_id = %job.id, %connection, "Worker is no longer connected via WebSocket");
+        return Err(DispatchFailure::Permanent);
+    };
+    let (ack, acknowledged) = oneshot::channel();
+    let offer = JobOffer { job: job.clone(), ack };
+    let result = match tokio::time::timeout(state.callback_timeout, async {
+        sender.send(offer).await.map_err(|_| DispatchFailure::Permanent)?;
+        acknowledged.await.map_err(|_| DispatchFailure::Permanent)
+    }).await {
+        Ok(result) => result,
+        Err(_) => Err(DispatchFailure::Transient),
+    };
+    if result.is_err() {
+        counter!(telemetry::CALLBACK_FAILURES).increment(1);
+        Stats::count(&state.stats.callback_failures, 1);
+        error!(job_id = %job.id, %connection, "Worker did not acknowledge job sent via WebSocket");
+    }
+    result
+}
+
+/// The notification sent to a job's result callback URL.
+/// Like [`AsynchronousWorkerResponse`], the payload is wrapped in an object naming its kind.
+#[derive(Debug, Serialize)]
+pub enum ResultNotification<'a> {
+    Result { id: Uuid, result: &'a Value },
+}
+
+/// The response to a job result report.
+#[derive(Debug, Serialize)]
+pub enum JobResultResponse {
+    /// The result was sent to the job's result callback URL.
+    Delivered,
+    /// The job was submitted without a result callback URL, so it was only marked as completed.
+    Completed,
+}
+
+/// The response to a job cancellation request.
+#[derive(Debug, Serialize)]
+pub enum CancelJobResponse {
+    /// The job was removed from the job queue.
+    Cancelled,
+}
+
+/// The response to a request to requeue one or all dead-lettered jobs.
+#[derive(Debug, Serialize)]
+pub enum RequeueDeadLetterResponse {
+    /// The job was moved back into the job queue at the given position.
+    Requeued { position: usize },
+    /// All dead-lettered jobs were moved back into the job queue.
+    RequeuedAll { requeued: usize },
+}
+
 /// POST /submit-job
 /// Submits a job to be processed by a worker.
-/// The job is sent to the workers in the worker queue in the order they are dequeued.
-/// The first worker to return a 2xx status code is assigned the job, and this endpoint
-/// responds with 200 Ok and "Assigned".
+/// The first worker in the worker queue which has all of the job's required tags is taken from the queue;
+/// workers without the required tags keep their place in the queue. This endpoint then responds right away
+/// with 202 Accepted and "Dispatching" along with the worker's callback URL, while the job is sent to the worker
+/// in the background, so that a slow worker does not delay the response. The worker queue is not locked while sending.
+/// If the worker does not return a 2xx status code, the job is sent to the next suitable worker, and so on,
+/// until a worker accepts it; if none does, the job is queued. Each worker is retried with exponential backoff
+/// up to the configured number of attempts before the job moves on to the next worker.
+/// Depending on the [`FailedWorkerPolicy`], a worker which failed is queued again at the back of the worker queue afterward,
+/// or discarded.
 /// If no workers are available, the job is queued and this endpoint responds with
 /// 202 Accepted and "Queued" along with the job's position in the queue.
+/// If the `mode=nowait` query parameter is given, the job is rejected instead and this endpoint responds with
+/// 503 Service Unavailable and "NoWorkerAvailable". Scheduled jobs are scheduled regardless.
+/// If the `wait=<seconds>` query parameter is given (at most 60), the job is held for up to that long before it is queued
+/// or rejected, and handed off to the first worker which registers in the meantime, so that the response can still be "Dispatching".
+/// If the job queue is full, this endpoint responds with 429 Too Many Requests and "QueueFull".
+/// If the job could not be persisted to the job queue, this endpoint responds with
+/// 500 Internal Server Error and "PersistenceFailed".
+/// If a maximum number of dispatch attempts is configured and as many workers failed to accept the job,
+/// it is moved to the dead-letter queue instead; since this happens in the background, the submitter can follow it via
+/// [`job_status`] or the job events.
+/// If the job has a `not_before` time in the future, it is not dispatched but scheduled,
+/// and this endpoint responds with 202 Accepted and "Scheduled". It is dispatched as described above once the time has passed.
+/// If a JSON Schema is configured and the job does not satisfy it, the job is rejected before any of the above,
+/// and this endpoint responds with 422 Unprocessable Entity and "Invalid" along with the violations.
+/// If the job has a `result_callback_url` which would not be allowed as the callback URL of a worker, e.g. because of its scheme
+/// or host, the job is rejected as well, and this endpoint responds with 400 Bad Request and the same code as a worker registration.
+/// The id of the job is generated, unless the job data contains an `id` field with a UUID, so that a submitter can
+/// retry a submission without creating a duplicate job. If that field is not a UUID, the job is rejected as "Invalid".
+/// If a known job already has that id, the job is rejected and this endpoint responds with 409 Conflict and "DuplicateId";
+/// only the ids of jobs which failed, e.g. because the job queue was full, may be used again. See [`claim_id`].
+/// The "Dispatching", "Queued" and "Scheduled" responses contain the id of the job,
+/// and the "Dispatching" response additionally contains the callback URL of the worker, see [`response_callback_url`].
 #[rustfmt::skip]
 pub async fn submit_job(
     State(state): State<AppState>,
+    Query(query): Query<SubmitQuery>,
     Json(data): Json<Value>
-) -> (StatusCode, Json<SubmitJobResponse>) {
-    let job = Job::new(data);
-    loop {
-        let Worker {
-            callback_url,
-            registered_at
-        } = match state.worker_queue.lock().await.dequeue().await {
-            Some(worker) => worker,
-            None => break,
-        };
-        let queue_time = Utc::now().signed_duration_since(registered_at).num_seconds();
-        match state.http_client.put(&callback_url).json(&AsynchronousWorkerResponse::Job(&job)).send().await {
-            Err(err) => {
-                // Something went wrong while sending the request (redirect loop, timeout, etc.)
-                error!("Failed to send job to worker at {callback_url}: '{err}', discarding... (was queued for {queue_time}s)");
+) -> SubmitResult {
+    if let Err(errors) = validate(&state, &data) {
+        info!("Job submission received. Job does not satisfy the schema, rejecting...");
+        return Err(invalid_job(&errors));
+    }
+    let supplied_id = data.get("id").is_some();
+    submit(&state, Job::new(data), supplied_id, query.mode, query.wait).await
+}
+
+/// POST /submit-raw-job
+/// Submits a job whose data is not JSON, e.g. text or binary data, given as the request body.
+/// The content type of the data is taken from the Content-Type header, and defaults to `application/octet-stream`.
+/// The options of the job can be given as the query parameters `id`, `priority`, `required_tags` (comma-separated),
+/// `result_callback_url`, `not_before`, `ttl`, `mode` and `wait`, e.g. `/submit-raw-job?priority=200&required_tags=gpu`.
+/// Since jobs are sent to workers as JSON, the job's data is a string containing the body in base64,
+/// and its `content_type` field contains the content type, so that workers can restore both faithfully.
+/// The configured JSON Schema does not apply to such jobs. Otherwise, the job is handled like one submitted to [`submit_job`],
+/// with the same possible responses.
+#[rustfmt::skip]
+pub async fn submit_raw_job(
+    State(state): State<AppState>,
+    Query(options): Query<RawJobQuery>,
+    headers: HeaderMap,
+    body: Bytes
+) -> SubmitResult {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or("application/octet-stream");
+    let (supplied_id, mode, wait) = (options.id.is_some(), options.mode, options.wait);
+    submit(&state, Job::new_raw(&body, content_type.to_string(), options), supplied_id, mode, wait).await
+}
+
+/// Reserves the id of a submitted job by recording the job as Submitted, unless a known job already has the id,
+/// in which case false is returned. The id of a job which failed is not reserved, so that it can be submitted again.
+/// Since only the states of the jobs submitted since the service started are recorded, the job queues
+/// and the scheduled, assigned and dead-lettered jobs are searched as well if the submitter supplied the id;
+/// generated ids are not searched for, since they never collide.
+/// Returns an error if a queue could not be read.
+async fn claim_id(state: &AppState, job: &Job, supplied: bool) -> QueueResult<bool> {
+    let id = job.id;
+    if supplied {
+        let mut queues = vec![state.scheduled_jobs.clone(), state.assigned_jobs.clone(), state.dead_letter_jobs.clone()];
+        // The global job queue is only known to the global state, while every state knows the job queues of all topics
+        if state.topic.is_none() {
+            queues.push(state.job_queue.clone());
+        }
+        queues.extend(state.topic_states().await.into_iter().map(|topic_state| topic_state.job_queue));
+        for queue in queues {
+            let found = queue.lock().await.find_by_id(id).await?;
+            if found.is_some() {
+                return Ok(false);
+            }
+        }
+    }
+    // Checking and recording the state under the same lock ensures that concurrent submissions cannot both claim the id
+    let mut job_statuses = state.job_statuses.lock().await;
+    if job_statuses.get(&id).is_some_and(|status| status.state != JobState::Failed) {
+        return Ok(false);
+    }
+    let status = JobStatus { state: JobState::Submitted, submitted_at: job.submitted_at, updated_at: Utc::now() };
+    job_statuses.insert(id, status);
+    Ok(true)
+}
+
+/// Schedules the submitted job if it must not be dispatched yet, or dispatches it otherwise.
+/// If a number of seconds to wait is given, a job which no worker accepted is offered to the workers which register
+/// in the meantime before it is queued, or rejected in the NoWait mode.
+/// Whether the submitter supplied the job's id decides how thoroughly the id is checked for duplicates, see [`claim_id`].
+/// See [`submit_job`] for the possible responses.
+#[rustfmt::skip]
+async fn submit(state: &AppState, mut job: Job, supplied_id: bool, mode: SubmitMode, wait: Option<u64>) -> SubmitResult {
+    job.topic = state.topic.as_deref().map(String::from);
+    telemetry::record_job_id(job.id);
+    if let Err(err) = check_result_callback_url(state, &job).await {
+        info!(job_id = %job.id, "Job submission received. The result callback URL is not allowed, rejecting...");
+        return Err(err);
+    }
+    match claim_id(state, &job, supplied_id).await {
+        Ok(true) => {},
+        Ok(false) => {
+            info!(job_id = %job.id, "Job submission received. A job with the same id is known, rejecting...");
+            return Err(duplicate_id(job.id));
+        },
+        Err(err) => {
+            error!(job_id = %job.id, "Failed to read job queues: '{err}'");
+            return Err(err.into());
+        },
+    }
+    counter!(telemetry::JOBS_SUBMITTED).increment(1);
+    Stats::count(&state.stats.jobs_submitted, 1);
+    events::publish(state, JobEvent::Submitted { id: job.id });
+    if !job.is_ready() {
+        let job_id = job.id;
+        info!(%job_id, not_before = ?job.not_before, "Job submission received. Job must not be dispatched yet, scheduling...");
+        if let Err(err) = state.scheduled_jobs.lock().await.enqueue(job.clone()).await {
+            error!(%job_id, "Failed to persist job to scheduled jobs: '{err}'");
+            job.record_state(state, JobState::Failed).await;
+            return Err(err.into());
+        }
+        job.record_state(state, JobState::Scheduled).await;
+        return Ok((StatusCode::ACCEPTED, Json(SubmitJobResponse::Scheduled { id: job_id })));
+    }
+    let offered_at = Utc::now();
+    let offered = match wait {
+        Some(wait) => offer_until(state, job, Duration::from_secs(wait).min(MAX_SUBMIT_WAIT)).await,
+        None => hand_off(state, job).await,
+    };
+    match (offered, mode) {
+        (Ok(response), _) => response,
+        (Err(job), SubmitMode::Queue) => queue(state, job, offered_at).await,
+        (Err(job), SubmitMode::NoWait) => {
+            info!(job_id = %job.id, "Job submission received. No workers available, rejecting job as requested...");
+            job.record_state(state, JobState::Failed).await;
+            Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::NoWorkerAvailable, "No worker is available for the job"))
+        },
+    }
+}
+
+/// POST /submit-jobs
+/// Submits several jobs at once, given as a JSON array with the data of each job.
+/// Each job is dispatched or scheduled like a job submitted to [`submit_job`], except that the jobs
+/// which no worker accepted are queued with a single queue operation, as are the scheduled jobs.
+/// If the job queue cannot hold all of the jobs which need to be queued, none of them are queued.
+/// Jobs which do not satisfy the configured JSON Schema, or whose id belongs to a known job, are rejected individually.
+/// Responds with 200 OK and an array containing the response to each job, in the order in which the jobs were submitted.
+#[rustfmt::skip]
+pub async fn submit_jobs(
+    State(state): State<AppState>,
+    Json(batch): Json<Vec<Value>>
+) -> (StatusCode, Json<Vec<BatchJobResponse>>) {
+    info!(jobs = batch.len(), "Batch submission received");
+    // The responses to the jobs which are scheduled or queued below are replaced once they were persisted, or failed to be
+    let pending = || BatchJobResponse::Rejected(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::PersistenceFailed, "The job was not persisted"));
+    let mut responses = Vec::with_capacity(batch.len());
+    let (mut scheduled, mut unassigned) = (Vec::new(), Vec::new());
+    for data in batch {
+        if let Err(errors) = validate(&state, &data) {
+            responses.push(BatchJobResponse::Rejected(invalid_job(&errors)));
+            continue;
+        }
+        let supplied_id = data.get("id").is_some();
+        let job = Job::new(data);
+        if let Err(err) = check_result_callback_url(&state, &job).await {
+            responses.push(BatchJobResponse::Rejected(err));
+            continue;
+        }
+        match claim_id(&state, &job, supplied_id).await {
+            Ok(true) => {},
+            Ok(false) => {
+                responses.push(BatchJobResponse::Rejected(duplicate_id(job.id)));
                 continue;
             },
-            Ok(response) if !response.status().is_success() => {
-                let status = response.status();
-                error!("Worker at {callback_url} responded to job assignment with non-2xx code ({status}), discarding... (was queued for {queue_time}s)");
+            Err(err) => {
+                error!(job_id = %job.id, "Failed to read job queues: '{err}'");
+                responses.push(BatchJobResponse::Rejected(err.into()));
                 continue;
             },
+        }
+        counter!(telemetry::JOBS_SUBMITTED).increment(1);
+        Stats::count(&state.stats.jobs_submitted, 1);
+        events::publish(&state, JobEvent::Submitted { id: job.id });
+        if !job.is_ready() {
+            scheduled.push((responses.len(), job));
+            responses.push(pending());
+            continue;
+        }
+        match hand_off(&state, job).await {
+            Ok(result) => responses.push(result.into()),
+            Err(job) => {
+                unassigned.push((responses.len(), job));
+                responses.push(pending());
+            },
+        }
+    }
+    if !scheduled.is_empty() {
+        let (indices, jobs): (Vec<_>, Vec<_>) = scheduled.into_iter().unzip();
+        info!(jobs = jobs.len(), "Jobs must not be dispatched yet, scheduling...");
+        let job_state = match state.scheduled_jobs.lock().await.enqueue_many(jobs.clone()).await {
             Ok(_) => {
-                info!("Job submission received. Assigning to worker at {callback_url} (was queued for {queue_time}s)");
-                return (StatusCode::OK, Json(SubmitJobResponse::Assigned));
+                for (index, job) in indices.into_iter().zip(&jobs) {
+                    responses[index] = BatchJobResponse::Accepted(SubmitJobResponse::Scheduled { id: job.id });
+                }
+                JobState::Scheduled
+            },
+            Err(err) => {
+                error!("Failed to persist jobs to scheduled jobs: '{err}'");
+                let err = ApiError::from(err);
+                indices.into_iter().for_each(|index| responses[index] = BatchJobResponse::Rejected(err.clone()));
+                JobState::Failed
+            },
+        };
+        for job in &jobs {
+            job.record_state(&state, job_state).await;
+        }
+    }
+    if !unassigned.is_empty() {
+        let (indices, jobs): (Vec<_>, Vec<_>) = unassigned.into_iter().unzip();
+        let count = jobs.len();
+        info!(jobs = count, "No workers available, queueing...");
+        let job_state = match state.job_queue.lock().await.enqueue_many(jobs.clone()).await {
+            Ok(positions) => {
+                state.job_queued.notify_waiters();
+                counter!(telemetry::JOBS_QUEUED).increment(count as u64);
+                for ((index, job), position) in indices.into_iter().zip(&jobs).zip(positions) {
+                    events::publish(&state, JobEvent::Queued { id: job.id, position });
+                    responses[index] = BatchJobResponse::Accepted(SubmitJobResponse::Queued { id: job.id, position });
+                }
+                JobState::Queued
+            },
+            Err(QueueError::Full) => {
+                info!("Job queue cannot hold {count} more jobs, rejecting them...");
+                indices.into_iter().for_each(|index| responses[index] = BatchJobResponse::Rejected(job_queue_full()));
+                JobState::Failed
+            },
+            Err(err) => {
+                error!("Failed to persist jobs to job queue: '{err}'");
+                let err = ApiError::from(err);
+                indices.into_iter().for_each(|index| responses[index] = BatchJobResponse::Rejected(err.clone()));
+                JobState::Failed
             },
         };
+        for job in &jobs {
+            job.record_state(&state, job_state).await;
+        }
+    }
+    (StatusCode::OK, Json(responses))
+}
+
+/// Validates the data of a submitted job against the configured JSON Schema, if there is one,
+/// and checks that the `id` field, if present, is a UUID, and that the `result_callback_url` field, if present, is a string.
+/// Returns a description of every violation, prefixed with the JSON pointer to the violating value, if the data is invalid.
+fn validate(state: &AppState, data: &Value) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+    if let Some(id) = data.get("id") && id.as_str().is_none_or(|id| Uuid::parse_str(id).is_err()) {
+        errors.push(format!("/id: {id} is not a valid UUID"));
+    }
+    if let Some(url) = data.get("result_callback_url") && !url.is_string() {
+        errors.push(format!("/result_callback_url: {url} is not a string"));
+    }
+    if let Some(schema) = &state.job_schema {
+        errors.extend(schema.iter_errors(data).map(|err| match err.instance_path.to_string() {
+            path if path.is_empty() => format!("/: {err}"),
+            path => format!("{path}: {err}"),
+        }));
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+/// Checks the result callback URL of a submitted job, if it has one, like the callback URL of a worker
+/// (see [`worker::check_callback_url`]), since the service sends the job's result there.
+/// Returns 400 Bad Request with the code of the [`CallbackHeaderError`](worker::CallbackHeaderError) if it is not allowed.
+async fn check_result_callback_url(state: &AppState, job: &Job) -> Result<(), ApiError> {
+    let Some(result_callback_url) = &job.result_callback_url else {
+        return Ok(());
+    };
+    match worker::check_callback_url(state, result_callback_url).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(ApiError::new(StatusCode::BAD_REQUEST, ErrorCode::Callback(err), format!("Invalid result_callback_url: {err}"))),
+    }
+}
+
+/// What became of a job which was dispatched again by [`dispatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dispatched {
+    /// A worker accepted the job.
+    Assigned,
+    /// No worker accepted the job, and it was queued.
+    Queued,
+    /// Too many workers failed to accept the job, and it was moved to the dead-letter queue.
+    DeadLettered,
+}
+
+/// Sends the job to the first suitable worker in the worker queue which accepts it,
+/// or queues the job if there is none.
+/// Returns an error only if the job could be neither assigned, queued nor dead-lettered,
+/// e.g. because the job queue is full or could not be persisted. The job is then given back along with the error,
+/// so that the caller can keep it wherever it came from instead of losing it.
+async fn dispatch(state: &AppState, job: Job) -> Result<Dispatched, (ApiError, Job)> {
+    let offered_at = Utc::now();
+    let response = match offer(state, job.clone(), None).await {
+        Ok(response) => response,
+        Err(job) => queue(state, job, offered_at).await,
+    };
+    match response {
+        Ok((_, Json(SubmitJobResponse::Queued { .. }))) => Ok(Dispatched::Queued),
+        Ok(_) => Ok(Dispatched::Assigned),
+        Err(err) if err.code() == ErrorCode::DeadLettered => Ok(Dispatched::DeadLettered),
+        Err(err) => Err((err, job)),
+    }
+}
+
+/// Sends the job to the first suitable worker in the worker queue which accepts it.
+/// Returns the response to the submission if the job was assigned to a worker or could not be dispatched,
+/// or gives the job back if no worker accepted it.
+/// Workers which failed to accept the job for a transient reason are queued again afterward,
+/// so that the job is not offered to them twice.
+/// If a worker is given, the job is sent to it before the workers in the worker queue.
+async fn offer(state: &AppState, job: Job, worker: Option<Worker>) -> Result<SubmitResult, Job> {
+    let mut requeue = Vec::new();
+    let response = offer_to_workers(state, job, worker, &mut requeue).await;
+    for worker in requeue {
+        let callback_url = worker.callback_url.clone();
+        info!(%callback_url, "Queueing worker again...");
+        if let Err(err) = return_slots(state, worker).await {
+            error!(%callback_url, "Failed to persist worker to worker queue: '{err}'");
+        }
+    }
+    response
+}
+
+/// Takes the first suitable worker for the job from the worker queue, and sends the job to it in the background,
+/// so that the submitter does not wait for the worker to accept it. If the worker fails to accept the job,
+/// the job is offered to the next suitable worker like in [`offer`], and queued if no worker accepts it.
+/// Returns the response to the submission, or gives the job back if no suitable worker is queued.
+async fn hand_off(state: &AppState, job: Job) -> Result<SubmitResult, Job> {
+    let worker = match next_worker(state, &job).await {
+        Ok(Some(worker)) => worker,
+        Ok(None) => return Err(job),
+        Err(err) => {
+            error!(job_id = %job.id, "Failed to dequeue from worker queue: '{err}'");
+            return Err(job);
+        },
+    };
+    let (id, callback_url) = (job.id, response_callback_url(state, &worker.callback_url));
+    info!(job_id = %id, callback_url = %worker.callback_url, "Job submission received. Dispatching to worker in the background...");
+    let state = state.clone();
+    // The dispatch continues the span of the submission, so that its log lines and trace are attributed to the job
+    let span = info_span!("dispatch", job_id = %id);
+    tokio::spawn(async move {
+        let offered_at = Utc::now();
+        if let Err(job) = offer(&state, job, Some(worker)).await {
+            let _ = queue(&state, job, offered_at).await;
+        }
+    }.instrument(span));
+    Ok(Ok((StatusCode::ACCEPTED, Json(SubmitJobResponse::Dispatching { id, callback_url }))))
+}
+
+/// Hands the job off to the first suitable worker like [`hand_off`], and if no worker is queued, tries again
+/// whenever a worker is queued, until a worker is found or the given time has elapsed.
+/// The worker queue is not locked while waiting, so that the registering workers can be queued.
+async fn offer_until(state: &AppState, mut job: Job, wait: Duration) -> Result<SubmitResult, Job> {
+    let deadline = Instant::now() + wait;
+    let mut waiting = false;
+    loop {
+        // Listen before offering the job, so that a worker which is queued in between is not missed
+        let mut worker_queued = pin!(state.worker_queued.notified());
+        worker_queued.as_mut().enable();
+        job = match hand_off(state, job).await {
+            Ok(response) => return Ok(response),
+            Err(job) => job,
+        };
+        if !waiting {
+            info!(job_id = %job.id, "No workers available, waiting for a worker to register...");
+            waiting = true;
+        }
+        if timeout_at(state.next_poll(deadline), worker_queued).await.is_err() && Instant::now() >= deadline {
+            info!(job_id = %job.id, "No worker registered within {wait:?}");
+            return Err(job);
+        }
+    }
+}
+
+/// Queues a worker which failed to accept a job again, along with the slots which were held back.
+/// If the worker was queued again in the meantime, e.g. because it registered again, the slots are added to it instead.
+async fn return_slots(state: &AppState, worker: Worker) -> QueueResult<()> {
+    let mut worker_queue = state.worker_queue.lock().await;
+    let returned = worker_queue.update(&|queued: &mut Worker| {
+        if queued.callback_url != worker.callback_url {
+            return false;
+        }
+        queued.slots += worker.slots;
+        true
+    }).await?;
+    if returned == 0 {
+        worker_queue.enqueue(worker).await?;
+    }
+    Ok(())
+}
+
+/// Removes the first suitable worker for the job from the worker queue, discarding expired workers on the way.
+/// Only one slot of the worker is taken; a worker with further slots stays queued, so that concurrent submissions can use them.
+/// The worker queue is locked only while dequeueing, never while a job is sent to a worker.
+async fn next_worker(state: &AppState, job: &Job) -> QueueResult<Option<Worker>> {
+    next_worker_matching(state, &|worker: &Worker| worker.can_process(job)).await
+}
+
+/// Removes the first worker which matches the predicate from the worker queue, like [`next_worker`].
+async fn next_worker_matching(state: &AppState, matches: &Predicate<'_, Worker>) -> QueueResult<Option<Worker>> {
+    loop {
+        let mut worker_queue = state.worker_queue.lock().await;
+        // Plain dequeueing is cheaper for some backends, so only match if the first worker cannot process the job.
+        // Workers which lack a required tag or do not accept the size of the job keep their place in the queue.
+        let dequeued = match worker_queue.peek().await? {
+            None => None,
+            Some(first) if matches(&first) => worker_queue.dequeue().await?,
+            Some(_) => worker_queue.dequeue_matching(matches).await?,
+        };
+        let Some(worker) = dequeued else {
+            return Ok(None);
+        };
+        // Another instance sharing the worker queue may have dequeued the first worker in the meantime
+        if !matches(&worker) {
+            worker_queue.enqueue(worker).await?;
+            continue;
+        }
+        if worker.slots > 1 {
+            let remaining = worker.clone().with_slots(worker.slots - 1);
+            if let Err(err) = worker_queue.enqueue(remaining).await {
+                error!(callback_url = %worker.callback_url, "Failed to persist worker to worker queue: '{err}'");
+            }
+        }
+        drop(worker_queue);
+        if let Some(ttl) = state.worker_ttl && worker.is_expired(ttl) {
+            info!(callback_url = %worker.callback_url, "Worker has not been seen for longer than {ttl:?}, discarding...");
+            continue;
+        }
+        if !worker.topics.is_empty() {
+            topic::release_other_topics(state, &worker).await;
+        }
+        return Ok(Some(worker.with_slots(1)));
+    }
+}
+
+/// Implements [`offer`], collecting the workers which should be queued again in `requeue`.
+/// No queue is locked while the job is sent to a worker, so that concurrent submissions are dispatched in parallel.
+#[rustfmt::skip]
+async fn offer_to_workers(state: &AppState, mut job: Job, mut first: Option<Worker>, requeue: &mut Vec<Worker>) -> Result<SubmitResult, Job> {
+    let job_id = job.id;
+    loop {
+        let dequeued = match first.take() {
+            Some(worker) => Ok(Some(worker)),
+            None => next_worker(state, &job).await,
+        };
+        let worker = match dequeued {
+            Ok(Some(worker)) => worker,
+            Ok(None) => break,
+            Err(err) => {
+                error!(%job_id, "Failed to dequeue from worker queue: '{err}', queueing job instead...");
+                break;
+            },
+        };
+        let callback_url = &worker.callback_url;
+        // Track the job before sending it, so a worker which reports its result quickly finds it.
+        if let Err(err) = job.track_assignment(state, callback_url).await {
+            error!(%job_id, "Failed to persist job to assigned jobs: '{err}'");
+            job.record_state(state, JobState::Failed).await;
+            // The worker did not receive the job, so it is queued again
+            requeue.push(worker);
+            return Ok(Err(err.into()));
+        }
+        let queue_time = Utc::now().signed_duration_since(worker.registered_at);
+        histogram!(telemetry::WORKER_QUEUE_TIME).record(queue_time.num_milliseconds() as f64 / 1000.0);
+        let queue_time = queue_time.num_seconds();
+        if let Err(failure) = send_job(state, &worker, &job).await {
+            job.untrack_assignment(state).await;
+            events::publish(state, JobEvent::DispatchFailed { id: job_id, callback_url: callback_url.clone() });
+            let requeue_worker = match state.failed_worker_policy {
+                FailedWorkerPolicy::Classify => failure == DispatchFailure::Transient,
+                FailedWorkerPolicy::Requeue => true,
+                FailedWorkerPolicy::Discard => false,
+            };
+            if requeue_worker {
+                info!(%job_id, callback_url, queue_time_secs = queue_time, "Worker failed to accept the job, queueing it again afterward...");
+                // The worker's other slots are held back as well, so that the job is not offered to the same worker again
+                let others = state.worker_queue.lock().await.remove_by_id(worker.id()).await;
+                let slots = 1 + others.ok().flatten().map_or(0, |others| others.slots);
+                requeue.push(worker.clone().with_slots(slots));
+            } else {
+                error!(%job_id, callback_url, queue_time_secs = queue_time, "Giving up on worker, discarding...");
+                // The worker's other slots are discarded along with it
+                if let Err(err) = state.worker_queue.lock().await.retain(&|queued: &Worker| queued.callback_url != *callback_url).await {
+                    error!(callback_url, "Failed to remove worker from worker queue: '{err}'");
+                }
+            }
+            job.dispatch_attempts += 1;
+            if let Some(max) = state.max_dispatch_attempts && job.dispatch_attempts >= max {
+                return Ok(dead_letter(state, job).await);
+            }
+            continue;
+        }
+        info!(%job_id, callback_url, queue_time_secs = queue_time, "Job submission received. Assigning to worker...");
+        counter!(telemetry::JOBS_ASSIGNED).increment(1);
+        Stats::count(&state.stats.jobs_assigned, 1);
+        events::publish(state, JobEvent::Assigned { id: job_id, callback_url: callback_url.clone() });
+        job.record_state(state, JobState::Assigned).await;
+        let callback_url = response_callback_url(state, callback_url);
+        return Ok(Ok((StatusCode::OK, Json(SubmitJobResponse::Assigned { id: job_id, callback_url }))));
+    }
+    Err(job)
+}
+
+/// Returns the callback URL of a worker as it is reported to the submitter of a job assigned to the worker.
+/// If worker URLs are redacted, only the origin of the URL (scheme, host and port) is reported, hiding its credentials,
+/// path and query; URLs without a host, such as those of workers connected via WebSocket, are reported as `redacted`.
+fn response_callback_url(state: &AppState, callback_url: &str) -> String {
+    if !state.redact_worker_urls {
+        return callback_url.to_owned();
+    }
+    Url::parse(callback_url).ok()
+        .filter(Url::has_host)
+        .map_or_else(|| "redacted".to_owned(), |url| url.origin().ascii_serialization())
+}
+
+/// Removes the first queued job which the given worker can process from the job queue.
+/// Jobs which waited longer than their time-to-live or the configured maximum job age are discarded on the way,
+/// so that they are never handed out.
+pub async fn dequeue_for_worker(state: &AppState, worker: &Worker) -> QueueResult<Option<Job>> {
+    loop {
+        let dequeued = state.job_queue.lock().await.dequeue_matching(&|job: &Job| worker.can_process(job)).await?;
+        let job = match dequeued {
+            Some(job) if job.is_stale(state.max_job_age) => job,
+            dequeued => return Ok(dequeued),
+        };
+        let queue_time = job.age().num_seconds();
+        info!(job_id = %job.id, queue_time_secs = queue_time, "Job waited longer than its time-to-live, discarding...");
+        counter!(telemetry::JOBS_EXPIRED).increment(1);
+        events::publish(state, JobEvent::Expired { id: job.id });
+        job.record_state(state, JobState::Expired).await;
+    }
+}
+
+/// Dispatches up to `count` queued jobs which the given worker can process, as if they were submitted just now.
+/// Used when a worker with several slots registers, so that its slots do not stay idle while suitable jobs are queued.
+/// The job queues of the given states are emptied in order, i.e. that of the worker's preferred topics.
+/// The jobs are dispatched to the first suitable queued workers, which are the worker's slots
+/// unless other workers were queued in the meantime.
+/// A job which can be neither assigned nor queued again is put back like in [`worker::return_queued_job`].
+pub async fn dispatch_queued_jobs(states: Vec<AppState>, worker: Worker, mut count: u32) {
+    for state in &states {
+        while count > 0 {
+            let job = match dequeue_for_worker(state, &worker).await {
+                Ok(Some(job)) => job,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed to dequeue from job queue: '{err}'");
+                    return;
+                },
+            };
+            info!(job_id = %job.id, callback_url = %worker.callback_url, "Dispatching queued job to a further slot of the worker...");
+            if let Err((err, job)) = dispatch(state, job).await {
+                warn!(job_id = %job.id, code = ?err.code(), "Queued job could be neither assigned nor queued again, putting it back...");
+                worker::return_queued_job(state, job).await;
+            }
+            count -= 1;
+        }
+    }
+}
+
+/// Queues a job which no worker accepted, which was first offered to the workers at `offered_at`.
+/// See [`submit_job`] for the possible responses.
+/// Right before, the worker queue is read once more for workers which registered since the job was first offered:
+/// such a worker may have registered after the worker queue was last read and found no job to take,
+/// and would otherwise stay queued next to the job. Workers which registered before, e.g. because they failed
+/// to accept the job, are not offered it again.
+async fn queue(state: &AppState, job: Job, offered_at: DateTime<Utc>) -> SubmitResult {
+    let worker = match next_worker_matching(state, &|worker: &Worker| worker.can_process(&job) && worker.registered_at >= offered_at).await {
+        Ok(worker) => worker,
+        Err(err) => {
+            error!(job_id = %job.id, "Failed to dequeue from worker queue: '{err}'");
+            None
+        },
+    };
+    let job = match worker {
+        Some(worker) => match offer(state, job, Some(worker)).await {
+            Ok(response) => return response,
+            Err(job) => job,
+        },
+        None => job,
+    };
+    let job_id = job.id;
+    info!(%job_id, "Job submission received. No workers available, queueing...");
+    // The job queue is only locked for the enqueue itself, not while the job state is recorded below
+    let enqueued = state.job_queue.lock().await.enqueue(job.clone()).await;
+    let position = match enqueued {
+        Ok(position) => position,
+        Err(QueueError::Full) => {
+            info!(%job_id, "Job queue is full, rejecting job...");
+            job.record_state(state, JobState::Failed).await;
+            return Err(job_queue_full());
+        },
+        Err(err) => {
+            error!(%job_id, "Failed to persist job to job queue: '{err}'");
+            job.record_state(state, JobState::Failed).await;
+            return Err(err.into());
+        },
+    };
+    counter!(telemetry::JOBS_QUEUED).increment(1);
+    events::publish(state, JobEvent::Queued { id: job_id, position });
+    job.record_state(state, JobState::Queued).await;
+    // Wake the workers which are waiting for a job, see [`worker::register_worker`](crate::worker::register_worker)
+    state.job_queued.notify_waiters();
+    Ok((StatusCode::ACCEPTED, Json(SubmitJobResponse::Queued { id: job_id, position })))
+}
+
+/// POST /job-result/{id}
+/// Reports the result of an assigned job, completing it. The request body can be any JSON value.
+/// Until its result is reported, the job is kept in-flight and dispatched again after a restart.
+/// If the job was submitted without a result callback URL, this endpoint responds with 200 OK and "Completed".
+/// If the job was submitted with a result callback URL, the result is sent there as
+/// `{"Result": {"id": <id>, "result": <body>}}` and this endpoint responds with 200 OK and "Delivered".
+/// If no assigned job with the given id awaits a result, this endpoint responds with 404 Not Found and "NotFound".
+/// If the result callback URL could not be reached or responded with a non-2xx code, this endpoint responds with
+/// 502 Bad Gateway and "CallbackFailed", and the result can be reported again.
+/// If the assigned jobs could not be persisted, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+#[rustfmt::skip]
+pub async fn job_result(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(result): Json<Value>
+) -> Result<(StatusCode, Json<JobResultResponse>), ApiError> {
+    telemetry::record_job_id(id);
+    let mut job = match state.assigned_jobs.lock().await.remove_by_id(id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => {
+            info!("Result received for unknown job {id}");
+            return Err(ApiError::not_found(format!("No assigned job with the id {id} awaits a result")));
+        },
+        Err(err) => {
+            error!("Failed to dequeue from assigned jobs: '{err}'");
+            return Err(err.into());
+        },
+    };
+    let Some(result_callback_url) = &job.result_callback_url else {
+        info!("Result received for job {id}. Job completed");
+        job.record_state(&state, JobState::Completed).await;
+        return Ok((StatusCode::OK, Json(JobResultResponse::Completed)));
+    };
+    let notification = ResultNotification::Result { id, result: &result };
+    let failure = match state.http_client.post(result_callback_url).json(&notification).send().await {
+        Err(err) => format!("'{err}'"),
+        Ok(response) if !response.status().is_success() => format!("non-2xx code ({})", response.status()),
+        Ok(_) => {
+            info!("Result received for job {id}. Delivered to {result_callback_url}");
+            job.record_state(&state, JobState::Completed).await;
+            return Ok((StatusCode::OK, Json(JobResultResponse::Delivered)));
+        },
+    };
+    error!("Failed to deliver result of job {id} to {result_callback_url}: {failure}, keeping job...");
+    // The worker gets another visibility timeout to report the result again
+    job.assigned_at = Some(Utc::now());
+    if let Err(err) = state.assigned_jobs.lock().await.enqueue(job).await {
+        error!("Failed to persist job to assigned jobs: '{err}'");
+        return Err(err.into());
+    }
+    Err(ApiError::new(StatusCode::BAD_GATEWAY, ErrorCode::CallbackFailed, "The result could not be delivered to the result callback URL"))
+}
+
+/// GET /jobs
+/// Lists the jobs queued without a topic in the order in which they will be dispatched.
+/// The jobs of a topic are listed by [`topic::list_jobs`].
+/// The optional `limit` query parameter caps the number of returned jobs.
+/// If the job queue could not be read, this endpoint responds with 500 Internal Server Error.
+#[rustfmt::skip]
+pub async fn list_jobs(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>
+) -> Result<Json<Vec<Job>>, ApiError> {
+    state.job_queue.lock().await.to_vec(query.limit).await.map(Json).map_err(|err| {
+        error!("Failed to read job queue: '{err}'");
+        err.into()
+    })
+}
+
+/// DELETE /jobs
+/// Removes all jobs from the job queue, and marks them as cancelled. Scheduled, assigned and dead-lettered jobs are not affected,
+/// and neither are the jobs of topics, which are removed by [`topic::clear_jobs`].
+/// Responds with 200 OK and "Cleared" along with the number of removed jobs,
+/// or with 500 Internal Server Error and "PersistenceFailed" if the job queue could not be persisted.
+pub async fn clear_jobs(State(state): State<AppState>) -> Result<(StatusCode, Json<ClearQueueResponse>), ApiError> {
+    // The job queue stays locked until the statuses are updated, so no job is queued in between
+    let mut job_queue = state.job_queue.lock().await;
+    // Only the jobs of this queue are cancelled, not the queued jobs of other topics
+    let ids: HashSet<Uuid> = match job_queue.to_vec(None).await {
+        Ok(jobs) => jobs.iter().map(Job::id).collect(),
+        Err(err) => {
+            error!("Failed to read job queue: '{err}'");
+            return Err(err.into());
+        },
+    };
+    match job_queue.clear().await {
+        Ok(removed) => {
+            info!(topic = state.topic.as_deref(), "Job queue cleared, removed {removed} job(s)");
+            let now = Utc::now();
+            state.job_statuses.lock().await.iter_mut()
+                .filter(|(id, status)| status.state == JobState::Queued && ids.contains(id))
+                .for_each(|(_, status)| *status = JobStatus { state: JobState::Cancelled, updated_at: now, ..*status });
+            Ok((StatusCode::OK, Json(ClearQueueResponse::Cleared { removed })))
+        },
+        Err(err) => {
+            error!("Failed to clear job queue: '{err}'");
+            Err(err.into())
+        },
+    }
+}
+
+/// DELETE /job/{id}
+/// Cancels a queued or scheduled job, removing it from the job queue it waits in, i.e. the global one or that of its topic,
+/// or from the scheduled jobs.
+/// The job is removed while the job queue is locked, so it cannot be dispatched concurrently.
+/// If the job was queued, this endpoint responds with 200 OK and "Cancelled".
+/// If no queued job has the given id, e.g. because it was already assigned to a worker,
+/// this endpoint responds with 404 Not Found and "NotFound".
+/// If the removal could not be persisted, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+#[rustfmt::skip]
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>
+) -> Result<(StatusCode, Json<CancelJobResponse>), ApiError> {
+    telemetry::record_job_id(id);
+    // The job may wait in the job queue of any topic, since the request does not name the topic
+    let mut queues = vec![state.job_queue.clone()];
+    queues.extend(state.topic_states().await.into_iter().map(|topic_state| topic_state.job_queue));
+    queues.push(state.scheduled_jobs.clone());
+    let mut cancelled = Ok(None);
+    for queue in queues {
+        cancelled = queue.lock().await.remove_by_id(id).await;
+        if !matches!(cancelled, Ok(None)) {
+            break;
+        }
+    }
+    match cancelled {
+        Ok(Some(job)) => {
+            info!("Job {id} cancelled");
+            job.record_state(&state, JobState::Cancelled).await;
+            Ok((StatusCode::OK, Json(CancelJobResponse::Cancelled)))
+        },
+        Ok(None) => Err(ApiError::not_found(format!("No queued or scheduled job has the id {id}"))),
+        Err(err) => {
+            error!("Failed to remove job {id} from job queue: '{err}'");
+            Err(err.into())
+        },
+    }
+}
+
+/// GET /job/{id}/status
+/// Returns the current state of the job with the given id, along with the time at which it was submitted
+/// and the time at which it entered its current state, e.g. `{"state": "Queued", "submitted_at": ..., "updated_at": ...}`.
+/// The states are kept in memory, so only jobs which were submitted or requeued since the service started are known,
+/// and the states of finished jobs are forgotten after the job status retention, see [`forget_finished_jobs`].
+/// If no job with the given id is known, this endpoint responds with 404 Not Found.
+#[rustfmt::skip]
+pub async fn job_status(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>
+) -> Result<Json<JobStatus>, ApiError> {
+    state.job_statuses.lock().await.get(&id).cloned().map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("No job with the id {id} is known")))
+}
+
+/// The response to a job position request.
+#[derive(Debug, Serialize)]
+pub enum JobPositionResponse {
+    /// The job waits in a job queue at the given 1-based position.
+    Queued { position: usize },
+    /// The job is known, but does not wait in a job queue anymore, or not yet. Its current state is provided.
+    NotQueued { state: JobState },
+}
+
+/// GET /job/{id}/position
+/// Returns the current position of the job with the given id in the job queue, e.g. `{"Queued": {"position": 3}}`,
+/// so that the submitter of a queued job can follow its progress by polling. The position of a job decreases
+/// as the jobs ahead of it are dispatched, but can also increase if jobs with a higher priority are submitted.
+/// If the job is known but not queued, e.g. because it was assigned, completed or is still scheduled,
+/// this endpoint responds with 200 OK and "NotQueued" along with the job's state, like [`job_status`].
+/// If no job with the given id is known, this endpoint responds with 404 Not Found and "NotFound".
+/// If a job queue could not be read, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+#[rustfmt::skip]
+pub async fn job_position(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>
+) -> Result<(StatusCode, Json<JobPositionResponse>), ApiError> {
+    let Some(status) = state.job_statuses.lock().await.get(&id).cloned() else {
+        return Err(ApiError::not_found(format!("No job with the id {id} is known")));
+    };
+    if status.state != JobState::Queued {
+        return Ok((StatusCode::OK, Json(JobPositionResponse::NotQueued { state: status.state })));
+    }
+    // The job may wait in the job queue of any topic, since its state does not record the topic
+    let mut states = vec![state.clone()];
+    states.extend(state.topic_states().await);
+    for topic_state in states {
+        let found = topic_state.job_queue.lock().await.position_of(&|job: &Job| job.id == id).await;
+        match found {
+            Ok(Some(position)) => return Ok((StatusCode::OK, Json(JobPositionResponse::Queued { position }))),
+            Ok(None) => {},
+            Err(err) => {
+                error!(job_id = %id, "Failed to read job queue: '{err}'");
+                return Err(err.into());
+            },
+        }
+    }
+    // The job was dequeued since its state was read
+    let job_state = state.job_statuses.lock().await.get(&id).map_or(status.state, |status| status.state);
+    Ok((StatusCode::OK, Json(JobPositionResponse::NotQueued { state: job_state })))
+}
+
+/// GET /dead-letter
+/// Lists the jobs in the dead-letter queue, i.e. the jobs which too many workers failed to accept.
+/// The optional `limit` query parameter caps the number of returned jobs.
+/// If the dead-letter queue could not be read, this endpoint responds with 500 Internal Server Error.
+#[rustfmt::skip]
+pub async fn list_dead_letter_jobs(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>
+) -> Result<Json<Vec<Job>>, ApiError> {
+    state.dead_letter_jobs.lock().await.to_vec(query.limit).await.map(Json).map_err(|err| {
+        error!("Failed to read dead-letter queue: '{err}'");
+        err.into()
+    })
+}
+
+/// POST /dead-letter/{id}/requeue
+/// Moves the dead-lettered job with the given id back into the job queue, with its dispatch attempts reset,
+/// e.g. once the problem which made the workers reject it is fixed. The dead-letter queue stays locked
+/// until the job is queued, so the job is never in both queues nor in neither.
+/// Responds with 200 OK and "Requeued" along with the position of the job in the job queue.
+/// If no dead-lettered job has the given id, this endpoint responds with 404 Not Found and "NotFound".
+/// If the job queue is full, this endpoint responds with 429 Too Many Requests and "QueueFull", and the job stays dead-lettered.
+/// If either queue could not be persisted, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+#[rustfmt::skip]
+pub async fn requeue_dead_letter_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>
+) -> Result<(StatusCode, Json<RequeueDeadLetterResponse>), ApiError> {
+    telemetry::record_job_id(id);
+    let mut dead_letter_jobs = state.dead_letter_jobs.lock().await;
+    let dead_lettered = match dead_letter_jobs.remove_by_id(id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Err(ApiError::not_found(format!("No dead-lettered job has the id {id}"))),
+        Err(err) => {
+            error!("Failed to remove job {id} from dead-letter queue: '{err}'");
+            return Err(err.into());
+        },
+    };
+    let job = Job { dispatch_attempts: 0, ..dead_lettered.clone() };
+    let position = match state.for_job(&job).await.job_queue.lock().await.enqueue(job.clone()).await {
+        Ok(position) => position,
+        Err(err) => {
+            // Put the job back, so that it is not lost
+            if let Err(err) = dead_letter_jobs.enqueue(dead_lettered).await {
+                error!("Failed to put job {id} back into the dead-letter queue, the job is lost: '{err}'");
+            }
+            if let QueueError::Full = err {
+                info!("Job queue is full, keeping job {id} in the dead-letter queue");
+                return Err(job_queue_full());
+            }
+            error!("Failed to persist job {id} to job queue: '{err}'");
+            return Err(err.into());
+        },
+    };
+    info!(job_id = %id, position, "Dead-lettered job requeued");
+    state.job_queued.notify_waiters();
+    job.record_state(&state, JobState::Queued).await;
+    events::publish(&state, JobEvent::Queued { id, position });
+    Ok((StatusCode::OK, Json(RequeueDeadLetterResponse::Requeued { position })))
+}
+
+/// POST /dead-letter/requeue-all
+/// Moves all dead-lettered jobs back into the job queue of their topic, with their dispatch attempts reset, like [`requeue_dead_letter_job`].
+/// Responds with 200 OK and "RequeuedAll" along with the number of requeued jobs.
+/// If the jobs of a topic do not all fit into its job queue, this endpoint responds with 429 Too Many Requests and "QueueFull",
+/// and these jobs stay dead-lettered along with those of the topics which were not requeued yet.
+/// If either queue could not be persisted, this endpoint responds with 500 Internal Server Error and "PersistenceFailed".
+pub async fn requeue_all_dead_letter_jobs(State(state): State<AppState>) -> Result<(StatusCode, Json<RequeueDeadLetterResponse>), ApiError> {
+    let mut dead_letter_jobs = state.dead_letter_jobs.lock().await;
+    let jobs: Vec<Job> = match dead_letter_jobs.to_vec(None).await {
+        Ok(jobs) => jobs.into_iter().map(|job| Job { dispatch_attempts: 0, ..job }).collect(),
+        Err(err) => {
+            error!("Failed to read dead-letter queue: '{err}'");
+            return Err(err.into());
+        },
+    };
+    let mut topics: BTreeMap<Option<String>, Vec<Job>> = BTreeMap::new();
+    for job in jobs {
+        topics.entry(job.topic.clone()).or_default().push(job);
+    }
+    let mut requeued = Vec::new();
+    let mut failure = None;
+    for topic_jobs in topics.into_values() {
+        let topic_state = state.for_job(&topic_jobs[0]).await;
+        match topic_state.job_queue.lock().await.enqueue_many(topic_jobs.clone()).await {
+            Ok(positions) => requeued.extend(topic_jobs.into_iter().zip(positions)),
+            Err(QueueError::Full) => {
+                info!("Job queue is full, keeping {} job(s) in the dead-letter queue", topic_jobs.len());
+                failure = Some(job_queue_full());
+                break;
+            },
+            Err(err) => {
+                error!("Failed to persist dead-lettered jobs to job queue: '{err}'");
+                failure = Some(err.into());
+                break;
+            },
+        }
+    }
+    // The jobs are only removed once they were queued, so a failure can cause a job to be dispatched twice, but never loses it
+    let requeued_ids: HashSet<Uuid> = requeued.iter().map(|(job, _)| job.id).collect();
+    if let Err(err) = dead_letter_jobs.retain(&|job: &Job| !requeued_ids.contains(&job.id)).await {
+        error!("Failed to remove requeued jobs from the dead-letter queue: '{err}'");
+    }
+    info!("Requeued {} dead-lettered job(s)", requeued.len());
+    if !requeued.is_empty() {
+        state.job_queued.notify_waiters();
+    }
+    for (job, position) in &requeued {
+        job.record_state(&state, JobState::Queued).await;
+        events::publish(&state, JobEvent::Queued { id: job.id, position: *position });
+    }
+    if let Some(err) = failure {
+        return Err(err);
+    }
+    Ok((StatusCode::OK, Json(RequeueDeadLetterResponse::RequeuedAll { requeued: requeued.len() })))
+}
+
+/// Moves the jobs which were in-flight when the service last stopped back into the job queue,
+/// so that they are dispatched again. Each job is only removed from the in-flight jobs once it was queued,
+/// so a crash during this operation can cause a job to be dispatched twice, but never loses it.
+pub async fn requeue_assigned_jobs(state: &AppState) {
+    let assigned = match state.assigned_jobs.lock().await.to_vec(None).await {
+        Ok(assigned) => assigned,
+        Err(err) => {
+            error!("Failed to read assigned jobs: '{err}'");
+            return;
+        },
+    };
+    if assigned.is_empty() {
+        return;
+    }
+    info!("Requeueing {} job(s) which were in-flight when the service last stopped...", assigned.len());
+    for mut job in assigned {
+        let id = job.id;
+        job.assigned_at = None;
+        job.assigned_to = None;
+        if let Err(err) = state.for_job(&job).await.job_queue.lock().await.enqueue(job.clone()).await {
+            error!("Failed to requeue in-flight job {id}: '{err}', keeping it in-flight...");
+            continue;
+        }
+        job.record_state(state, JobState::Queued).await;
+        if let Err(err) = state.assigned_jobs.lock().await.retain(&|job: &Job| job.id != id).await {
+            error!("Failed to remove requeued job {id} from assigned jobs: '{err}'");
+        }
+    }
+}
+
+/// Dispatches the scheduled jobs whose `not_before` time has passed, as if they were submitted just now.
+/// Runs forever, checking once per interval.
+/// A job which can be neither assigned nor queued, e.g. because the job queue is full, stays scheduled until the next check;
+/// a job which too many workers failed to accept is dead-lettered as usual, and not scheduled again.
+pub async fn dispatch_scheduled_jobs(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        loop {
+            let job = match state.scheduled_jobs.lock().await.dequeue_matching(&Job::is_ready).await {
+                Ok(Some(job)) => job,
+                Ok(None) => break,
+                Err(err) => {
+                    error!("Failed to dequeue from scheduled jobs: '{err}'");
+                    break;
+                },
+            };
+            info!("Scheduled job {} is due, dispatching...", job.id);
+            // A job which was assigned, queued or dead-lettered is no longer scheduled
+            let job = match dispatch(&state.for_job(&job).await, job).await {
+                Ok(_) => continue,
+                Err((_, job)) => job,
+            };
+            let rescheduled = state.scheduled_jobs.lock().await.enqueue(job.clone()).await;
+            match rescheduled {
+                Ok(_) => job.record_state(&state, JobState::Scheduled).await,
+                Err(err) => error!("Failed to persist job to scheduled jobs: '{err}'"),
+            }
+            break;
+        }
+    }
+}
+
+/// Dispatches the in-flight jobs again whose worker did not report a result within the visibility timeout,
+/// e.g. because it crashed after accepting the job. Runs forever, checking once per interval.
+/// The expired assignment is only forgotten once the job was assigned to another worker, queued or dead-lettered,
+/// so a crash during this operation can cause a job to be dispatched twice, but never loses it.
+/// A job which can be neither assigned nor queued, e.g. because the job queue is full, stays in-flight until the next check.
+pub async fn requeue_expired_assignments(state: AppState, timeout: Duration, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let expired: Vec<Job> = match state.assigned_jobs.lock().await.to_vec(None).await {
+            Ok(assigned) => assigned.into_iter().filter(|job| job.is_assignment_expired(timeout)).collect(),
+            Err(err) => {
+                error!("Failed to read assigned jobs: '{err}'");
+                continue;
+            },
+        };
+        for job in expired {
+            let (id, assigned_at) = (job.id, job.assigned_at);
+            warn!(job_id = %id, "No result was reported for job within {timeout:?}, dispatching it again...");
+            let topic_state = state.for_job(&job).await;
+            if let Err((err, _)) = dispatch(&topic_state, Job { assigned_at: None, assigned_to: None, ..job }).await {
+                warn!(job_id = %id, code = ?err.code(), "Expired job could be neither assigned nor queued, keeping it in-flight...");
+                continue;
+            }
+            if let Err(err) = state.assigned_jobs.lock().await.retain(&|job: &Job| job.id != id || job.assigned_at != assigned_at).await {
+                error!(job_id = %id, "Failed to remove requeued job from assigned jobs: '{err}'");
+            }
+        }
+    }
+}
+
+/// Forgets the states of the finished jobs (see [`JobState::is_finished`]) which entered their state longer than
+/// `retention` ago, so that the states of all jobs submitted since the service started do not accumulate in memory.
+/// Runs forever, checking once per interval. The forgotten jobs are no longer known to [`job_status`] and [`job_position`].
+/// A dead-lettered job is still listed and can be requeued, which records its state anew.
+pub async fn forget_finished_jobs(state: AppState, retention: Duration, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let cutoff = Utc::now() - retention;
+        let mut job_statuses = state.job_statuses.lock().await;
+        let before = job_statuses.len();
+        job_statuses.retain(|_, status| !status.state.is_finished() || status.updated_at > cutoff);
+        let forgotten = before - job_statuses.len();
+        if forgotten > 0 {
+            info!("Forgot the states of {forgotten} job(s) which finished more than {retention:?} ago");
+        }
+    }
+}
+
+/// Dispatches the in-flight jobs of the worker with the given callback URL again, because the worker deregistered
+/// and will not report their results. Returns the number of jobs which were in-flight at the worker.
+/// As for an expired assignment, the assignment is only forgotten once the job was assigned to another worker, queued or dead-lettered,
+/// so a job whose result the worker still reports in the meantime can be dispatched twice, but is never lost.
+/// A job which can be neither assigned nor queued, e.g. because the job queue is full, stays in-flight until the visibility timeout.
+/// Returns an error if the in-flight jobs could not be read.
+pub async fn requeue_jobs_of_worker(state: &AppState, callback_url: &str) -> QueueResult<usize> {
+    let in_flight: Vec<Job> = state.assigned_jobs.lock().await.to_vec(None).await?
+        .into_iter()
+        .filter(|job| job.assigned_to.as_deref() == Some(callback_url))
+        .collect();
+    let count = in_flight.len();
+    for job in in_flight {
+        let (id, assigned_at) = (job.id, job.assigned_at);
+        info!(job_id = %id, callback_url, "Worker deregistered while the job was in-flight, dispatching it again...");
+        let topic_state = state.for_job(&job).await;
+        if let Err((err, _)) = dispatch(&topic_state, Job { assigned_at: None, assigned_to: None, ..job }).await {
+            warn!(job_id = %id, code = ?err.code(), "Job of deregistered worker could be neither assigned nor queued, keeping it in-flight...");
+            continue;
+        }
+        if let Err(err) = state.assigned_jobs.lock().await.retain(&|job: &Job| job.id != id || job.assigned_at != assigned_at).await {
+            error!(job_id = %id, "Failed to remove requeued job from assigned jobs: '{err}'");
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{BoundedQueue, InMemoryQueue, JsonFileQueue, Queue};
+    use crate::callback_filter::CallbackFilter;
+    use crate::tests::{mock_server, serve};
+    use axum::body::Body;
+    use axum::extract::{DefaultBodyLimit, Request};
+    use axum::http::HeaderValue;
+    use axum::routing::post;
+    use axum::Router;
+    use axum::response::IntoResponse;
+    use clap::Parser;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio::sync::{mpsc, Mutex, Semaphore};
+
+    #[tokio::test]
+    async fn finds_and_removes_jobs_by_id() {
+        let dir = TempDir::new().unwrap();
+        let queues: [Queue<Job>; 2] = [Box::new(InMemoryQueue::new()), Box::new(JsonFileQueue::new(dir.path().join("jobs.json")))];
+        for mut queue in queues {
+            let jobs: Vec<Job> = (0..3).map(|customer| Job::new(json!({ "drink": "mojito", "customer": customer }))).collect();
+            queue.enqueue_many(jobs.clone()).await.unwrap();
+
+            let found = queue.find_by_id(jobs[1].id()).await.unwrap().unwrap();
+            assert_eq!(found.data, jobs[1].data);
+            let removed = queue.remove_by_id(jobs[1].id()).await.unwrap().unwrap();
+            assert_eq!(removed.id, jobs[1].id);
+            assert!(queue.find_by_id(jobs[1].id()).await.unwrap().is_none());
+            assert!(queue.remove_by_id(jobs[1].id()).await.unwrap().is_none());
+
+            let remaining: Vec<Uuid> = queue.to_vec(None).await.unwrap().iter().map(Job::id).collect();
+            assert_eq!(remaining, [jobs[0].id, jobs[2].id]);
+        }
+    }
+
+    #[tokio::test]
+    async fn callback_headers_are_only_sent_to_workers() {
+        let mut state = crate::tests::state();
+        state.callback_headers = Arc::new(HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_static("Bearer worker-token"))]));
+        let (worker_url, mut worker_requests) = mock_server(StatusCode::OK).await;
+        let (result_url, mut result_requests) = mock_server(StatusCode::OK).await;
+        let job = Job::new(json!({ "drink": "mojito", "result_callback_url": result_url }));
+
+        send_job(&state, &Worker::new(worker_url, vec![]), &job).await.unwrap();
+        let received = worker_requests.recv().await.unwrap();
+        assert_eq!(received.headers[header::AUTHORIZATION], "Bearer worker-token");
+        assert_eq!(received.headers[JOB_ID_HEADER], job.id.to_string());
+
+        state.assigned_jobs.lock().await.enqueue(job.clone()).await.unwrap();
+        let (status, _) = job_result(State(state), Path(job.id), Json(json!({ "served": true }))).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let received = result_requests.recv().await.unwrap();
+        assert!(!received.headers.contains_key(header::AUTHORIZATION));
+        assert_eq!(received.body["Result"]["result"]["served"], true);
+    }
+
+    /// Returns the query of a submission with the default options.
+    fn submit_query() -> Query<SubmitQuery> {
+        Query(SubmitQuery { mode: SubmitMode::Queue, wait: None })
+    }
+
+    #[tokio::test]
+    async fn unsafe_result_callback_urls_are_rejected() {
+        let mut state = crate::tests::state();
+        let deny = ["169.254.0.0/16", "10.0.0.0/8"].map(|pattern| pattern.parse().unwrap());
+        state.callback_filter = Arc::new(CallbackFilter::new(Vec::new(), deny.into()));
+        let too_long = format!("http://example.com/{}", "a".repeat(state.max_callback_url_length));
+        let rejected = [
+            ("file:///etc/passwd", "UnsupportedScheme"),
+            ("ftp://example.com/results", "UnsupportedScheme"),
+            ("http://169.254.169.254/latest/meta-data", "HostNotAllowed"),
+            ("http://10.0.0.1/results", "HostNotAllowed"),
+            ("not a url", "NotAUrl"),
+            (too_long.as_str(), "TooLong"),
+        ];
+        for (url, code) in rejected {
+            let err = submit_job(State(state.clone()), submit_query(), Json(json!({ "result_callback_url": url }))).await.unwrap_err();
+            assert_eq!(serde_json::to_value(err.code()).unwrap(), code, "{url}");
+            assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+
+            let uri = format!("/submit-raw-job?result_callback_url={}", url.replace(' ', "%20")).parse().unwrap();
+            let err = submit_raw_job(State(state.clone()), Query::try_from_uri(&uri).unwrap(), HeaderMap::new(), Bytes::new()).await.unwrap_err();
+            assert_eq!(serde_json::to_value(err.code()).unwrap(), code, "{url}");
+        }
+        let (_, Json(responses)) = submit_jobs(State(state.clone()), Json(vec![
+            json!({ "result_callback_url": "http://169.254.169.254/" }),
+            json!({ "result_callback_url": "https://192.0.2.1/results" }),
+        ])).await;
+        assert!(matches!(&responses[0], BatchJobResponse::Rejected(err) if err.code() == ErrorCode::Callback(worker::CallbackHeaderError::HostNotAllowed)));
+        assert!(matches!(&responses[1], BatchJobResponse::Accepted(SubmitJobResponse::Queued { .. })));
+        assert_eq!(state.job_queue.lock().await.len().await.unwrap(), 1);
+        assert_eq!(state.job_statuses.lock().await.len(), 1);
+
+        let errors = validate(&state, &json!({ "result_callback_url": 42 })).unwrap_err();
+        assert_eq!(errors, ["/result_callback_url: 42 is not a string"]);
+    }
+
+    #[tokio::test]
+    async fn results_are_forwarded_to_the_result_callback_url() {
+        let state = crate::tests::state();
+        let (result_url, mut result_requests) = mock_server(StatusCode::OK).await;
+        let (status, Json(response)) = submit_job(State(state.clone()), submit_query(), Json(json!({ "result_callback_url": result_url }))).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let SubmitJobResponse::Queued { id, .. } = response else { panic!("{response:?}") };
+        // The job is assigned to a worker which takes it from the queue
+        let job = state.job_queue.lock().await.dequeue().await.unwrap().unwrap();
+        state.assigned_jobs.lock().await.enqueue(job).await.unwrap();
+
+        let (status, Json(response)) = job_result(State(state.clone()), Path(id), Json(json!({ "served": "mojito" }))).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(response, JobResultResponse::Delivered));
+        let received = result_requests.recv().await.unwrap();
+        assert_eq!(received.body["Result"]["id"], id.to_string());
+        assert_eq!(received.body["Result"]["result"]["served"], "mojito");
+        assert!(state.assigned_jobs.lock().await.is_empty().await.unwrap());
+        assert_eq!(state.job_statuses.lock().await[&id].state, JobState::Completed);
+    }
+
+    #[tokio::test]
+    async fn undeliverable_results_keep_the_job_assigned() {
+        let state = crate::tests::state();
+        let (result_url, mut result_requests) = mock_server(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let job = Job::new(json!({ "result_callback_url": result_url }));
+        state.assigned_jobs.lock().await.enqueue(job.clone()).await.unwrap();
+
+        let err = job_result(State(state.clone()), Path(job.id), Json(json!({ "served": "mojito" }))).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::CallbackFailed);
+        assert_eq!(err.into_response().status(), StatusCode::BAD_GATEWAY);
+        assert!(result_requests.recv().await.is_some());
+        // The worker can report the result again
+        assert!(state.assigned_jobs.lock().await.find_by_id(job.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn queued_job_which_cannot_be_dispatched_is_kept() {
+        let mut state = crate::tests::state();
+        let job = Job::new(json!({ "drink": "mojito" }));
+        let mut inner: Queue<Job> = Box::new(InMemoryQueue::new());
+        inner.enqueue(job.clone()).await.unwrap();
+        // The job can be dequeued, but neither queued again nor assigned, since no worker is queued
+        state.job_queue = Arc::new(Mutex::new(Box::new(BoundedQueue::new(inner, 0))));
+
+        dispatch_queued_jobs(vec![state.clone()], Worker::new("http://localhost:9000/", vec![]).with_slots(2), 1).await;
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+        assert!(state.scheduled_jobs.lock().await.find_by_id(job.id).await.unwrap().is_some());
+        assert_eq!(state.job_statuses.lock().await[&job.id].state, JobState::Scheduled);
+    }
+
+    #[tokio::test]
+    async fn states_of_finished_jobs_are_forgotten_after_the_retention() {
+        let state = crate::tests::state();
+        let job_states = [
+            JobState::Submitted, JobState::Scheduled, JobState::Queued, JobState::Assigned,
+            JobState::Completed, JobState::Failed, JobState::DeadLettered, JobState::Expired, JobState::Cancelled,
+        ];
+        let jobs = job_states.map(|_| Job::new(json!({ "drink": "mojito" })));
+        let long_ago = Utc::now() - TimeDelta::hours(2);
+        let mut job_statuses = state.job_statuses.lock().await;
+        for (job, job_state) in jobs.iter().zip(job_states) {
+            job_statuses.insert(job.id, JobStatus { state: job_state, submitted_at: long_ago, updated_at: long_ago });
+        }
+        // A job which finished just now is kept
+        let recent = Job::new(json!({ "drink": "mojito" }));
+        job_statuses.insert(recent.id, JobStatus { state: JobState::Completed, submitted_at: long_ago, updated_at: Utc::now() });
+        drop(job_statuses);
+
+        // The first check happens right away
+        let reaper = tokio::spawn(forget_finished_jobs(state.clone(), Duration::from_secs(60 * 60), Duration::from_secs(60)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        reaper.abort();
+        let job_statuses = state.job_statuses.lock().await;
+        let kept: Vec<JobState> = jobs.iter().filter_map(|job| job_statuses.get(&job.id)).map(|status| status.state).collect();
+        assert_eq!(kept, [JobState::Submitted, JobState::Scheduled, JobState::Queued, JobState::Assigned]);
+        assert!(job_statuses.contains_key(&recent.id));
+    }
+
+    #[tokio::test]
+    async fn oversized_submissions_are_rejected_without_enqueueing() {
+        let state = crate::tests::state();
+        let limit = || DefaultBodyLimit::max(64);
+        let routes = Router::new()
+            .route("/submit-job", post(submit_job).layer(limit()))
+            .route("/submit-jobs", post(submit_jobs).layer(limit()))
+            .route("/submit-raw-job", post(submit_raw_job).layer(limit()))
+            .with_state(state.clone());
+        let url = serve(routes).await;
+        let client = reqwest::Client::new();
+        let drink = "mojito".repeat(10);
+
+        let oversized = [
+            client.post(format!("{url}submit-job")).json(&json!({ "drink": drink })),
+            client.post(format!("{url}submit-jobs")).json(&json!([{ "drink": "mojito" }, { "drink": drink }])),
+            client.post(format!("{url}submit-raw-job")).body(drink.repeat(2)),
+        ];
+        for request in oversized {
+            assert_eq!(request.send().await.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+        assert!(state.job_statuses.lock().await.is_empty());
+
+        let response = client.post(format!("{url}submit-job")).json(&json!({ "drink": "mojito" })).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(state.job_queue.lock().await.len().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn expired_workers_are_skipped() {
+        let mut state = crate::tests::state();
+        state.worker_ttl = Some(Duration::from_secs(60));
+        let (stale_url, mut stale_requests) = mock_server(StatusCode::OK).await;
+        let (fresh_url, mut fresh_requests) = mock_server(StatusCode::OK).await;
+        let stale = Worker { last_seen: Utc::now() - TimeDelta::minutes(2), ..Worker::new(stale_url, vec![]) };
+        state.worker_queue.lock().await.enqueue_many(vec![stale, Worker::new(fresh_url.clone(), vec![])]).await.unwrap();
+
+        let (_, Json(response)) = submit_job(State(state.clone()), submit_query(), Json(json!({ "drink": "mojito" }))).await.unwrap();
+        assert!(matches!(&response, SubmitJobResponse::Dispatching { callback_url, .. } if *callback_url == fresh_url), "{response:?}");
+        assert!(fresh_requests.recv().await.is_some());
+        assert!(stale_requests.try_recv().is_err());
+        assert!(state.worker_queue.lock().await.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn jobs_are_queued_until_a_worker_with_their_tags_is_available() {
+        let state = crate::tests::state();
+        let (worker_url, mut worker_requests) = mock_server(StatusCode::OK).await;
+        state.worker_queue.lock().await.enqueue(Worker::new(worker_url.clone(), vec!["gpu".into()]).with_slots(2)).await.unwrap();
+
+        let data = json!({ "drink": "mojito", "required_tags": ["gpu", "tpu"] });
+        let (status, Json(response)) = submit_job(State(state.clone()), submit_query(), Json(data)).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(matches!(response, SubmitJobResponse::Queued { position: 1, .. }), "{response:?}");
+        assert_eq!(state.worker_queue.lock().await.peek().await.unwrap().unwrap().slots, 2);
+
+        let data = json!({ "drink": "mojito", "required_tags": ["gpu"] });
+        let (_, Json(response)) = submit_job(State(state.clone()), submit_query(), Json(data)).await.unwrap();
+        assert!(matches!(&response, SubmitJobResponse::Dispatching { callback_url, .. } if *callback_url == worker_url), "{response:?}");
+        assert_eq!(worker_requests.recv().await.unwrap().body["Job"]["data"]["required_tags"], json!(["gpu"]));
+        assert_eq!(state.job_queue.lock().await.len().await.unwrap(), 1);
+    }
+
+    /// Returns the callback URLs of the queued workers, in the order of the worker queue.
+    async fn queued_workers(state: &AppState) -> Vec<String> {
+        state.worker_queue.lock().await.to_vec(None).await.unwrap().into_iter().map(|worker| worker.callback_url).collect()
+    }
+
+    #[tokio::test]
+    async fn workers_which_do_not_respond_in_time_are_passed_over() {
+        let mut state = crate::tests::state();
+        state.http_client = crate::callback_client(&crate::Args::try_parse_from(["job-dispatcher-service", "--callback-timeout", "1"]).unwrap());
+        let unresponsive_url = serve(Router::new().fallback(std::future::pending::<StatusCode>)).await;
+        let (worker_url, mut worker_requests) = mock_server(StatusCode::OK).await;
+        let workers = vec![Worker::new(unresponsive_url.clone(), vec![]), Worker::new(worker_url, vec![])];
+        state.worker_queue.lock().await.enqueue_many(workers).await.unwrap();
+
+        let started = Instant::now();
+        assert_eq!(dispatch(&state, Job::new(json!({ "drink": "mojito" }))).await.unwrap(), Dispatched::Assigned);
+        assert!((Duration::from_secs(1)..Duration::from_secs(5)).contains(&started.elapsed()));
+        assert!(worker_requests.recv().await.is_some());
+        // A timeout is a transient failure, so the unresponsive worker is queued again
+        assert_eq!(queued_workers(&state).await, [unresponsive_url]);
+    }
+
+    /// Serves an endpoint which responds with the given status codes in turn, and with the last one from then on,
+    /// and returns its URL and the channel through which it passes on the times at which it received requests.
+    async fn scripted_server(statuses: Vec<StatusCode>) -> (String, mpsc::UnboundedReceiver<Instant>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let received = Arc::new(AtomicUsize::new(0));
+        let routes = Router::new().fallback(move || async move {
+            sender.send(Instant::now()).unwrap();
+            let index = received.fetch_add(1, Ordering::SeqCst).min(statuses.len() - 1);
+            statuses[index]
+        });
+        (serve(routes).await, receiver)
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_are_retried_with_exponential_backoff() {
+        let mut state = crate::tests::state();
+        state.callback_attempts = 3;
+        state.callback_backoff = Duration::from_millis(50);
+        let job = Job::new(json!({ "drink": "mojito" }));
+
+        let (url, mut requests) = scripted_server(vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::TOO_MANY_REQUESTS, StatusCode::OK]).await;
+        assert_eq!(send_job(&state, &Worker::new(url, vec![]), &job).await, Ok(()));
+        let attempts: Vec<Instant> = (0..3).map(|_| requests.try_recv().unwrap()).collect();
+        assert!(attempts[1] - attempts[0] >= Duration::from_millis(50));
+        assert!(attempts[2] - attempts[1] >= Duration::from_millis(100));
+
+        let (url, mut requests) = scripted_server(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+        assert_eq!(send_job(&state, &Worker::new(url, vec![]), &job).await, Err(DispatchFailure::Transient));
+        let (url, mut not_found_requests) = scripted_server(vec![StatusCode::NOT_FOUND]).await;
+        assert_eq!(send_job(&state, &Worker::new(url, vec![]), &job).await, Err(DispatchFailure::Permanent));
+        for requests in [&mut requests, &mut not_found_requests] {
+            let mut attempts = 0;
+            while requests.try_recv().is_ok() {
+                attempts += 1;
+            }
+            assert_eq!(attempts, 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_are_dead_lettered_after_too_many_failed_dispatches() {
+        let mut state = crate::tests::state();
+        state.max_dispatch_attempts = Some(2);
+        let (mut urls, mut requests) = (Vec::new(), Vec::new());
+        for _ in 0..3 {
+            let (url, received) = mock_server(StatusCode::INTERNAL_SERVER_ERROR).await;
+            urls.push(url);
+            requests.push(received);
+        }
+        let workers = urls.iter().map(|url| Worker::new(url.clone(), vec![])).collect();
+        state.worker_queue.lock().await.enqueue_many(workers).await.unwrap();
+        let job = Job::new(json!({ "drink": "mojito" }));
+
+        assert_eq!(dispatch(&state, job.clone()).await.unwrap(), Dispatched::DeadLettered);
+        let dead_lettered = state.dead_letter_jobs.lock().await.to_vec(None).await.unwrap();
+        assert_eq!(dead_lettered.iter().map(|job| (job.id, job.dispatch_attempts)).collect::<Vec<_>>(), [(job.id, 2)]);
+        assert_eq!(state.job_statuses.lock().await[&job.id].state, JobState::DeadLettered);
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+        // The third worker is not offered the job, and the failed workers are queued again behind it
+        assert!(requests[0].try_recv().is_ok() && requests[1].try_recv().is_ok());
+        assert!(requests[2].try_recv().is_err());
+        assert_eq!(queued_workers(&state).await, [urls[2].as_str(), &urls[0], &urls[1]]);
+    }
+
+    #[tokio::test]
+    async fn failed_workers_are_queued_again_or_discarded_according_to_the_policy() {
+        let cases = [
+            (FailedWorkerPolicy::Classify, StatusCode::SERVICE_UNAVAILABLE, true),
+            (FailedWorkerPolicy::Classify, StatusCode::NOT_FOUND, false),
+            (FailedWorkerPolicy::Requeue, StatusCode::NOT_FOUND, true),
+            (FailedWorkerPolicy::Discard, StatusCode::SERVICE_UNAVAILABLE, false),
+        ];
+        for (policy, status, requeued) in cases {
+            let mut state = crate::tests::state();
+            state.failed_worker_policy = policy;
+            let (url, mut requests) = mock_server(status).await;
+            state.worker_queue.lock().await.enqueue(Worker::new(url.clone(), vec![]).with_slots(2)).await.unwrap();
+            let job = Job::new(json!({ "drink": "mojito" }));
+
+            // The job is offered to the worker once, and queued since no other worker accepts it
+            assert_eq!(dispatch(&state, job.clone()).await.unwrap(), Dispatched::Queued, "{policy} {status}");
+            assert!(requests.try_recv().is_ok() && requests.try_recv().is_err());
+            assert_eq!(state.job_queue.lock().await.peek().await.unwrap().unwrap().id, job.id);
+            let queued = state.worker_queue.lock().await.to_vec(None).await.unwrap();
+            if requeued {
+                // The worker keeps all of its slots
+                assert_eq!(queued.iter().map(|worker| (worker.callback_url.as_str(), worker.slots)).collect::<Vec<_>>(), [(url.as_str(), 2)]);
+            } else {
+                assert!(queued.is_empty(), "{policy} {status}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn flaky_workers_do_not_keep_jobs_from_healthy_workers() {
+        let state = crate::tests::state();
+        let (flaky_url, mut flaky_requests) = mock_server(StatusCode::SERVICE_UNAVAILABLE).await;
+        let (healthy_url, mut healthy_requests) = mock_server(StatusCode::OK).await;
+        let workers = vec![Worker::new(flaky_url.clone(), vec![]), Worker::new(healthy_url, vec![]).with_slots(2)];
+        state.worker_queue.lock().await.enqueue_many(workers).await.unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(dispatch(&state, Job::new(json!({ "drink": "mojito" }))).await.unwrap(), Dispatched::Assigned);
+            assert!(healthy_requests.try_recv().is_ok());
+        }
+        // The flaky worker was queued again behind the healthy one, so it was only offered the first job
+        assert!(flaky_requests.try_recv().is_ok() && flaky_requests.try_recv().is_err());
+        assert_eq!(queued_workers(&state).await, [flaky_url]);
+    }
+
+    #[tokio::test]
+    async fn waiting_submissions_are_assigned_to_workers_registering_in_the_meantime() {
+        let state = crate::tests::state();
+        let (worker_url, mut worker_requests) = mock_server(StatusCode::OK).await;
+        let query = Query(SubmitQuery { mode: SubmitMode::Queue, wait: Some(5) });
+        let submission = tokio::spawn(submit_job(State(state.clone()), query, Json(json!({ "drink": "mojito" }))));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!submission.is_finished());
+        let registration = Request::builder().header("cpee-callback", &worker_url).body(Body::empty()).unwrap();
+        let query = Query(worker::RegisterWorkerQuery { wait: None });
+        let response = worker::register_worker(State(state.clone()), query, registration).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let (_, Json(response)) = tokio::time::timeout(Duration::from_secs(1), submission).await.unwrap().unwrap().unwrap();
+        assert!(matches!(&response, SubmitJobResponse::Dispatching { callback_url, .. } if *callback_url == worker_url), "{response:?}");
+        assert!(worker_requests.recv().await.is_some());
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn waiting_submissions_are_queued_once_the_wait_elapsed() {
+        let state = crate::tests::state();
+        let started = Instant::now();
+        let query = Query(SubmitQuery { mode: SubmitMode::Queue, wait: Some(1) });
+        let (_, Json(response)) = submit_job(State(state.clone()), query, Json(json!({ "drink": "mojito" }))).await.unwrap();
+        assert!(matches!(response, SubmitJobResponse::Queued { position: 1, .. }), "{response:?}");
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn waiting_submissions_poll_for_workers_queued_by_other_instances() {
+        let mut state = crate::tests::state();
+        state.wait_poll_interval = Some(Duration::from_millis(10));
+        let (worker_url, mut worker_requests) = mock_server(StatusCode::OK).await;
+        let started = Instant::now();
+        let query = Query(SubmitQuery { mode: SubmitMode::Queue, wait: Some(5) });
+        let submission = tokio::spawn(submit_job(State(state.clone()), query, Json(json!({ "drink": "mojito" }))));
+
+        // Another instance sharing the worker queue queues the worker, so this instance is not woken
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        state.worker_queue.lock().await.enqueue(Worker::new(worker_url.clone(), vec![])).await.unwrap();
+
+        let (_, Json(response)) = submission.await.unwrap().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(matches!(&response, SubmitJobResponse::Dispatching { callback_url, .. } if *callback_url == worker_url), "{response:?}");
+        assert!(worker_requests.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrent_callbacks_are_limited() {
+        let mut state = crate::tests::state();
+        state.callback_permits = Some(Arc::new(Semaphore::new(2)));
+        // The workers take a while to respond, and their endpoint records how many requests it handled at once
+        let (in_flight, most_in_flight) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let routes = Router::new().fallback({
+            let (in_flight, most_in_flight) = (in_flight.clone(), most_in_flight.clone());
+            move || async move {
+                most_in_flight.fetch_max(in_flight.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                sender.send(()).unwrap();
+                StatusCode::OK
+            }
+        });
+        let url = serve(routes).await;
+        let workers = (0..8).map(|index| Worker::new(format!("{url}workers/{index}"), vec![])).collect();
+        state.worker_queue.lock().await.enqueue_many(workers).await.unwrap();
+
+        for _ in 0..8 {
+            let (_, Json(response)) = submit_job(State(state.clone()), submit_query(), Json(json!({ "drink": "mojito" }))).await.unwrap();
+            assert!(matches!(response, SubmitJobResponse::Dispatching { .. }), "{response:?}");
+        }
+        for _ in 0..8 {
+            received.recv().await.unwrap();
+        }
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn job_id_is_taken_from_the_data() {
+        let id = Uuid::new_v4();
+        assert_eq!(Job::new(json!({ "id": id.to_string() })).id(), id);
+        assert_ne!(Job::new(json!({ "id": "not-a-uuid" })).id(), Job::new(json!({ "id": "not-a-uuid" })).id());
     }
-    info!("Job submission received. No workers available, queueing...");
-    let queue_size = state.job_queue.lock().await.enqueue(job).await;
-    (StatusCode::ACCEPTED, Json(SubmitJobResponse::Queued { position: queue_size }))
 }
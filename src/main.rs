@@ -1,19 +1,39 @@
+mod admin;
+mod auth;
+mod callback_filter;
+mod drain;
+mod events;
+mod error;
+mod health;
 mod job;
+#[cfg(feature = "otel")]
+mod otel;
 mod queue;
+mod rate_limit;
+mod read_only;
+mod telemetry;
+mod topic;
 mod worker;
 
-use crate::{job::Job, queue::Queue, worker::Worker};
-use axum::{routing::{get, post}, Json, Router};
-use clap::Parser;
+use crate::{callback_filter::{CallbackFilter, HostPattern}, job::{FailedWorkerPolicy, Job, JobStatus}, queue::{FileFormat, Queue}, worker::{WebSocketWorkers, Worker}};
+use axum::{extract::DefaultBodyLimit, http::{header, HeaderMap, HeaderName, HeaderValue, Method}, middleware, routing::{delete, get, post}, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use jsonschema::Validator;
+use clap::{error::ErrorKind, parser::ValueSource, CommandFactory, FromArgMatches, Parser};
 use derive_more::{Display, FromStr};
+use metrics_exporter_prometheus::PrometheusHandle;
+use reqwest::redirect;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{net::{Ipv6Addr, SocketAddr}, sync::Arc};
-use tokio::{net::TcpListener, sync::Mutex};
-use tower_http::{services::ServeDir, trace::TraceLayer};
-use tracing::info;
+use std::{collections::HashMap, io, net::{IpAddr, Ipv6Addr, SocketAddr}, path::{Path, PathBuf}, process, sync::{atomic::AtomicBool, Arc}, time::Duration};
+use tokio::{net::TcpListener, sync::{broadcast, Mutex, Notify, Semaphore}, time::Instant};
+use tower_http::{cors::{AllowOrigin, CorsLayer}, request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer}, services::ServeDir, trace::TraceLayer};
+use tracing::{error, info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
 
 /// The available queue implementations chosen via the command line.
-#[derive(Debug, Clone, Copy, Display, FromStr)]
+#[derive(Debug, Clone, Copy, Display, FromStr, Serialize)]
 #[non_exhaustive]
 enum QueueMode {
     /// An in-memory queue, backed by a `VecDeque`.
@@ -22,15 +42,62 @@ enum QueueMode {
     JsonFile,
     /// A queue that writes to a JSON file on every operation, but caches the entire queue in memory.
     CachedJsonFile,
+    /// A queue that appends one line per change to a newline-delimited JSON file, and caches the entire queue in memory.
+    JsonlFile,
+    /// A queue stored as a table in a SQLite database, touching only the affected row on every operation.
+    Sqlite,
+    /// A queue stored as a list on a Redis server, which can be shared by several service instances.
+    Redis,
+    /// A queue stored as a table in a PostgreSQL database, which can be shared by several service instances.
+    Postgres,
+}
+
+/// The available log output formats chosen via the command line.
+#[derive(Debug, Clone, Copy, Display, FromStr)]
+enum LogFormat {
+    /// Human-readable lines, colored if the output is a terminal.
+    Pretty,
+    /// One JSON object per line, with the fields of each event as separate keys, for ingestion into log aggregators.
+    Json,
 }
 
-#[derive(Debug, Clone, Copy, clap::Parser)]
+#[derive(Debug, Clone, clap::Parser)]
 struct Args {
+    /// The path to a TOML file containing values for any of the other options, keyed by their long names,
+    /// e.g. `job-queue-capacity = 1000`. Options given on the command line or via environment variables take precedence.
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// The format of the log output.
+    /// Possible values are `Pretty` and `Json`.
+    #[clap(long, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// The events which are logged, as a comma-separated list of a default level and overrides for single modules,
+    /// e.g. `info,tower_http=warn,job_dispatcher_service::job=debug`. Levels are `error`, `warn`, `info`, `debug` and `trace`.
+    /// The `RUST_LOG` environment variable takes precedence if it is set and valid.
+    #[clap(long, default_value = "info", value_parser = parse_log_level)]
+    log_level: String,
+    /// The URL of an OpenTelemetry collector to which the spans of the requests are exported via OTLP over HTTP,
+    /// e.g. `http://localhost:4318/v1/traces`. If not specified, spans are only logged.
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// The IP address on which the server will listen, e.g. `127.0.0.1` to only accept local connections.
+    /// The default `::` accepts both IPv4 and IPv6 connections on systems with dual-stack sockets.
+    #[clap(long, default_value_t = IpAddr::V6(Ipv6Addr::UNSPECIFIED))]
+    bind: IpAddr,
     /// The TCP port on which the server will listen.
     #[clap(short, long, default_value_t = 2567)]
     port: u16,
+    /// The number of following ports to try, one after another, if the port is already in use.
+    /// By default, the service exits if the port is already in use.
+    #[clap(long, default_value_t = 0)]
+    port_retry: u16,
+    /// The directory whose files are served under `/public`, e.g. `/usr/share/job-dispatcher/public`
+    /// when the binary is packaged separately from its assets. Relative paths are resolved against the working directory.
+    #[clap(long, default_value = "public")]
+    public_dir: PathBuf,
     /// The queue implementation to use for both the worker and job queues.
-    /// Possible values are `InMemory`, `JsonFile`, and `CachedJsonFile`.
+    /// Possible values are `InMemory`, `JsonFile`, `CachedJsonFile`, `JsonlFile`, `Sqlite`, `Redis`, and `Postgres`.
     #[clap(short, long, default_value_t = QueueMode::CachedJsonFile)]
     mode: QueueMode,
     /// The queue implementation to use for the job queue.
@@ -41,63 +108,1079 @@ struct Args {
     /// If not specified, the mode will be used.
     #[clap(long)]
     worker_queue_mode: Option<QueueMode>,
+    /// The URL of the Redis server to use for the `Redis` queue mode.
+    #[clap(long, default_value = "redis://127.0.0.1/")]
+    redis_url: String,
+    /// The URL of the PostgreSQL database to use for the `Postgres` queue mode.
+    #[clap(long, default_value = "postgres://localhost/postgres")]
+    postgres_url: String,
+    /// The minimum interval in milliseconds between writes to the queue file in the `CachedJsonFile` mode.
+    /// If not specified, the file is written on every operation.
+    /// If specified, a crash can lose up to one interval worth of changes.
+    #[clap(long)]
+    write_debounce: Option<u64>,
+    /// Whether to gzip the queue files in the `JsonFile` and `CachedJsonFile` modes.
+    /// The files are then named `<name>.json.gz` instead of `<name>.json`.
+    #[clap(long)]
+    compress_queue_files: bool,
+    /// Whether to record Prometheus metrics about the operations on every queue, whatever its mode:
+    /// the numbers of enqueued and dequeued elements, and the durations of the operations.
+    #[clap(long)]
+    queue_metrics: bool,
+    /// The serialization format of the queue files in the `JsonFile` and `CachedJsonFile` modes.
+    /// Possible values are `Json`, `MessagePack` (`<name>.msgpack`), and `Cbor` (`<name>.cbor`).
+    #[clap(long, default_value_t = FileFormat::Json)]
+    queue_file_format: FileFormat,
+    /// Whether to write JSON queue files without whitespace in the `JsonFile` and `CachedJsonFile` modes.
+    /// By default, they are pretty-printed for human readability, which makes large queues slower to write.
+    #[clap(long)]
+    compact_json: bool,
+    /// The number of times a queue file is read in the `JsonFile`, `CachedJsonFile`, and `JsonlFile` modes before giving up,
+    /// with a backoff starting at 100 milliseconds and doubling after every failed read.
+    /// A file which still cannot be read fails the operation, or the startup, instead of being treated as an empty queue.
+    #[clap(long, default_value_t = queue::DEFAULT_READ_ATTEMPTS, value_parser = clap::value_parser!(u32).range(1..))]
+    file_read_attempts: u32,
+    /// The path of the job queue file in the `JsonFile`, `CachedJsonFile`, and `JsonlFile` modes.
+    /// The in-flight, scheduled and dead-lettered jobs are stored in the same directory.
+    /// If not specified, the file is named after the queue in the working directory, e.g. `jobs.json`.
+    /// Missing parent directories are created.
+    #[clap(long)]
+    job_queue_path: Option<PathBuf>,
+    /// The path of the worker queue file in the `JsonFile`, `CachedJsonFile`, and `JsonlFile` modes.
+    /// If not specified, the file is named after the queue in the working directory, e.g. `workers.json`.
+    /// Missing parent directories are created.
+    #[clap(long)]
+    worker_queue_path: Option<PathBuf>,
+    /// The maximum number of jobs which can be queued.
+    /// If not specified, the job queue is unbounded.
+    #[clap(long)]
+    job_queue_capacity: Option<usize>,
+    /// The maximum number of workers which can be queued.
+    /// If not specified, the worker queue is unbounded.
+    #[clap(long)]
+    worker_queue_capacity: Option<usize>,
+    /// The number of seconds after which a queued worker which has not sent a heartbeat is evicted.
+    /// If not specified, workers stay queued until they are assigned a job.
+    #[clap(long)]
+    worker_ttl: Option<u64>,
+    /// The number of seconds a worker may take to report the result of a job it accepted.
+    /// Jobs whose result was not reported in time are dispatched again, e.g. because their worker crashed.
+    /// If not specified, jobs stay in-flight until their result is reported or the service restarts.
+    #[clap(long)]
+    visibility_timeout: Option<u64>,
+    /// The number of seconds for which the state of a job which is Completed, Failed, DeadLettered, Expired or Cancelled
+    /// is still reported by `/job/{id}/status` and `/job/{id}/position`, after which it is forgotten so that the states
+    /// do not accumulate in memory, or 0 to keep them until the service stops.
+    #[clap(long, default_value_t = 24 * 60 * 60)]
+    job_status_retention: u64,
+    /// The URL schemes which workers may use for their callback URL.
+    /// Multiple schemes are separated by commas. Registrations with any other scheme are rejected with 400 Bad Request.
+    #[clap(long, value_delimiter = ',', default_values = ["http", "https"])]
+    callback_schemes: Vec<String>,
+    /// The maximum length in bytes of the callback URL of a worker, since it is persisted with every change to the worker queue.
+    /// Registrations with a longer URL are rejected with 400 Bad Request.
+    #[clap(long, default_value_t = 4096)]
+    max_callback_url_length: usize,
+    /// The hosts which workers may use in their callback URL, as host names (which include their subdomains),
+    /// IP addresses, or IP ranges in CIDR notation. Multiple hosts are separated by commas.
+    /// If specified, registrations with any other host are rejected with 400 Bad Request.
+    #[clap(long, value_delimiter = ',')]
+    callback_allow: Vec<HostPattern>,
+    /// The hosts which workers may not use in their callback URL, in the same notation as `--callback-allow`,
+    /// e.g. `169.254.0.0/16,10.0.0.0/8,127.0.0.0/8` to keep workers from making the service call internal services.
+    /// Denied hosts take precedence over allowed hosts.
+    #[clap(long, value_delimiter = ',')]
+    callback_deny: Vec<HostPattern>,
+    /// The number of seconds to wait for a worker to respond to a job sent to its callback URL,
+    /// or for a worker connected via WebSocket to acknowledge a job.
+    /// Workers which do not respond in time fail to accept the job, which is offered to the next worker.
+    #[clap(long, default_value_t = 10)]
+    callback_timeout: u64,
+    /// The number of times a job is sent to a worker's callback URL before the worker is discarded.
+    /// Attempts after the first are delayed with exponential backoff.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    callback_attempts: u32,
+    /// The number of redirects to follow when a worker's callback URL redirects the job elsewhere.
+    /// By default, redirects are not followed and a worker responding with a 3xx code fails to accept the job,
+    /// since the redirect could lead to a host which would not be allowed as a callback URL.
+    /// Redirects to URLs with a scheme other than `--callback-schemes` are never followed.
+    #[clap(long, default_value_t = 0)]
+    max_redirects: usize,
+    /// The delay in milliseconds before the second attempt to send a job to a worker.
+    /// The delay doubles with every further attempt.
+    #[clap(long, default_value_t = 100)]
+    callback_backoff: u64,
+    /// The maximum number of jobs which are sent to workers' callback URLs at the same time.
+    /// Further jobs wait until a worker responded, so that a burst of submissions or registrations does not overwhelm the workers.
+    /// If not specified, jobs are sent without limit.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_callbacks: Option<u32>,
+    /// The interval in milliseconds at which a submission waiting for a worker with `?wait=`, or a worker waiting for a job,
+    /// looks at the queues again. Waiting requests are woken as soon as this instance queues a worker or job,
+    /// so this is only needed to find the workers and jobs queued by other instances sharing the same queues.
+    /// If not specified, waiting requests only look again when woken.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    wait_poll_interval: Option<u64>,
+    /// The User-Agent header sent with every job sent to a worker's callback URL.
+    /// If not specified, no User-Agent header is sent.
+    #[clap(long)]
+    callback_user_agent: Option<HeaderValue>,
+    /// Additional headers sent with every job sent to a worker's callback URL, e.g. `Authorization: Bearer <token>`,
+    /// for worker endpoints which require authentication or route by header. Multiple headers are separated by commas.
+    /// Every job is also sent with an `X-Job-Id` header containing its id.
+    #[clap(long, value_delimiter = ',', value_parser = parse_header)]
+    callback_headers: Vec<(HeaderName, HeaderValue)>,
+    /// The maximum number of idle connections kept open to each worker host for sending further jobs.
+    /// If not specified, idle connections are not limited.
+    #[clap(long)]
+    callback_pool_max_idle_per_host: Option<usize>,
+    /// The number of seconds after which an idle connection to a worker host is closed, or 0 to keep idle connections open.
+    #[clap(long, default_value_t = 90)]
+    callback_pool_idle_timeout: u64,
+    /// The interval in seconds at which TCP keepalive probes are sent on connections to worker hosts.
+    /// If not specified, no keepalive probes are sent.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    callback_tcp_keepalive: Option<u64>,
+    /// The number of seconds a job may wait in the job queue before it is discarded instead of dispatched,
+    /// for jobs which were submitted without a shorter `ttl`. If not specified, jobs wait indefinitely.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_job_age: Option<u64>,
+    /// Whether to report only the origin (scheme, host and port) of the callback URL of the worker which was assigned a job
+    /// to the submitter, instead of the full URL, so that the credentials, paths and queries of workers are not disclosed.
+    #[clap(long)]
+    redact_worker_urls: bool,
+    /// The number of workers which may fail to accept a job before it is moved to the dead-letter queue.
+    /// If not specified, a job is queued again no matter how many workers failed to accept it.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_dispatch_attempts: Option<u32>,
+    /// What happens to a worker which failed to accept a job.
+    /// Possible values are `Classify` (queue it again if the failure was transient, discard it otherwise),
+    /// `Requeue` (always queue it again at the back), and `Discard` (always discard it).
+    #[clap(long, default_value_t = FailedWorkerPolicy::Classify)]
+    failed_worker_policy: FailedWorkerPolicy,
+    /// The maximum size in bytes of a submitted job.
+    /// Larger submissions are rejected with 413 Payload Too Large.
+    #[clap(long, default_value_t = 2 * 1024 * 1024)]
+    max_job_size: usize,
+    /// The path to a JSON Schema which the data of every submitted job must satisfy.
+    /// Submissions which do not are rejected with 422 Unprocessable Entity. If not specified, any JSON is accepted.
+    #[clap(long, value_parser = parse_job_schema)]
+    job_schema: Option<Arc<Validator>>,
+    /// The API keys which clients must provide as a bearer token to use the job and worker endpoints.
+    /// Multiple keys are separated by commas. If not specified, the endpoints are open to anyone.
+    #[clap(long, env = "API_KEYS", value_delimiter = ',', hide_env_values = true)]
+    api_keys: Vec<String>,
+    /// The number of jobs per second each client can submit in the long run.
+    /// Clients are identified by their API key, or by their IP address if they send none.
+    /// If not specified, submissions are not rate limited.
+    #[clap(long, value_parser = parse_positive_rate)]
+    rate_limit: Option<f64>,
+    /// The number of jobs each client can submit at once before the rate limit applies.
+    #[clap(long, default_value_t = 10)]
+    rate_limit_burst: u32,
+    /// The path to a PEM file containing the TLS certificate chain.
+    /// If specified together with `--tls-key`, the server only accepts HTTPS connections.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// The path to a PEM file containing the private key of the TLS certificate.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Whether to drain the job queues before shutting down. On the first Ctrl-C or SIGTERM, submissions are rejected
+    /// with 503 Service Unavailable, while workers keep pulling the queued jobs; once the job queues of the service and of all
+    /// topics are empty, the service shuts down.
+    /// A second signal shuts the service down right away.
+    #[clap(long)]
+    drain_on_shutdown: bool,
+    /// Whether to only serve the endpoints which read the queues, such as the listing, status and stats endpoints,
+    /// and reject the submission, registration and all other endpoints which would change them with 405 Method Not Allowed.
+    /// Meant for replicas serving dashboards from queues shared with other instances, i.e. in the `JsonFile`, `Sqlite`,
+    /// `Redis` and `Postgres` modes. A read-only instance does not dispatch, requeue or evict anything either.
+    #[clap(long, conflicts_with = "drain_on_shutdown")]
+    read_only: bool,
+    /// The origins from which browsers may call the API, e.g. `http://localhost:8080`, or `*` for any origin.
+    /// Multiple origins are separated by commas. If not specified, browsers may only call the API from the same origin.
+    #[clap(long, value_delimiter = ',')]
+    cors_origins: Vec<HeaderValue>,
+    /// The methods which browsers may use when calling the API from one of the allowed origins.
+    /// Multiple methods are separated by commas.
+    #[clap(long, value_delimiter = ',', default_values = ["GET", "POST", "PUT", "DELETE"])]
+    cors_methods: Vec<Method>,
+    /// The request headers which browsers may send when calling the API from one of the allowed origins.
+    /// Multiple headers are separated by commas.
+    #[clap(long, value_delimiter = ',', default_values = ["authorization", "content-type", "cpee-callback", "cpee-tags", "cpee-slots", "cpee-max-payload", "cpee-topics", "x-request-id"])]
+    cors_headers: Vec<HeaderName>,
+}
+
+/// Parses a rate which must be a positive number.
+fn parse_positive_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        Ok(_) => Err("must be a positive number".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Checks that the given log level directives are valid, see [`log_filter`].
+fn parse_log_level(directives: &str) -> Result<String, String> {
+    EnvFilter::builder().parse(directives).map_err(|err| err.to_string())?;
+    Ok(directives.to_owned())
+}
+
+/// Builds the filter deciding which events are logged from the `RUST_LOG` environment variable if it is set and valid,
+/// and otherwise from the directives of `--log-level`, which were already validated.
+fn log_filter(log_level: &str) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_level))
+}
+
+/// Parses a header given as `Name: value`, trimming the whitespace around the name and the value.
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header.split_once(':').ok_or("must have the form `Name: value`")?;
+    let name = HeaderName::try_from(name.trim()).map_err(|err| format!("invalid header name '{}': {err}", name.trim()))?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|err| format!("invalid value for header '{name}': {err}"))?;
+    Ok((name, value))
 }
 
+/// Reads a JSON Schema from the given file and compiles it, so that it is compiled only once.
+fn parse_job_schema(path: &str) -> Result<Arc<Validator>, String> {
+    let schema = std::fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+    let schema = serde_json::from_str(&schema).map_err(|err| format!("{path} is not valid JSON: {err}"))?;
+    jsonschema::validator_for(&schema)
+        .map(Arc::new)
+        .map_err(|err| format!("{path} is not a valid JSON Schema: {err}"))
+}
+
+/// Builds the client with which jobs are sent to the callback URLs of workers and results to the result callback URLs,
+/// pooling connections as configured.
+/// The configured User-Agent and headers are not sent with every request, see [`callback_headers`].
+fn callback_client(args: &Args) -> reqwest::Client {
+    let idle_timeout = Some(args.callback_pool_idle_timeout).filter(|&secs| secs > 0).map(Duration::from_secs);
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(args.callback_timeout))
+        .redirect(redirect_policy(args))
+        .pool_idle_timeout(idle_timeout)
+        .tcp_keepalive(args.callback_tcp_keepalive.map(Duration::from_secs));
+    if let Some(max_idle) = args.callback_pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    builder.build().unwrap()
+}
+
+/// Collects the configured User-Agent and headers, which are sent only with the jobs sent to workers,
+/// so that credentials meant for the workers never reach the result callback URLs chosen by the submitters.
+fn callback_headers(args: &Args) -> HeaderMap {
+    let mut headers: HeaderMap = args.callback_headers.iter().cloned().collect();
+    if let Some(user_agent) = &args.callback_user_agent {
+        headers.insert(header::USER_AGENT, user_agent.clone());
+    }
+    headers
+}
+
+/// The interval at which scheduled jobs are checked for being due.
+const SCHEDULED_JOBS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The interval at which in-flight jobs are checked for having exceeded the visibility timeout.
+const VISIBILITY_TIMEOUT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The interval at which the states of finished jobs are checked for having exceeded the job status retention.
+const JOB_STATUS_RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
 /// The application state.
 /// The queues are wrapped in Arc<Mutex<_>> to allow synchronized multithreaded mutation.
 #[derive(Debug, Clone)]
 struct AppState {
     http_client: reqwest::Client,
+    /// The User-Agent and headers sent with every job sent to a worker's callback URL, see [`callback_headers`].
+    callback_headers: Arc<HeaderMap>,
     worker_queue: Arc<Mutex<Queue<Worker>>>,
     job_queue: Arc<Mutex<Queue<Job>>>,
+    /// Jobs which were assigned to a worker and whose result has not been reported yet.
+    assigned_jobs: Arc<Mutex<Queue<Job>>>,
+    /// Jobs which must not be dispatched before their `not_before` time.
+    scheduled_jobs: Arc<Mutex<Queue<Job>>>,
+    /// Jobs which too many workers failed to accept, and which are no longer dispatched.
+    dead_letter_jobs: Arc<Mutex<Queue<Job>>>,
+    /// The current state of every job which was submitted or requeued since the service started, keyed by job id.
+    job_statuses: Arc<Mutex<HashMap<Uuid, JobStatus>>>,
+    /// The workers connected via WebSocket, to which jobs are sent over their connection instead of to a callback URL.
+    websocket_workers: Arc<Mutex<WebSocketWorkers>>,
+    /// The channel through which job events are published to the subscribers of `/events`.
+    events: broadcast::Sender<events::TimedEvent>,
+    /// Notified whenever a worker is queued, to wake the submissions which are waiting for a worker.
+    /// Shared by all topics, so that a submission may be woken by a worker of another topic and wait again.
+    worker_queued: Arc<Notify>,
+    /// Notified whenever a job is queued, to wake the workers which are waiting for a job in a long poll.
+    /// Shared by all topics, so that a worker may be woken by a job of another topic and wait again.
+    job_queued: Arc<Notify>,
+    /// How often waiting submissions and workers look at the queues again even if they were not woken.
+    wait_poll_interval: Option<Duration>,
+    /// The number of workers which may fail to accept a job before it is moved to the dead-letter queue.
+    max_dispatch_attempts: Option<u32>,
+    /// What happens to a worker which failed to accept a job.
+    failed_worker_policy: FailedWorkerPolicy,
+    /// The JSON Schema which the data of every submitted job must satisfy, if any.
+    job_schema: Option<Arc<Validator>>,
+    /// Workers which have not been seen for longer than this are evicted and skipped during dispatch.
+    worker_ttl: Option<Duration>,
+    /// Queued jobs which waited longer than this are discarded instead of dispatched.
+    max_job_age: Option<Duration>,
+    /// Renders the metrics recorded by the handlers.
+    metrics: PrometheusHandle,
+    /// The counters summarized by `/stats`.
+    stats: Arc<telemetry::Stats>,
+    /// The number of times a job is sent to a worker before the worker is discarded.
+    callback_attempts: u32,
+    /// The delay before retrying to send a job to a worker, doubling with every attempt.
+    callback_backoff: Duration,
+    /// Limits the number of jobs which are sent to callback URLs at the same time, if configured.
+    callback_permits: Option<Arc<Semaphore>>,
+    /// How long a worker connected via WebSocket may take to acknowledge a job.
+    callback_timeout: Duration,
+    /// The URL schemes which workers may use for their callback URL.
+    callback_schemes: Arc<[String]>,
+    /// The maximum length in bytes of the callback URL of a worker.
+    max_callback_url_length: usize,
+    /// The hosts which workers may or may not use for their callback URL.
+    callback_filter: Arc<CallbackFilter>,
+    /// Whether only the origin of the assigned worker's callback URL is reported to the submitter of a job.
+    redact_worker_urls: bool,
+    /// Whether the service is draining the job queues before shutting down, see [`drain`].
+    draining: Arc<AtomicBool>,
+    /// Whether the service only serves the endpoints which read the queues, see [`read_only`].
+    read_only: bool,
+    /// The implementation of the job queue and the other job queues, reported by `/stats`.
+    job_queue_mode: QueueMode,
+    /// The implementation of the worker queue, reported by `/stats`.
+    worker_queue_mode: QueueMode,
+    /// The topic whose job and worker queues this state holds, or None if it holds the global ones. See [`AppState::for_topic`].
+    topic: Option<Arc<str>>,
+    /// The job and worker queues of the named topics, created on first use.
+    topics: Arc<topic::Topics>,
+}
+
+impl AppState {
+    /// Returns when a waiting submission or worker should look at the queues again if it was not woken before:
+    /// after the wait poll interval if one is configured, but never after the given deadline.
+    fn next_poll(&self, deadline: Instant) -> Instant {
+        self.wait_poll_interval.map_or(deadline, |interval| deadline.min(Instant::now() + interval))
+    }
+}
+
+impl Args {
+    /// Parses the command-line arguments. If a config file is given with `--config`, its values are used
+    /// for the options which were neither given on the command line nor via environment variables.
+    /// The values from the file are passed through the same parsers and validation as the command-line arguments.
+    /// Exits the process with an error message if the arguments or the config file are invalid.
+    fn load() -> Self {
+        Self::try_load_from(std::env::args()).unwrap_or_else(|err| err.exit())
+    }
+
+    /// Parses the given command-line arguments like [`Args::load`], but returns an error instead of exiting.
+    /// Flags are set by `true` in the config file, and left unset by `false`.
+    fn try_load_from(argv: impl IntoIterator<Item = String>) -> Result<Self, clap::Error> {
+        let mut argv: Vec<String> = argv.into_iter().collect();
+        let matches = Args::command().try_get_matches_from(&argv)?;
+        let args = Args::from_arg_matches(&matches)?;
+        let Some(config) = &args.config else {
+            return Ok(args);
+        };
+        let table = std::fs::read_to_string(config)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| contents.parse::<toml::Table>().map_err(|err| err.to_string()))
+            .map_err(|err| Args::command().error(ErrorKind::Io, format!("Failed to read config file {}: {err}", config.display())))?;
+        let command = Args::command();
+        for (key, value) in table {
+            let flag = key.replace('_', "-");
+            let id = key.replace('-', "_");
+            // Unknown options are passed on so that clap reports them.
+            let known = command.get_arguments().any(|arg| arg.get_id() == id.as_str());
+            if known && matches!(matches.value_source(&id), Some(ValueSource::CommandLine | ValueSource::EnvVariable)) {
+                continue;
+            }
+            let value = match value {
+                // Flags take no value on the command line
+                toml::Value::Boolean(true) => {
+                    argv.push(format!("--{flag}"));
+                    continue;
+                },
+                toml::Value::Boolean(false) => continue,
+                toml::Value::String(value) => value,
+                // Lists are passed like comma-separated command-line values, e.g. for `api-keys`.
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| match value {
+                        toml::Value::String(value) => value,
+                        value => value.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+                value => value.to_string(),
+            };
+            argv.push(format!("--{flag}"));
+            argv.push(value);
+        }
+        Args::try_parse_from(argv)
+    }
+}
+
+/// The query parameters of the endpoints listing the contents of a queue.
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    /// The maximum number of elements to return. If not specified, all elements are returned.
+    limit: Option<usize>,
+}
+
+/// The response of the endpoints clearing a queue.
+#[derive(Debug, Serialize)]
+enum ClearQueueResponse {
+    /// The queue was cleared. The number of removed elements is provided.
+    Cleared { removed: usize },
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize the logger.
-    tracing_subscriber::fmt::init();
-
     // Parse the command-line arguments.
-    let args = Args::parse();
-    let port = args.port;
-    let job_queue = match args.job_queue_mode.unwrap_or(args.mode) {
-        QueueMode::InMemory => queue::InMemoryQueue::new().into(),
-        QueueMode::JsonFile => queue::JsonFileQueue::new("jobs.json").into(),
-        QueueMode::CachedJsonFile => queue::CachedJsonFileQueue::new("jobs.json").await.into(),
-    };
-    let worker_queue = match args.worker_queue_mode.unwrap_or(args.mode) {
-        QueueMode::InMemory => queue::InMemoryQueue::new().into(),
-        QueueMode::JsonFile => queue::JsonFileQueue::new("workers.json").into(),
-        QueueMode::CachedJsonFile => queue::CachedJsonFileQueue::new("workers.json").await.into(),
+    let args = Args::load();
+    // Initialize the logger, and the export of spans if enabled.
+    let log_layer = match args.log_format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
     };
+    let subscriber = tracing_subscriber::registry().with(log_filter(&args.log_level)).with(log_layer);
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().map(otel::layer));
+    subscriber.init();
+    // Initialize the metrics recorder.
+    let metrics = telemetry::install();
+
+    let write_debounce = args.write_debounce.map(Duration::from_millis);
+    let job_queue_mode = args.job_queue_mode.unwrap_or(args.mode);
+    let job_queue_path = args.job_queue_path.as_deref();
+    let job_queue_dir = job_queue_path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let mut job_queue: Queue<Job> = create_queue(job_queue_mode, "jobs", job_queue_dir, job_queue_path, &args).await;
+    if let Some(capacity) = args.job_queue_capacity {
+        job_queue = Box::new(queue::BoundedQueue::new(job_queue, capacity));
+    }
+    let worker_queue_path = args.worker_queue_path.as_deref();
+    let worker_queue_dir = worker_queue_path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let worker_queue_mode = args.worker_queue_mode.unwrap_or(args.mode);
+    let mut worker_queue: Queue<Worker> = create_queue(worker_queue_mode, "workers", worker_queue_dir, worker_queue_path, &args).await;
+    if let Some(capacity) = args.worker_queue_capacity {
+        worker_queue = Box::new(queue::BoundedQueue::new(worker_queue, capacity));
+    }
+    let job_queue = metered(job_queue, "jobs", None, &args);
+    let worker_queue = metered(worker_queue, "workers", None, &args);
+    // In-flight jobs are stored alongside the job queue until their result is reported.
+    let assigned_jobs: Queue<Job> = metered(create_queue(job_queue_mode, "assigned_jobs", job_queue_dir, None, &args).await, "assigned_jobs", None, &args);
+    // Jobs which must not be dispatched yet are stored alongside the job queue until they are due.
+    let scheduled_jobs: Queue<Job> = metered(create_queue(job_queue_mode, "scheduled_jobs", job_queue_dir, None, &args).await, "scheduled_jobs", None, &args);
+    let dead_letter_jobs: Queue<Job> = metered(create_queue(job_queue_mode, "dead_letter_jobs", job_queue_dir, None, &args).await, "dead_letter_jobs", None, &args);
+
+    // Listen over TCP on the specified address and port.
+    // The listener is bound before the state is created and the config is generated, so that both know the actual port
+    // even if the OS chose it because the port was 0.
+    let listener = bind(args.bind, args.port, args.port_retry).await;
+    let addr = listener.local_addr().unwrap();
 
     // Create the application state for the handlers to use.
     let state = AppState {
-        http_client: reqwest::Client::new(),
+        http_client: callback_client(&args),
+        callback_headers: Arc::new(callback_headers(&args)),
         job_queue: Arc::new(Mutex::new(job_queue)),
         worker_queue: Arc::new(Mutex::new(worker_queue)),
+        assigned_jobs: Arc::new(Mutex::new(assigned_jobs)),
+        scheduled_jobs: Arc::new(Mutex::new(scheduled_jobs)),
+        dead_letter_jobs: Arc::new(Mutex::new(dead_letter_jobs)),
+        job_statuses: Arc::new(Mutex::new(HashMap::new())),
+        websocket_workers: Arc::new(Mutex::new(HashMap::new())),
+        events: events::channel(),
+        worker_queued: Arc::new(Notify::new()),
+        job_queued: Arc::new(Notify::new()),
+        wait_poll_interval: args.wait_poll_interval.map(Duration::from_millis),
+        max_dispatch_attempts: args.max_dispatch_attempts,
+        failed_worker_policy: args.failed_worker_policy,
+        job_schema: args.job_schema.clone(),
+        worker_ttl: args.worker_ttl.map(Duration::from_secs),
+        max_job_age: args.max_job_age.map(Duration::from_secs),
+        metrics,
+        stats: Arc::default(),
+        callback_attempts: args.callback_attempts,
+        callback_backoff: Duration::from_millis(args.callback_backoff),
+        callback_permits: args.max_concurrent_callbacks.map(|max| Arc::new(Semaphore::new(max as usize))),
+        callback_timeout: Duration::from_secs(args.callback_timeout),
+        callback_schemes: args.callback_schemes.clone().into(),
+        max_callback_url_length: args.max_callback_url_length,
+        callback_filter: Arc::new(CallbackFilter::new(args.callback_allow.clone(), args.callback_deny.clone()).with_own_address(addr)),
+        redact_worker_urls: args.redact_worker_urls,
+        draining: Arc::default(),
+        read_only: args.read_only,
+        job_queue_mode,
+        worker_queue_mode,
+        topic: None,
+        topics: Arc::new(topic::Topics::new(args.clone())),
     };
 
+    // Load the queues of the topics which were used before, so that their jobs and workers are not forgotten until they are used again.
+    state.topics.load_existing().await;
+
+    if args.read_only {
+        // The queues are left to the instances which change them.
+        for mode in [job_queue_mode, worker_queue_mode] {
+            if !matches!(mode, QueueMode::JsonFile | QueueMode::Sqlite | QueueMode::Redis | QueueMode::Postgres) {
+                warn!("The {mode} mode keeps the queues in memory, so a read-only instance does not see changes made by other instances");
+            }
+        }
+        info!("Running in read-only mode, rejecting requests which would change the queues");
+    } else {
+        // Dispatch the jobs again which were in-flight when the service last stopped.
+        job::requeue_assigned_jobs(&state).await;
+
+        // Periodically dispatch scheduled jobs which are due.
+        tokio::spawn(job::dispatch_scheduled_jobs(state.clone(), SCHEDULED_JOBS_INTERVAL));
+    }
+
+    // Periodically flush debounced writes so they reach the disk even if no further operations arrive.
+    if let Some(interval) = write_debounce {
+        tokio::spawn(flush_periodically(state.clone(), interval));
+    }
+
+    // Periodically dispatch in-flight jobs again whose result was not reported in time.
+    if let Some(timeout) = args.visibility_timeout.filter(|_| !args.read_only) {
+        tokio::spawn(job::requeue_expired_assignments(state.clone(), Duration::from_secs(timeout), VISIBILITY_TIMEOUT_INTERVAL));
+    }
+
+    // Periodically forget the states of jobs which finished long enough ago.
+    if let Some(retention) = Some(args.job_status_retention).filter(|&secs| secs > 0) {
+        tokio::spawn(job::forget_finished_jobs(state.clone(), Duration::from_secs(retention), JOB_STATUS_RETENTION_INTERVAL));
+    }
+
+    // Periodically evict workers which have stopped sending heartbeats.
+    if let Some(ttl) = state.worker_ttl.filter(|_| !args.read_only) {
+        tokio::spawn(worker::evict_stale_workers(state.clone(), ttl));
+    }
+
+    // Static files are served from the public directory; only the generated config.json is served without it.
+    if !args.public_dir.is_dir() {
+        warn!("Public directory {} does not exist, only /public/config.json is served", args.public_dir.display());
+    }
+
     // Generate the contents of the public/config.json file.
     let config = json!({
-        "server_port": port,
+        "server_port": addr.port(),
     });
 
     // Create the application routes.
+    // The size limit applies to the whole request, i.e. to all jobs of a batch together.
+    let mut submit_job = post(job::submit_job).layer(DefaultBodyLimit::max(args.max_job_size));
+    let mut submit_jobs = post(job::submit_jobs).layer(DefaultBodyLimit::max(args.max_job_size));
+    let mut submit_raw_job = post(job::submit_raw_job).layer(DefaultBodyLimit::max(args.max_job_size));
+    let mut submit_topic_job = post(topic::submit_job).layer(DefaultBodyLimit::max(args.max_job_size));
+    if let Some(rate) = args.rate_limit {
+        // All submission endpoints share the same buckets, so a batch counts as a single submission.
+        let limiter = Arc::new(rate_limit::RateLimiter::new(rate, args.rate_limit_burst));
+        submit_job = submit_job.layer(middleware::from_fn_with_state(limiter.clone(), rate_limit::limit_rate));
+        submit_jobs = submit_jobs.layer(middleware::from_fn_with_state(limiter.clone(), rate_limit::limit_rate));
+        submit_raw_job = submit_raw_job.layer(middleware::from_fn_with_state(limiter.clone(), rate_limit::limit_rate));
+        submit_topic_job = submit_topic_job.layer(middleware::from_fn_with_state(limiter, rate_limit::limit_rate));
+    }
+    if args.drain_on_shutdown {
+        // Submissions are rejected before they count towards the rate limit.
+        submit_job = submit_job.layer(middleware::from_fn_with_state(state.clone(), drain::reject_while_draining));
+        submit_jobs = submit_jobs.layer(middleware::from_fn_with_state(state.clone(), drain::reject_while_draining));
+        submit_raw_job = submit_raw_job.layer(middleware::from_fn_with_state(state.clone(), drain::reject_while_draining));
+        submit_topic_job = submit_topic_job.layer(middleware::from_fn_with_state(state.clone(), drain::reject_while_draining));
+    }
+    // Every endpoint which changes the queues is rejected in read-only mode, before it counts towards the rate limit.
+    let read_only = middleware::from_fn_with_state(state.clone(), read_only::reject_if_read_only);
+    let mut api = Router::new()
+        .route("/register-worker", post(worker::register_worker).layer(read_only.clone()))
+        .route("/register-worker/{topic}", post(topic::register_worker).layer(read_only.clone()))
+        .route("/worker-heartbeat", post(worker::worker_heartbeat).layer(read_only.clone()))
+        .route("/deregister-worker", post(worker::deregister_worker).layer(read_only.clone()))
+        .route("/worker-ws", get(worker::worker_websocket).layer(read_only.clone()))
+        .route("/submit-job", submit_job.layer(read_only.clone()))
+        .route("/submit-job/{topic}", submit_topic_job.layer(read_only.clone()))
+        .route("/submit-jobs", submit_jobs.layer(read_only.clone()))
+        .route("/submit-raw-job", submit_raw_job.layer(read_only.clone()))
+        .route("/jobs", get(job::list_jobs).merge(delete(job::clear_jobs).layer(read_only.clone())))
+        .route("/jobs/{topic}", get(topic::list_jobs).merge(delete(topic::clear_jobs).layer(read_only.clone())))
+        .route("/workers", get(worker::list_workers).merge(delete(worker::clear_workers).layer(read_only.clone())))
+        .route("/workers/{topic}", get(topic::list_workers).merge(delete(topic::clear_workers).layer(read_only.clone())))
+        .route("/dead-letter", get(job::list_dead_letter_jobs))
+        .route("/dead-letter/{id}/requeue", post(job::requeue_dead_letter_job).layer(read_only.clone()))
+        .route("/dead-letter/requeue-all", post(job::requeue_all_dead_letter_jobs).layer(read_only.clone()))
+        .route("/events", get(events::events))
+        .route("/job/{id}", delete(job::cancel_job).layer(read_only.clone()))
+        .route("/job/{id}/status", get(job::job_status))
+        .route("/job/{id}/position", get(job::job_position))
+        .route("/job-result/{id}", post(job::job_result).layer(read_only.clone()))
+        .route("/admin/compact", post(admin::compact_queues).layer(read_only));
+    // The job and worker endpoints require an API key if any are configured; probes, metrics and public files do not.
+    if !args.api_keys.is_empty() {
+        let api_keys: Arc<[String]> = args.api_keys.clone().into();
+        api = api.route_layer(middleware::from_fn_with_state(api_keys, auth::require_api_key));
+    }
     let app = Router::new()
-        .route("/register-worker", post(worker::register_worker))
-        .route("/submit-job", post(job::submit_job))
-        .with_state(state)
+        .merge(api)
+        .route("/health", get(health::health))
+        .route("/ready", get(health::ready))
+        .route("/version", get(health::version))
+        .route("/metrics", get(telemetry::metrics))
+        .route("/stats", get(telemetry::stats))
+        .with_state(state.clone())
         .route(
             "/public/config.json",
             get(move || async move { Json(config.clone()) }),
         )
-        .nest_service("/public", ServeDir::new("public"))
-        .layer(TraceLayer::new_for_http());
-
-    // Listen over TCP on the specified port.
-    let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
-    let listener = TcpListener::bind(addr).await.unwrap();
-    info!("Queue service running on {addr}");
-    axum::serve(listener, app).await.unwrap();
+        .nest_service("/public", ServeDir::new(&args.public_dir))
+        // Requests without an X-Request-Id header are given one, which is returned in the response
+        .layer(PropagateRequestIdLayer::new(HeaderName::from_static(telemetry::REQUEST_ID_HEADER)))
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::request_span))
+        .layer(SetRequestIdLayer::new(HeaderName::from_static(telemetry::REQUEST_ID_HEADER), MakeRequestUuid));
+    // Preflight requests are answered before they reach the authentication
+    let app = match cors_layer(&args) {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    // Serve the application on the listener.
+    // On shutdown, stop accepting connections but let in-flight requests (e.g. job dispatches) complete.
+    // The connection info provides the client IP addresses for rate limiting.
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .expect("Failed to install TLS crypto provider");
+        let config = RustlsConfig::from_pem_file(cert, key).await.unwrap();
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            let state = state.clone();
+            let drain = args.drain_on_shutdown;
+            async move {
+                shutdown_signal(state, drain).await;
+                handle.graceful_shutdown(None);
+            }
+        });
+        info!("Queue service running on {addr} (TLS)");
+        axum_server::from_tcp_rustls(listener.into_std().unwrap(), config).handle(handle).serve(app).await.unwrap();
+    } else {
+        info!("Queue service running on {addr}");
+        axum::serve(listener, app)
+            .with_graceful_shutdown(shutdown_signal(state.clone(), args.drain_on_shutdown))
+            .await
+            .unwrap();
+    }
+
+    // Write any pending changes to disk before exiting.
+    info!("Flushing queues...");
+    flush_queues(&state).await;
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+}
+
+/// Completes when the service should shut down, i.e. when the process receives Ctrl-C (SIGINT) or, on Unix, SIGTERM.
+/// If `drain` is set, the service first drains the job queue, unless the signal is received a second time.
+async fn shutdown_signal(state: AppState, drain: bool) {
+    termination_signal().await;
+    if drain {
+        info!("Shutdown signal received, draining the job queue before shutting down. Send the signal again to shut down right away...");
+        tokio::select! {
+            _ = drain::drain(&state) => {},
+            _ = termination_signal() => info!("Second shutdown signal received, shutting down without draining..."),
+        }
+    }
+    info!("Shutting down, waiting for in-flight requests to complete...");
+}
+
+/// Completes when the process receives Ctrl-C (SIGINT) or, on Unix, SIGTERM.
+async fn termination_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("Failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Creates the policy deciding which redirects are followed when sending jobs and results to callback URLs.
+/// Once a redirect is not followed, its 3xx response is returned as the response to the request.
+fn redirect_policy(args: &Args) -> redirect::Policy {
+    let max_redirects = args.max_redirects;
+    if max_redirects == 0 {
+        return redirect::Policy::none();
+    }
+    let schemes = args.callback_schemes.clone();
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > max_redirects || !schemes.iter().any(|scheme| scheme == attempt.url().scheme()) {
+            attempt.stop()
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Creates the layer which allows browsers to call the API from the origins given with `--cors-origins`,
+/// or None if no origins are given, in which case browsers only allow calls from the same origin.
+fn cors_layer(args: &Args) -> Option<CorsLayer> {
+    if args.cors_origins.is_empty() {
+        return None;
+    }
+    let origins = if args.cors_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(args.cors_origins.clone())
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(args.cors_methods.clone())
+            .allow_headers(args.cors_headers.clone())
+            // Let scripts read the headers which the API returns besides the body
+            .expose_headers([HeaderName::from_static(telemetry::REQUEST_ID_HEADER), header::RETRY_AFTER, HeaderName::from_static("cpee-callback")]),
+    )
+}
+
+/// Creates a queue with the given implementation, named after the given name:
+/// the file `<name>.json` (or `.msgpack` or `.cbor` depending on the file format, and with `.gz` appended if compressed) for the JSON file modes, the file `<name>.jsonl` for the `JsonlFile` mode, the table `<name>` in `queues.sqlite` for the `Sqlite` mode,
+/// the sorted set `<name>` for the `Redis` mode, and the table `<name>` for the `Postgres` mode.
+/// In the file modes, the file is placed in `dir`, or at `path` if given, and missing parent directories are created.
+/// # Panics
+/// This function panics if the parent directory of the queue file cannot be created,
+/// or if the SQLite database, the Redis server or the PostgreSQL database cannot be reached.
+async fn create_queue<T: queue::QueueItem>(mode: QueueMode, name: &str, dir: &Path, path: Option<&Path>, args: &Args) -> Queue<T> {
+    let extension = args.queue_file_format.extension();
+    let file = match (path, mode) {
+        (Some(path), _) => path.to_path_buf(),
+        (None, QueueMode::JsonlFile) => dir.join(format!("{name}.jsonl")),
+        (None, _) if args.compress_queue_files => dir.join(format!("{name}.{extension}.gz")),
+        (None, _) => dir.join(format!("{name}.{extension}")),
+    };
+    if matches!(mode, QueueMode::JsonFile | QueueMode::CachedJsonFile | QueueMode::JsonlFile)
+        && let Some(parent) = file.parent()
+    {
+        std::fs::create_dir_all(parent)
+            .unwrap_or_else(|err| panic!("Failed to create the directory {} for the queue file: {err}", parent.display()));
+    }
+    match mode {
+        QueueMode::InMemory => Box::new(queue::InMemoryQueue::new()),
+        QueueMode::JsonFile if args.compact_json => {
+            Box::new(queue::JsonFileQueue::new(file).with_read_attempts(args.file_read_attempts).with_compact_json())
+        }
+        QueueMode::JsonFile => Box::new(queue::JsonFileQueue::new(file).with_read_attempts(args.file_read_attempts)),
+        QueueMode::CachedJsonFile => Box::new(cached_json_file_queue(&file, args).await),
+        QueueMode::JsonlFile => Box::new(
+            queue::JsonlFileQueue::new(&file, args.file_read_attempts).await
+                .unwrap_or_else(|err| panic!("Failed to load the queue file {}: {err}", file.display()))
+        ),
+        QueueMode::Sqlite => Box::new(queue::SqliteQueue::new("queues.sqlite", name).await.unwrap()),
+        QueueMode::Redis => Box::new(queue::RedisQueue::new(&args.redis_url, name).await.unwrap()),
+        QueueMode::Postgres => Box::new(queue::PostgresQueue::new(&args.postgres_url, name).await.unwrap()),
+    }
+}
+
+/// Returns the names of the existing queues of the given implementation which start with the given prefix,
+/// i.e. of the files in `dir` named like those created by [`create_queue`], or of the tables or sorted sets.
+/// In the `InMemory` mode, no queue outlives the service. If the queues cannot be listed, the error is logged and no names are returned.
+async fn existing_queue_names(mode: QueueMode, prefix: &str, dir: &Path, args: &Args) -> Vec<String> {
+    let extension = match mode {
+        QueueMode::JsonlFile => "jsonl".to_owned(),
+        _ if args.compress_queue_files => format!("{}.gz", args.queue_file_format.extension()),
+        _ => args.queue_file_format.extension().to_owned(),
+    };
+    let names = match mode {
+        QueueMode::InMemory => Ok(Vec::new()),
+        QueueMode::JsonFile | QueueMode::CachedJsonFile | QueueMode::JsonlFile => {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            match std::fs::read_dir(dir) {
+                Ok(entries) => Ok(entries
+                    .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                    .filter_map(|file_name| Some(file_name.strip_suffix(&extension)?.strip_suffix('.')?.to_owned()))
+                    .filter(|name| name.starts_with(prefix))
+                    .collect()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(err) => Err(err.to_string()),
+            }
+        },
+        QueueMode::Sqlite => queue::sqlite_tables("queues.sqlite", prefix).await.map_err(|err| err.to_string()),
+        QueueMode::Redis => queue::redis_keys(&args.redis_url, prefix).await.map_err(|err| err.to_string()),
+        QueueMode::Postgres => queue::postgres_tables(&args.postgres_url, prefix).await.map_err(|err| err.to_string()),
+    };
+    names.unwrap_or_else(|err| {
+        error!("Failed to list the existing {prefix}* queues: {err}");
+        Vec::new()
+    })
+}
+
+/// Wraps the given queue in a MeteredQueue with the given name, and the given topic if it belongs to one,
+/// if `--queue-metrics` is set, or returns it as is.
+fn metered<T: queue::QueueItem>(queue: Queue<T>, name: &'static str, topic: Option<&str>, args: &Args) -> Queue<T> {
+    if !args.queue_metrics {
+        return queue;
+    }
+    match topic {
+        Some(topic) => Box::new(queue::MeteredQueue::new(queue, name).with_topic(topic)),
+        None => Box::new(queue::MeteredQueue::new(queue, name)),
+    }
+}
+
+/// Binds a TCP listener to the given address and port. If the port is already in use,
+/// up to `retries` following ports are tried one after another.
+/// If no port could be bound, a descriptive error is logged and the process exits with a non-zero code.
+async fn bind(ip: IpAddr, port: u16, retries: u16) -> TcpListener {
+    let last_port = port.saturating_add(retries);
+    let mut next_port = port;
+    loop {
+        let addr = SocketAddr::new(ip, next_port);
+        let err = match TcpListener::bind(addr).await {
+            Ok(listener) => return listener,
+            Err(err) => err,
+        };
+        if err.kind() != io::ErrorKind::AddrInUse {
+            error!("Failed to listen on {addr}: {err}");
+            process::exit(1);
+        }
+        if next_port == last_port {
+            error!("Port {next_port} is already in use, is another instance running?");
+            process::exit(1);
+        }
+        warn!("Port {next_port} is already in use, trying port {}...", next_port + 1);
+        next_port += 1;
+    }
+}
+
+/// Creates a CachedJsonFileQueue, debouncing its writes and writing compact JSON as configured by the arguments.
+/// # Panics
+/// This function panics if the file exists but cannot be read, so that the service does not start with an empty queue.
+async fn cached_json_file_queue<T: queue::QueueItem>(file: &Path, args: &Args) -> queue::CachedJsonFileQueue<T> {
+    let mut queue = queue::CachedJsonFileQueue::new(file, args.file_read_attempts).await
+        .unwrap_or_else(|err| panic!("Failed to load the queue file {}: {err}", file.display()));
+    if args.compact_json {
+        queue = queue.with_compact_json();
+    }
+    match args.write_debounce.map(Duration::from_millis) {
+        Some(interval) => queue.with_write_debounce(interval),
+        None => queue,
+    }
+}
+
+/// Flushes all queues once per interval, forever.
+async fn flush_periodically(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        flush_queues(&state).await;
+    }
+}
+
+/// Writes the pending changes of all queues, including those of the topics.
+/// A queue which could not be flushed is logged, and does not keep the other queues from being flushed.
+async fn flush_queues(state: &AppState) {
+    flush(&state.job_queue, "jobs").await;
+    flush(&state.worker_queue, "workers").await;
+    for topic_state in state.topic_states().await {
+        let topic = topic_state.topic.as_deref().unwrap_or_default();
+        flush(&topic_state.job_queue, &format!("{topic} jobs")).await;
+        flush(&topic_state.worker_queue, &format!("{topic} workers")).await;
+    }
+    flush(&state.assigned_jobs, "assigned_jobs").await;
+    flush(&state.scheduled_jobs, "scheduled_jobs").await;
+    flush(&state.dead_letter_jobs, "dead_letter_jobs").await;
+}
+
+/// Writes the pending changes of the given queue, and logs an error if they could not be written.
+async fn flush<T: queue::QueueItem>(queue: &Mutex<Queue<T>>, name: &str) {
+    if let Err(err) = queue.lock().await.flush().await {
+        error!("Failed to flush the {name} queue: '{err}'");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::StatusCode;
+    use std::env;
+    use tokio::sync::mpsc;
+    use tracing::Level;
+    use tracing::level_filters::LevelFilter;
+
+    /// Returns an in-memory queue for the state of a test.
+    fn in_memory<T: queue::QueueItem>() -> Arc<Mutex<Queue<T>>> {
+        Arc::new(Mutex::new(Box::new(queue::InMemoryQueue::new())))
+    }
+
+    /// Returns the state of a service started with the default arguments and InMemory queues.
+    /// Tests may replace its queues, e.g. with failing ones.
+    pub(crate) fn state() -> AppState {
+        let args = Args::try_parse_from(["job-dispatcher-service", "--mode", "InMemory"]).unwrap();
+        AppState {
+            http_client: callback_client(&args),
+            callback_headers: Arc::new(callback_headers(&args)),
+            job_queue: in_memory(),
+            worker_queue: in_memory(),
+            assigned_jobs: in_memory(),
+            scheduled_jobs: in_memory(),
+            dead_letter_jobs: in_memory(),
+            job_statuses: Arc::new(Mutex::new(HashMap::new())),
+            websocket_workers: Arc::new(Mutex::new(HashMap::new())),
+            events: events::channel(),
+            worker_queued: Arc::new(Notify::new()),
+            job_queued: Arc::new(Notify::new()),
+            wait_poll_interval: None,
+            max_dispatch_attempts: args.max_dispatch_attempts,
+            failed_worker_policy: args.failed_worker_policy,
+            job_schema: None,
+            worker_ttl: None,
+            max_job_age: None,
+            metrics: metrics_exporter_prometheus::PrometheusBuilder::new().build_recorder().handle(),
+            stats: Arc::default(),
+            callback_attempts: args.callback_attempts,
+            callback_backoff: Duration::from_millis(args.callback_backoff),
+            callback_permits: None,
+            callback_timeout: Duration::from_secs(args.callback_timeout),
+            callback_schemes: args.callback_schemes.clone().into(),
+            max_callback_url_length: args.max_callback_url_length,
+            callback_filter: Arc::new(CallbackFilter::new(Vec::new(), Vec::new())),
+            redact_worker_urls: false,
+            draining: Arc::default(),
+            read_only: false,
+            job_queue_mode: QueueMode::InMemory,
+            worker_queue_mode: QueueMode::InMemory,
+            topic: None,
+            topics: Arc::new(topic::Topics::new(args)),
+        }
+    }
+
+    /// A request received by a [`mock_server`].
+    #[derive(Debug)]
+    pub(crate) struct ReceivedRequest {
+        pub(crate) headers: HeaderMap,
+        pub(crate) body: serde_json::Value,
+    }
+
+    /// Serves the given routes on a free local port, standing in for workers and result callback URLs,
+    /// and returns the URL of its root. Like the service, it provides the client addresses to the routes.
+    pub(crate) async fn serve(routes: Router) -> String {
+        let listener = TcpListener::bind((IpAddr::from([127, 0, 0, 1]), 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = routes.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/")
+    }
+
+    /// Serves an endpoint which responds to every request with the given status code,
+    /// and returns its URL and the channel through which it passes on the requests it received.
+    pub(crate) async fn mock_server(status: StatusCode) -> (String, mpsc::UnboundedReceiver<ReceivedRequest>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let routes = Router::new().fallback(move |headers: HeaderMap, body: Bytes| async move {
+            let body = serde_json::from_slice(&body).unwrap_or_default();
+            sender.send(ReceivedRequest { headers, body }).unwrap();
+            status
+        });
+        (serve(routes).await, receiver)
+    }
+
+    /// Loads the arguments from the given command line and a config file with the given contents.
+    fn load_with_config(argv: &[&str], config: &str) -> Result<Args, clap::Error> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, config).unwrap();
+        let config_argv = ["job-dispatcher-service", "--config", path.to_str().unwrap()];
+        Args::try_load_from(config_argv.iter().chain(argv).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn config_file_sets_flags() {
+        let args = load_with_config(&[], "drain-on-shutdown = true\nredact_worker_urls = false\nport = 3000\napi-keys = [\"a\", \"b\"]").unwrap();
+        assert!(args.drain_on_shutdown);
+        assert!(!args.redact_worker_urls);
+        assert_eq!(args.port, 3000);
+        assert_eq!(args.api_keys, ["a", "b"]);
+
+        let args = load_with_config(&[], "drain-on-shutdown = false").unwrap();
+        assert!(!args.drain_on_shutdown);
+        // The command line takes precedence over the file
+        let args = load_with_config(&["--port", "4000", "--redact-worker-urls"], "port = 3000\nredact-worker-urls = false").unwrap();
+        assert_eq!(args.port, 4000);
+        assert!(args.redact_worker_urls);
+        assert!(load_with_config(&[], "unknown-flag = true").is_err());
+    }
+
+    #[tokio::test]
+    async fn cors_origins_are_allowed_to_call_the_api() {
+        let args = Args::try_parse_from(["job-dispatcher-service", "--cors-origins", "http://localhost:8080"]).unwrap();
+        let routes = Router::new().route("/jobs", get(|| async { "[]" })).layer(cors_layer(&args).unwrap());
+        let url = format!("{}jobs", serve(routes).await);
+        let client = reqwest::Client::new();
+
+        let response = client.get(&url).header(header::ORIGIN, "http://localhost:8080").send().await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:8080");
+        assert!(response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS].to_str().unwrap().contains("retry-after"));
+        let preflight = client
+            .request(Method::OPTIONS, &url)
+            .header(header::ORIGIN, "http://localhost:8080")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "DELETE")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(preflight.status(), StatusCode::OK);
+        assert_eq!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:8080");
+        assert!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap().contains("DELETE"));
+        assert!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("authorization"));
+        // Other origins are not allowed
+        let response = client.get(&url).header(header::ORIGIN, "http://evil.example").send().await.unwrap();
+        assert!(!response.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let args = Args::try_parse_from(["job-dispatcher-service", "--cors-origins", "*"]).unwrap();
+        let routes = Router::new().route("/jobs", get(|| async { "[]" })).layer(cors_layer(&args).unwrap());
+        let url = format!("{}jobs", serve(routes).await);
+        let response = client.get(&url).header(header::ORIGIN, "http://evil.example").send().await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(cors_layer(&Args::try_parse_from(["job-dispatcher-service"]).unwrap()).is_none());
+    }
+
+    /// Returns whether `enabled` finds the events it checks for enabled while the given filter is in place.
+    fn passes(filter: EnvFilter, enabled: impl FnOnce() -> bool) -> bool {
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(filter), enabled)
+    }
+
+    #[test]
+    fn log_level_sets_the_filter() {
+        let args = Args::try_parse_from(["job-dispatcher-service", "--log-level", "info,tower_http=warn"]).unwrap();
+        assert_eq!(EnvFilter::new(&args.log_level).max_level_hint(), Some(LevelFilter::INFO));
+        assert!(!passes(EnvFilter::new(&args.log_level), || tracing::enabled!(target: "tower_http::trace", Level::INFO)));
+        assert!(passes(EnvFilter::new(&args.log_level), || tracing::enabled!(target: "tower_http::trace", Level::WARN)));
+        assert!(passes(EnvFilter::new(&args.log_level), || tracing::enabled!(target: "job_dispatcher_service::job", Level::INFO)));
+        assert!(!passes(EnvFilter::new(&args.log_level), || tracing::enabled!(target: "job_dispatcher_service::job", Level::DEBUG)));
+
+        let args = Args::try_parse_from(["job-dispatcher-service"]).unwrap();
+        assert_eq!(EnvFilter::new(&args.log_level).max_level_hint(), Some(LevelFilter::INFO));
+        assert!(Args::try_parse_from(["job-dispatcher-service", "--log-level", "info,tower_http=loud"]).is_err());
+    }
+
+    #[test]
+    fn rust_log_takes_precedence_if_valid() {
+        // SAFETY: no other test reads or writes RUST_LOG
+        unsafe { env::set_var("RUST_LOG", "warn,job_dispatcher_service::job=debug") };
+        assert_eq!(log_filter("info").max_level_hint(), Some(LevelFilter::DEBUG));
+        assert!(!passes(log_filter("info"), || tracing::enabled!(target: "tower_http::trace", Level::INFO)));
+        assert!(passes(log_filter("info"), || tracing::enabled!(target: "job_dispatcher_service::job", Level::DEBUG)));
+
+        // An invalid RUST_LOG is ignored in favor of --log-level
+        unsafe { env::set_var("RUST_LOG", "warn,job_dispatcher_service=loud") };
+        assert_eq!(log_filter("error").max_level_hint(), Some(LevelFilter::ERROR));
+        unsafe { env::remove_var("RUST_LOG") };
+        assert_eq!(log_filter("error").max_level_hint(), Some(LevelFilter::ERROR));
+    }
 }
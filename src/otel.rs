@@ -0,0 +1,67 @@
+//! Export of the request spans to an OpenTelemetry collector, and propagation of the trace context
+//! from incoming requests to the jobs sent to workers, so that the dispatch of a job is part of an end-to-end trace.
+//! Only compiled with the `otel` feature.
+
+use axum::http::HeaderMap;
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use std::sync::OnceLock;
+use tracing::{error, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The provider which exports the spans, kept so that the remaining spans can be exported on shutdown.
+static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// Creates the layer which exports spans to the OTLP collector at the given endpoint via HTTP,
+/// e.g. `http://localhost:4318/v1/traces`, and enables the propagation of the W3C trace context.
+/// # Panics
+/// This function panics if the exporter cannot be created, e.g. because the endpoint is not a valid URL.
+pub fn layer<S>(endpoint: &str) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .expect("Failed to create the OTLP exporter");
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(env!("CARGO_PKG_NAME")).build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    let _ = TRACER_PROVIDER.set(provider);
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Makes the trace context given in the `traceparent` header of an incoming request the parent of the request's span,
+/// so that the span is part of the caller's trace. Does nothing if the request has no trace context.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(context);
+}
+
+/// Returns the headers carrying the trace context of the current span, to be sent along with an outgoing request.
+/// Returns no headers if no collector endpoint is configured.
+pub fn context_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut HeaderInjector(&mut headers)));
+    headers
+}
+
+/// Exports the spans which were not exported yet. Called on shutdown.
+pub fn shutdown() {
+    if let Some(provider) = TRACER_PROVIDER.get()
+        && let Err(err) = provider.shutdown()
+    {
+        error!("Failed to export the remaining spans: '{err}'");
+    }
+}
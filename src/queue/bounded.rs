@@ -0,0 +1,137 @@
+use super::{Identifiable, Predicate, Queue, QueueBackend, QueueError, QueueItem, QueueResult, Update};
+use async_trait::async_trait;
+use uuid::Uuid;
+
+/// A wrapper around another queue which limits the number of elements it can hold.
+/// Once the capacity is reached, enqueueing fails with [`QueueError::Full`] and the wrapped queue is left untouched.
+/// All other operations are passed through to the wrapped queue.
+#[derive(Debug)]
+pub struct BoundedQueue<T> {
+    inner: Queue<T>,
+    capacity: usize,
+}
+
+impl<T> BoundedQueue<T> {
+    /// Wraps the given queue, limiting it to the given number of elements.
+    pub fn new(inner: Queue<T>, capacity: usize) -> Self {
+        Self { inner, capacity }
+    }
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for BoundedQueue<T> {
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        self.inner.dequeue().await
+    }
+
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        self.inner.dequeue_matching(matches).await
+    }
+
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        self.inner.peek().await
+    }
+
+    async fn len(&self) -> QueueResult<usize> {
+        self.inner.len().await
+    }
+
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        self.inner.to_vec(limit).await
+    }
+
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        self.inner.position_of(matches).await
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        self.inner.find_by_id(id).await
+    }
+
+    /// Inserts an element into the wrapped queue if it is not full,
+    /// and returns its position in the queue.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        if self.inner.len().await? >= self.capacity {
+            return Err(QueueError::Full);
+        }
+        self.inner.enqueue(item).await
+    }
+
+    /// Inserts the elements into the wrapped queue if all of them fit,
+    /// and returns their positions in the queue. If they do not fit, none of them are inserted.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        if self.inner.len().await? + items.len() > self.capacity {
+            return Err(QueueError::Full);
+        }
+        self.inner.enqueue_many(items).await
+    }
+
+    async fn remove_by_id(&mut self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        self.inner.remove_by_id(id).await
+    }
+
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        self.inner.retain(keep).await
+    }
+
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        self.inner.update(update).await
+    }
+
+    async fn clear(&mut self) -> QueueResult<usize> {
+        self.inner.clear().await
+    }
+
+    async fn check(&self) -> QueueResult<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&mut self) -> QueueResult<()> {
+        self.inner.flush().await
+    }
+
+    async fn compact(&mut self) -> QueueResult<u64> {
+        self.inner.compact().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::TestItem;
+    use super::super::{InMemoryQueue, JsonFileQueue};
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn rejects_enqueues_past_capacity() {
+        let mut queue = BoundedQueue::new(Box::new(InMemoryQueue::new()), 2);
+        queue.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+        let result = queue.enqueue(TestItem { id: 3, priority: 9 }).await;
+        assert!(matches!(result, Err(QueueError::Full)), "unexpected result {result:?}");
+        assert_eq!(QueueError::Full.status_code(), axum::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(1..=2));
+
+        queue.dequeue().await.unwrap();
+        assert_eq!(queue.enqueue(TestItem { id: 3, priority: 0 }).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_bulk_enqueues_which_do_not_fit_entirely() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let mut queue = BoundedQueue::new(Box::new(JsonFileQueue::new(&file)), 3);
+        queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap();
+        let contents = std::fs::read(&file).unwrap();
+
+        let result = queue.enqueue_many(TestItem::many(2..=4)).await;
+        assert!(matches!(result, Err(QueueError::Full)), "unexpected result {result:?}");
+        assert_eq!(std::fs::read(&file).unwrap(), contents);
+        assert_eq!(queue.enqueue_many(TestItem::many(2..=3)).await.unwrap(), [2, 3]);
+    }
+}
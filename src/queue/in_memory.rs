@@ -1,4 +1,7 @@
+use super::{Predicate, QueueBackend, QueueItem, QueueResult, Update, insertion_index, merge_by_priority};
+use async_trait::async_trait;
 use std::collections::VecDeque;
+use std::mem;
 
 /// A queue backed by an in-memory VecDeque.
 /// This is the simplest and most performant queue implementation.
@@ -11,15 +14,86 @@ impl<T> InMemoryQueue<T> {
     pub fn new() -> Self {
         Self(VecDeque::new())
     }
+}
 
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for InMemoryQueue<T> {
     /// Removes and returns the first element of the queue, if there is one.
-    pub fn dequeue(&mut self) -> Option<T> {
-        self.0.pop_front()
+    /// This operation never fails.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        Ok(self.0.pop_front())
     }
 
-    /// Appends an element to the end of the queue, and returns the new length of the queue.
-    pub fn enqueue(&mut self, item: T) -> usize {
-        self.0.push_back(item);
-        self.0.len()
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// This operation never fails.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        Ok(self.0.iter().position(matches).and_then(|index| self.0.remove(index)))
     }
-}
\ No newline at end of file
+
+    /// Returns a copy of the first element of the queue without removing it, if there is one.
+    /// This operation never fails.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        Ok(self.0.front().cloned())
+    }
+
+    /// Returns the number of elements in the queue.
+    /// This operation never fails.
+    async fn len(&self) -> QueueResult<usize> {
+        Ok(self.0.len())
+    }
+
+    /// Returns copies of the first `limit` elements of the queue, or of all elements.
+    /// This operation never fails.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        Ok(self.0.iter().take(limit.unwrap_or(usize::MAX)).cloned().collect())
+    }
+
+    /// Returns the position of the first element which matches, if there is one.
+    /// This operation never fails.
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        Ok(self.0.iter().position(matches).map(|index| index + 1))
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// This operation never fails.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let index = insertion_index(&self.0, item.priority());
+        self.0.insert(index, item);
+        Ok(index + 1)
+    }
+
+    /// Inserts the elements according to their priorities, and returns their positions in the queue.
+    /// This operation never fails.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let (merged, positions) = merge_by_priority(mem::take(&mut self.0), items);
+        self.0 = merged.into();
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// This operation never fails.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let len = self.0.len();
+        self.0.retain(|item| keep(item));
+        Ok(len - self.0.len())
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// This operation never fails.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        Ok(self.0.iter_mut().filter_map(|item| update(item).then_some(())).count())
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// This operation never fails.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let len = self.0.len();
+        self.0.clear();
+        Ok(len)
+    }
+
+    /// The queue is always usable.
+    async fn check(&self) -> QueueResult<()> {
+        Ok(())
+    }
+}
@@ -1,109 +1,888 @@
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use super::{Predicate, QueueBackend, QueueItem, QueueResult, Update, insertion_index, merge_by_priority};
+use async_trait::async_trait;
+use derive_more::{Display, FromStr};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
+use std::mem;
 use std::path::Path;
+use std::time::Duration;
 use tokio::fs;
-use tracing::error;
-
-/// Load a JSON file and deserialize it into a `Vec<T>`.
-/// The JSON file must contain a top-level JSON array.
-/// Each element of the array is deserialized into a `T`; if deserialization fails, the element is skipped.
-/// If the file does not exist, or if it is empty, an empty `Vec<T>` is returned.
-async fn load<T: for<'de> Deserialize<'de>>(file: &Path) -> Vec<T> {
-    fs::read_to_string(file)
-        .await
-        .ok()
-        .and_then(|data| serde_json::from_str::<Vec<Value>>(&data).ok())
-        .map(|vec| vec.into_iter().filter_map(|value| serde_json::from_value(value).ok()).collect())
-        .unwrap_or_default()
-}
-
-/// Serialize a slice of Ts into a JSON string and save it to a file.
-/// An error message is logged if the file cannot be written to.
+use tokio::time::Instant;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// The serialization format of a queue file, chosen via the command line.
+/// The binary formats are smaller and faster to read and write than JSON, especially for large job payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, FromStr)]
+pub enum FileFormat {
+    /// A JSON array, stored in a `.json` file. It is pretty-printed unless compact JSON is requested.
+    Json,
+    /// A MessagePack array of maps, stored in a `.msgpack` file.
+    MessagePack,
+    /// A CBOR array of maps, stored in a `.cbor` file.
+    Cbor,
+}
+
+impl FileFormat {
+    /// Returns the file extension of the format, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    /// Returns the format of the given file, judging by its extension after removing a `.gz` extension.
+    /// Files with any other extension are read and written as JSON.
+    fn of(file: &Path) -> Self {
+        let file = if is_compressed(file) { Path::new(file.file_stem().unwrap_or_default()) } else { file };
+        match file.extension().and_then(|extension| extension.to_str()) {
+            Some("msgpack") => Self::MessagePack,
+            Some("cbor") => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+
+    /// Serializes a slice of Ts into an array in this format.
+    /// Structs are serialized as maps, so that fields can be added to them later, just like in JSON.
+    /// JSON is pretty-printed unless `compact_json` is set; the binary formats are always compact.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    fn serialize<T: Serialize>(self, queue: &[T], compact_json: bool) -> Vec<u8> {
+        match self {
+            Self::Json if compact_json => serde_json::to_vec(queue).unwrap(),
+            Self::Json => serde_json::to_vec_pretty(queue).unwrap(),
+            Self::MessagePack => rmp_serde::to_vec_named(queue).unwrap(),
+            Self::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(queue, &mut data).unwrap();
+                data
+            }
+        }
+    }
+
+    /// Deserializes an array in this format, read from the given file, into a `Vec<T>`.
+    /// The array is first read into values of the format, so that each element can be deserialized on its own;
+    /// if deserialization fails, the element is skipped with a warning. Returns None if the data is not an array in this format.
+    fn deserialize<T: DeserializeOwned>(self, file: &Path, data: &[u8]) -> Option<Vec<T>> {
+        match self {
+            Self::Json => {
+                let values = parse_json(data)?;
+                Some(deserialize_elements(file, values, serde_json::from_value))
+            }
+            Self::MessagePack => {
+                let values: Vec<rmpv::Value> = rmp_serde::from_slice(data).ok()?;
+                Some(deserialize_elements(file, values, rmpv::ext::from_value))
+            }
+            Self::Cbor => {
+                let values: Vec<ciborium::Value> = ciborium::from_reader(data).ok()?;
+                Some(deserialize_elements(file, values, |value| value.deserialized()))
+            }
+        }
+    }
+}
+
+/// Parses a JSON array into values. With the `json5` feature, JSON5 is accepted as well, so that hand-edited queue files
+/// may contain comments and trailing commas. Strict JSON is tried first, since it is what the queue files are written in.
+#[cfg(feature = "json5")]
+fn parse_json(data: &[u8]) -> Option<Vec<serde_json::Value>> {
+    serde_json::from_slice(data).ok().or_else(|| json5::from_str(std::str::from_utf8(data).ok()?).ok())
+}
+
+/// Parses a JSON array into values.
+#[cfg(not(feature = "json5"))]
+fn parse_json(data: &[u8]) -> Option<Vec<serde_json::Value>> {
+    serde_json::from_slice(data).ok()
+}
+
+/// Deserializes each of the values read from the given file into a `T`.
+/// Values which cannot be deserialized are skipped, and a warning with their index and the error is logged,
+/// so that elements which no longer match the structure of `T` do not vanish unnoticed.
+fn deserialize_elements<V, T, E: Display>(file: &Path, values: Vec<V>, from_value: impl Fn(V) -> Result<T, E>) -> Vec<T> {
+    let count = values.len();
+    let items: Vec<T> = values
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, value)| {
+            from_value(value)
+                .inspect_err(|err| warn!("Skipping malformed element {index} of {}: {err}", file.display()))
+                .ok()
+        })
+        .collect();
+    if items.len() < count {
+        warn!("Skipped {} of {count} elements of {}", count - items.len(), file.display());
+    }
+    items
+}
+
+/// Returns whether the given file is gzip-compressed, judging by its `.gz` extension.
+fn is_compressed(file: &Path) -> bool {
+    file.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// The number of times a queue file is read before giving up, unless configured otherwise.
+pub const DEFAULT_READ_ATTEMPTS: u32 = 3;
+
+/// The delay before reading a queue file again after a failed read, doubling with every further attempt.
+const READ_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Reads the contents of a queue file, making up to `attempts` attempts with exponential backoff if reading fails,
+/// e.g. because the file is momentarily locked by another process. Returns None if the file does not exist.
+/// Returns the last error if every attempt failed, so that the queue is not mistaken for an empty one and overwritten.
+pub(super) async fn read_file(file: &Path, attempts: u32) -> io::Result<Option<Vec<u8>>> {
+    let mut backoff = READ_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let err = match fs::read(file).await {
+            Ok(data) => return Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => err,
+        };
+        if attempt >= attempts {
+            error!("Failed to read {} (attempt {attempt}/{attempts}): {err}", file.display());
+            return Err(err);
+        }
+        warn!("Failed to read {} (attempt {attempt}/{attempts}), retrying in {backoff:?}: {err}", file.display());
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Load a queue file and deserialize it into a `Vec<T>`, reading it up to `read_attempts` times, see [`read_file`].
+/// The file must contain a top-level array in the [`FileFormat`] given by its extension,
+/// and is decompressed first if it is gzip-compressed.
+/// Each element of the array is deserialized into a `T`; if deserialization fails, the element is skipped with a warning.
+/// If the file does not exist, or if it is empty or only contains whitespace, an empty `Vec<T>` is returned.
+/// Returns an error if the file exists but could not be read, if it is compressed but could not be decompressed,
+/// or if it does not contain an array in its format (with [`io::ErrorKind::InvalidData`]), e.g. because it was truncated
+/// or edited by hand, so that the queue is not mistaken for an empty one and overwritten.
+async fn load<T: DeserializeOwned>(file: &Path, read_attempts: u32) -> io::Result<Vec<T>> {
+    let Some(data) = read_file(file, read_attempts).await? else {
+        return Ok(Vec::new());
+    };
+    let data = if is_compressed(file) && !data.is_empty() {
+        decompress(&data).inspect_err(|err| error!("Failed to decompress {}: {err}", file.display()))?
+    } else {
+        data
+    };
+    if data.trim_ascii().is_empty() {
+        return Ok(Vec::new());
+    }
+    let format = FileFormat::of(file);
+    format.deserialize(file, &data).ok_or_else(|| {
+        let message = format!("{} does not contain an array in the {format} format", file.display());
+        error!("Failed to load queue from file: {message}");
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    })
+}
+
+/// Serialize a slice of Ts in the [`FileFormat`] given by the file's extension and save it to the file,
+/// see [`write_atomically`]. The data is gzip-compressed if the file is, and JSON is written without whitespace if `compact_json` is set.
 /// # Panics
 /// This function panics if the serialization impl for T fails.
 /// It is easily verifiable at compile time that this will never happen.
-async fn save<T: Serialize>(file: &Path, queue: &[T]) {
-    if let Err(err) = fs::write(file, serde_json::to_string_pretty(queue).unwrap()).await {
+async fn save<T: Serialize>(file: &Path, queue: &[T], compact_json: bool) -> io::Result<()> {
+    let data = FileFormat::of(file).serialize(queue, compact_json);
+    if is_compressed(file) {
+        write_atomically(file, compress(&data)?).await
+    } else {
+        write_atomically(file, data).await
+    }
+}
+
+/// Compresses the given bytes with gzip.
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses the given gzip-compressed bytes.
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Replaces the contents of a file.
+/// The data is first written to a uniquely named temporary file next to the target,
+/// which is then renamed over the target. The rename is atomic on the same filesystem,
+/// so a crash mid-write never leaves the target file truncated.
+/// An error message is logged and returned if the file cannot be written to.
+pub(super) async fn write_atomically(file: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut tmp_name = file.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(".{}.tmp", Uuid::new_v4()));
+    let tmp_file = file.with_file_name(tmp_name);
+    let result = match fs::write(&tmp_file, contents).await {
+        Ok(()) => fs::rename(&tmp_file, file).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = &result {
         error!("Failed to save queue to file: {}", err);
+        let _ = fs::remove_file(&tmp_file).await;
     }
+    result
 }
 
-/// A queue backed by a JSON file.
+/// Verifies that a file can be written next to the given file, by creating and removing a probe file.
+pub(super) async fn check_writable(file: &Path) -> io::Result<()> {
+    let mut probe_name = file.file_name().unwrap_or_default().to_os_string();
+    probe_name.push(format!(".{}.probe", Uuid::new_v4()));
+    let probe_file = file.with_file_name(probe_name);
+    fs::write(&probe_file, b"").await?;
+    fs::remove_file(&probe_file).await
+}
+
+/// A queue backed by a JSON file, or a file in another [`FileFormat`] given by its extension.
 /// Every operation on the queue reads from or writes to the file.
+/// If the file exists but cannot be read, the operation fails rather than treating the queue as empty,
+/// so that a transient read error never causes the file to be overwritten.
 #[derive(Debug)]
 pub struct JsonFileQueue<T> {
     file: Box<Path>, // Path is an unsized type, so we need to box it to store it on the heap
+    compact_json: bool,
+    read_attempts: u32,
     _phantom: PhantomData<T>, // This field is needed to keep the type parameter T alive
 }
 
 impl<T> JsonFileQueue<T>
 where
-    T: Serialize + for<'de> Deserialize<'de>,
+    T: QueueItem,
 {
     /// Creates a new JsonFileQueue pointing to the given file path.
     pub fn new(file: impl AsRef<Path>) -> Self {
         Self {
             file: Box::from(file.as_ref()),
+            compact_json: false,
+            read_attempts: DEFAULT_READ_ATTEMPTS,
             _phantom: PhantomData,
         }
     }
 
+    /// Writes JSON files without whitespace instead of pretty-printing them,
+    /// which makes large queues faster to write and smaller on disk.
+    pub fn with_compact_json(mut self) -> Self {
+        self.compact_json = true;
+        self
+    }
+
+    /// Sets the number of times the file is read before an operation fails, see [`read_file`].
+    pub fn with_read_attempts(mut self, attempts: u32) -> Self {
+        self.read_attempts = attempts;
+        self
+    }
+
+    /// Loads the queue from the file, see [`load`].
+    async fn load(&self) -> io::Result<Vec<T>> {
+        load(&self.file, self.read_attempts).await
+    }
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for JsonFileQueue<T> {
     /// Removes and returns the first element of the queue, if there is one.
     /// This operation reads from and writes to the file.
-    pub async fn dequeue(&mut self) -> Option<T> {
-        let mut queue = load(&self.file).await;
-        let item = queue.pop();
-        save(&self.file, &queue).await;
-        item
+    /// If the file cannot be written to, the element stays in the queue and an error is returned.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        let mut queue = self.load().await?;
+        let item = (!queue.is_empty()).then(|| queue.remove(0));
+        save(&self.file, &queue, self.compact_json).await?;
+        Ok(item)
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// This operation reads from the file, and writes to it if an element was removed.
+    /// If the file cannot be written to, the element stays in the queue and an error is returned.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let mut queue = self.load().await?;
+        let Some(index) = queue.iter().position(matches) else {
+            return Ok(None);
+        };
+        let item = queue.remove(index);
+        save(&self.file, &queue, self.compact_json).await?;
+        Ok(Some(item))
+    }
+
+    /// Returns the first element of the queue without removing it, if there is one.
+    /// This operation only reads from the file.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        Ok(self.load().await?.into_iter().next())
+    }
+
+    /// Returns the number of elements in the queue.
+    /// This operation only reads from the file.
+    async fn len(&self) -> QueueResult<usize> {
+        Ok(self.load().await?.len())
+    }
+
+    /// Returns the first `limit` elements of the queue, or all elements, as read from the file.
+    /// This operation only reads from the file.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        let mut queue = self.load().await?;
+        queue.truncate(limit.unwrap_or(usize::MAX));
+        Ok(queue)
     }
 
-    /// Appends an element to the end of the queue, and returns the new length of the queue.
+    /// Inserts an element according to its priority, and returns its position in the queue.
     /// This operation reads from and writes to the file.
-    pub async fn enqueue(&mut self, item: T) -> usize {
-        let mut queue = load(&self.file).await;
-        queue.push(item);
-        save(&self.file, &queue).await;
-        queue.len()
+    /// If the file cannot be written to, the element is not added and an error is returned.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let mut queue = self.load().await?;
+        let index = insertion_index(&queue, item.priority());
+        queue.insert(index, item);
+        save(&self.file, &queue, self.compact_json).await?;
+        Ok(index + 1)
+    }
+
+    /// Inserts the elements according to their priorities, and returns their positions in the queue.
+    /// This operation reads from and writes to the file once, so bulk-loading a queue this way serializes it only once.
+    /// If the file cannot be written to, the elements are not added and an error is returned.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let (queue, positions) = merge_by_priority(self.load().await?, items);
+        save(&self.file, &queue, self.compact_json).await?;
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// This operation reads from the file, and writes to it if any elements were removed.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let mut queue = self.load().await?;
+        let len = queue.len();
+        queue.retain(|item| keep(item));
+        let removed = len - queue.len();
+        if removed > 0 {
+            save(&self.file, &queue, self.compact_json).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// This operation reads from the file, and writes to it if any elements were modified.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let mut queue = self.load().await?;
+        let updated = queue.iter_mut().filter_map(|item| update(item).then_some(())).count();
+        if updated > 0 {
+            save(&self.file, &queue, self.compact_json).await?;
+        }
+        Ok(updated)
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// This operation reads from the file, and writes an empty array to it.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let len = self.load().await?.len();
+        save::<T>(&self.file, &[], self.compact_json).await?;
+        Ok(len)
+    }
+
+    /// Verifies that the file can be written to.
+    async fn check(&self) -> QueueResult<()> {
+        Ok(check_writable(&self.file).await?)
     }
 }
 
-/// A queue backed by a JSON file with an in-memory cache.
+/// A queue backed by a JSON file, or a file in another [`FileFormat`] given by its extension, with an in-memory cache.
 /// The cache is loaded once upon creation and is updated on every enqueue and dequeue operation.
 /// Additionally, the file is written to on every enqueue and dequeue operation.
 /// This is more performant than JsonFileQueue because it only reads from the file once,
 /// but is more memory-intensive because it keeps the entire queue in memory.
+///
+/// Optionally, writes can be debounced with [`with_write_debounce`](Self::with_write_debounce):
+/// the file is then written at most once per interval, and any remaining changes are written by [`flush`](QueueBackend::flush).
+/// This trades durability for throughput, since a crash can lose up to one interval worth of changes.
 #[derive(Debug)]
 pub struct CachedJsonFileQueue<T> {
     file: Box<Path>,
     cache: Vec<T>,
+    write_debounce: Option<Duration>,
+    compact_json: bool,
+    dirty: bool, // Whether the cache contains changes which have not been written to the file yet
+    last_save: Instant,
 }
 
 impl<T> CachedJsonFileQueue<T>
 where
-    T: Serialize + for<'de> Deserialize<'de>,
+    T: QueueItem,
 {
     /// Creates a new CachedJsonFileQueue pointing to the given file path.
-    /// The queue is loaded from the file upon creation.
-    pub async fn new(file: impl AsRef<Path>) -> Self {
+    /// The queue is loaded from the file upon creation, reading it up to `read_attempts` times, see [`read_file`].
+    /// Returns an error if the file exists but could not be read.
+    pub async fn new(file: impl AsRef<Path>, read_attempts: u32) -> QueueResult<Self> {
         let file = Box::from(file.as_ref());
-        let cache = load(&file).await;
-        Self { file, cache }
+        let cache = load(&file, read_attempts).await?;
+        Ok(Self {
+            file,
+            cache,
+            write_debounce: None,
+            compact_json: false,
+            dirty: false,
+            last_save: Instant::now(),
+        })
+    }
+
+    /// Debounces writes to the file so that it is written at most once per `interval`.
+    /// Changes made in between are only kept in the cache until the next write or [`flush`](QueueBackend::flush).
+    pub fn with_write_debounce(mut self, interval: Duration) -> Self {
+        self.write_debounce = Some(interval);
+        self
+    }
+
+    /// Writes JSON files without whitespace instead of pretty-printing them,
+    /// which makes large queues faster to write and smaller on disk.
+    pub fn with_compact_json(mut self) -> Self {
+        self.compact_json = true;
+        self
+    }
+
+    /// Writes the cache to the file after it was replaced, unless writes are debounced.
+    /// If the file cannot be written to, the `previous` contents of the cache are restored and an error is returned.
+    async fn persist_replaced(&mut self, previous: Vec<T>) -> io::Result<()> {
+        if let Some(interval) = self.write_debounce {
+            self.save_debounced(interval).await;
+        } else if let Err(err) = save(&self.file, &self.cache, self.compact_json).await {
+            self.cache = previous;
+            return Err(err);
+        }
+        Ok(())
     }
 
+    /// Marks the cache as dirty and writes it to the file if the debounce interval has elapsed.
+    /// Errors are not returned because the changes stay in the cache and are retried on the next write.
+    async fn save_debounced(&mut self, interval: Duration) {
+        self.dirty = true;
+        if self.last_save.elapsed() >= interval {
+            let _ = self.flush().await;
+        }
+    }
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for CachedJsonFileQueue<T> {
     /// Removes and returns the first element of the queue, if there is one.
-    /// This operation writes to the file.
-    pub async fn dequeue(&mut self) -> Option<T> {
-        let item = self.cache.pop();
-        save(&self.file, &self.cache).await;
-        item
-    }
-
-    /// Appends an element to the end of the queue, and returns the new length of the queue.
-    /// This operation writes to the file.
-    pub async fn enqueue(&mut self, item: T) -> usize {
-        self.cache.push(item);
-        save(&self.file, &self.cache).await;
-        self.cache.len()
+    /// This operation writes to the file, unless writes are debounced.
+    /// If the file cannot be written to, the element is put back into the cache and an error is returned.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        if self.cache.is_empty() {
+            return Ok(None);
+        }
+        let item = self.cache.remove(0);
+        if let Some(interval) = self.write_debounce {
+            self.save_debounced(interval).await;
+        } else if let Err(err) = save(&self.file, &self.cache, self.compact_json).await {
+            self.cache.insert(0, item);
+            return Err(err.into());
+        }
+        Ok(Some(item))
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// This operation writes to the file if an element was removed, unless writes are debounced.
+    /// If the file cannot be written to, the element is put back into the cache and an error is returned.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let Some(index) = self.cache.iter().position(matches) else {
+            return Ok(None);
+        };
+        let item = self.cache.remove(index);
+        if let Some(interval) = self.write_debounce {
+            self.save_debounced(interval).await;
+        } else if let Err(err) = save(&self.file, &self.cache, self.compact_json).await {
+            self.cache.insert(index, item);
+            return Err(err.into());
+        }
+        Ok(Some(item))
+    }
+
+    /// Returns a copy of the first element of the queue without removing it, if there is one.
+    /// This operation only reads from the cache, and never fails.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        Ok(self.cache.first().cloned())
+    }
+
+    /// Returns the number of elements in the queue.
+    /// This operation only reads from the cache, and never fails.
+    async fn len(&self) -> QueueResult<usize> {
+        Ok(self.cache.len())
+    }
+
+    /// Returns copies of the first `limit` elements of the cache, or of all elements.
+    /// This operation never fails.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        Ok(self.cache.iter().take(limit.unwrap_or(usize::MAX)).cloned().collect())
+    }
+
+    /// Returns the position of the first element of the cache which matches, if there is one.
+    /// This operation never fails.
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        Ok(self.cache.iter().position(matches).map(|index| index + 1))
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// This operation writes to the file, unless writes are debounced.
+    /// If the file cannot be written to, the element is removed from the cache again and an error is returned.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let index = insertion_index(&self.cache, item.priority());
+        self.cache.insert(index, item);
+        if let Some(interval) = self.write_debounce {
+            self.save_debounced(interval).await;
+        } else if let Err(err) = save(&self.file, &self.cache, self.compact_json).await {
+            self.cache.remove(index);
+            return Err(err.into());
+        }
+        Ok(index + 1)
+    }
+
+    /// Inserts the elements according to their priorities, and returns their positions in the queue.
+    /// This operation writes to the file once, unless writes are debounced.
+    /// If the file cannot be written to, the elements are removed from the cache again and an error is returned.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let (merged, positions) = merge_by_priority(self.cache.iter().cloned(), items);
+        let previous = mem::replace(&mut self.cache, merged);
+        self.persist_replaced(previous).await?;
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// This operation writes to the file if any elements were removed, unless writes are debounced.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let mut retained = self.cache.clone();
+        retained.retain(|item| keep(item));
+        let removed = self.cache.len() - retained.len();
+        if removed > 0 {
+            let previous = mem::replace(&mut self.cache, retained);
+            self.persist_replaced(previous).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// This operation writes to the file if any elements were modified, unless writes are debounced.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let mut updated = self.cache.clone();
+        let count = updated.iter_mut().filter_map(|item| update(item).then_some(())).count();
+        if count > 0 {
+            let previous = mem::replace(&mut self.cache, updated);
+            self.persist_replaced(previous).await?;
+        }
+        Ok(count)
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// This operation writes an empty array to the file, unless writes are debounced.
+    /// If the file cannot be written to, the elements are put back into the cache and an error is returned.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let previous = mem::take(&mut self.cache);
+        let len = previous.len();
+        self.persist_replaced(previous).await?;
+        Ok(len)
+    }
+
+    /// Verifies that the file can be written to.
+    async fn check(&self) -> QueueResult<()> {
+        Ok(check_writable(&self.file).await?)
+    }
+
+    /// Writes the cache to the file if it contains changes which have not been written yet.
+    async fn flush(&mut self) -> QueueResult<()> {
+        if self.dirty {
+            save(&self.file, &self.cache, self.compact_json).await?;
+            self.dirty = false;
+            self.last_save = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::QueueError;
+    use super::super::tests::TestItem;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// A log output which collects everything written to it.
+    #[derive(Debug, Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        /// Calls `f` while collecting the events it logs, and returns its result along with the collected output.
+        fn capture<R>(f: impl FnOnce() -> R) -> (R, String) {
+            let logs = Self::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+            let result = tracing::subscriber::with_default(subscriber, f);
+            let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            (result, output)
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_queue_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json.gz");
+        let mut queue = JsonFileQueue::new(&file);
+        for item in TestItem::many(1..=3) {
+            queue.enqueue(item).await.unwrap();
+        }
+        let data = std::fs::read(&file).unwrap();
+        assert_eq!(data[..2], [0x1f, 0x8b], "the file is not gzip-compressed");
+
+        let mut cached = CachedJsonFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        assert_eq!(cached.to_vec(None).await.unwrap(), TestItem::many(1..=3));
+        assert_eq!(cached.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(2..=3));
+    }
+
+    #[tokio::test]
+    async fn corrupt_compressed_file_is_not_overwritten() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json.gz");
+        let mut data = compress(&serde_json::to_vec(&TestItem::many(1..=3)).unwrap()).unwrap();
+        data.truncate(data.len() / 2);
+        std::fs::write(&file, &data).unwrap();
+
+        let mut queue = JsonFileQueue::new(&file);
+        let result = queue.enqueue(TestItem { id: 4, priority: 0 }).await;
+        assert!(matches!(result, Err(QueueError::Io(_))), "unexpected result {result:?}");
+        assert!(CachedJsonFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn unparseable_file_is_not_overwritten() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let data = br#"[{"id": 1, "priority": 0}, {"id": 2, "prio"#;
+        std::fs::write(&file, data).unwrap();
+
+        let mut queue = JsonFileQueue::new(&file);
+        match queue.enqueue(TestItem { id: 3, priority: 0 }).await {
+            Err(QueueError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert!(queue.clear().await.is_err());
+        assert!(CachedJsonFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn empty_file_is_an_empty_queue() {
+        let dir = TempDir::new().unwrap();
+        for (name, data) in [("jobs.json", "\n"), ("jobs.json.gz", ""), ("jobs.msgpack", "")] {
+            let file = dir.path().join(name);
+            std::fs::write(&file, data).unwrap();
+            let mut queue = JsonFileQueue::new(&file);
+            assert_eq!(queue.len().await.unwrap(), 0, "{name}");
+            assert_eq!(queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap(), 1, "{name}");
+        }
+    }
+
+    /// A file which cannot be read, here because it is a symbolic link to itself, must neither be treated as an empty
+    /// queue nor be replaced by one, although the link itself could be replaced by renaming a file over it.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unreadable_file_is_not_overwritten() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        std::os::unix::fs::symlink(&file, &file).unwrap();
+
+        let mut queue = JsonFileQueue::new(&file).with_read_attempts(2);
+        let result = queue.enqueue(TestItem { id: 1, priority: 0 }).await;
+        assert!(matches!(result, Err(QueueError::Io(_))), "unexpected result {result:?}");
+        assert!(matches!(queue.dequeue().await, Err(QueueError::Io(_))));
+        assert!(CachedJsonFileQueue::<TestItem>::new(&file, 1).await.is_err());
+        assert!(std::fs::symlink_metadata(&file).unwrap().is_symlink(), "the file was overwritten");
+    }
+
+    #[test]
+    fn malformed_elements_are_skipped_with_a_warning() {
+        let file = Path::new("jobs.json");
+        let data = br#"[{"id": 1, "priority": 0}, {"id": "two", "priority": 0}, {"id": 3, "priority": 0}]"#;
+        let (items, logs) = Logs::capture(|| FileFormat::Json.deserialize::<TestItem>(file, data));
+        assert_eq!(items, Some(vec![TestItem { id: 1, priority: 0 }, TestItem { id: 3, priority: 0 }]));
+        assert!(logs.contains("WARN") && logs.contains("Skipping malformed element 1 of jobs.json"), "{logs}");
+        assert!(logs.contains("Skipped 1 of 3 elements of jobs.json"), "{logs}");
+    }
+
+    #[test]
+    fn well_formed_elements_load_without_a_warning() {
+        let data = FileFormat::Cbor.serialize(&TestItem::many(1..=2), false);
+        let (items, logs) = Logs::capture(|| FileFormat::Cbor.deserialize::<TestItem>(Path::new("jobs.cbor"), &data));
+        assert_eq!(items, Some(TestItem::many(1..=2)));
+        assert!(!logs.contains("WARN"), "{logs}");
+    }
+
+    /// Returns the names of the temporary files left in the given directory.
+    fn tmp_files(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn partial_write_leaves_previous_contents() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let mut queue = JsonFileQueue::new(&file);
+        queue.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+        // A crash mid-write leaves a truncated temporary file behind, but never a truncated queue file
+        let data = std::fs::read(&file).unwrap();
+        std::fs::write(dir.path().join(format!("jobs.json.{}.tmp", Uuid::new_v4())), &data[..data.len() / 2]).unwrap();
+
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(1..=2));
+        queue.enqueue(TestItem { id: 3, priority: 0 }).await.unwrap();
+        assert_eq!(JsonFileQueue::<TestItem>::new(&file).to_vec(None).await.unwrap(), TestItem::many(1..=3));
+    }
+
+    #[tokio::test]
+    async fn failed_write_leaves_previous_contents() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        std::fs::create_dir(&file).unwrap();
+        std::fs::write(file.join("keep"), b"").unwrap();
+
+        assert!(write_atomically(&file, b"[]").await.is_err());
+        assert!(file.join("keep").exists(), "the target was replaced");
+        assert_eq!(tmp_files(dir.path()), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_do_not_clobber_each_other() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let first = serde_json::to_vec(&TestItem::many(1..=100)).unwrap();
+        let second = serde_json::to_vec(&TestItem::many(101..=200)).unwrap();
+        let (a, b) = tokio::join!(write_atomically(&file, &first), write_atomically(&file, &second));
+        a.unwrap();
+        b.unwrap();
+        let data = std::fs::read(&file).unwrap();
+        assert!(data == first || data == second, "the file contains a mix of both writes");
+        assert_eq!(tmp_files(dir.path()), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn debounced_writes_converge_with_the_cache() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let on_disk = || async { JsonFileQueue::<TestItem>::new(&file).to_vec(None).await.unwrap() };
+        let mut queue = CachedJsonFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap()
+            .with_write_debounce(Duration::from_millis(200));
+        for item in TestItem::many(1..=3) {
+            queue.enqueue(item).await.unwrap();
+        }
+        assert_eq!(queue.len().await.unwrap(), 3);
+        assert_eq!(on_disk().await, vec![], "the writes were not debounced");
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        queue.dequeue().await.unwrap();
+        assert_eq!(on_disk().await, TestItem::many(2..=3));
+
+        queue.enqueue(TestItem { id: 4, priority: 0 }).await.unwrap();
+        assert_eq!(on_disk().await, TestItem::many(2..=3));
+        queue.flush().await.unwrap();
+        assert_eq!(on_disk().await, queue.to_vec(None).await.unwrap());
+        assert_eq!(on_disk().await, TestItem::many(2..=4));
+    }
+
+    #[tokio::test]
+    async fn every_format_round_trips() {
+        let dir = TempDir::new().unwrap();
+        for (name, format) in [("jobs.json", FileFormat::Json), ("jobs.msgpack", FileFormat::MessagePack), ("jobs.cbor", FileFormat::Cbor)] {
+            let file = dir.path().join(name);
+            assert_eq!(FileFormat::of(&file), format);
+            let mut queue = JsonFileQueue::new(&file);
+            queue.enqueue_many(vec![TestItem { id: 1, priority: 0 }, TestItem { id: 2, priority: 5 }]).await.unwrap();
+            let data = std::fs::read(&file).unwrap();
+            assert_eq!(format.deserialize::<TestItem>(&file, &data), Some(vec![TestItem { id: 2, priority: 5 }, TestItem { id: 1, priority: 0 }]), "{name}");
+
+            let mut cached = CachedJsonFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+            assert_eq!(cached.dequeue().await.unwrap(), Some(TestItem { id: 2, priority: 5 }), "{name}");
+            assert_eq!(queue.to_vec(None).await.unwrap(), vec![TestItem { id: 1, priority: 0 }], "{name}");
+        }
+    }
+
+    #[test]
+    fn binary_formats_are_not_json() {
+        let items = TestItem::many(1..=2);
+        for format in [FileFormat::MessagePack, FileFormat::Cbor] {
+            let data = format.serialize(&items, false);
+            assert!(serde_json::from_slice::<serde_json::Value>(&data).is_err(), "{format} was written as JSON");
+            assert_eq!(format.deserialize::<TestItem>(Path::new("jobs"), &data), Some(items.clone()), "{format}");
+        }
+        assert_eq!(FileFormat::of(Path::new("jobs.msgpack.gz")), FileFormat::MessagePack);
+        assert_eq!(FileFormat::of(Path::new("jobs.txt")), FileFormat::Json);
+    }
+
+    #[tokio::test]
+    async fn compact_and_pretty_json_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let compact_file = dir.path().join("compact.json");
+        let pretty_file = dir.path().join("pretty.json");
+        let mut compact = JsonFileQueue::new(&compact_file).with_compact_json();
+        let mut pretty = JsonFileQueue::new(&pretty_file);
+        compact.enqueue_many(TestItem::many(1..=3)).await.unwrap();
+        pretty.enqueue_many(TestItem::many(1..=3)).await.unwrap();
+
+        let compact_data = std::fs::read_to_string(&compact_file).unwrap();
+        assert!(!compact_data.contains('\n'), "{compact_data}");
+        assert!(std::fs::read_to_string(&pretty_file).unwrap().lines().count() > 3);
+        // Either form is read back the same way, regardless of how the reading queue writes
+        assert_eq!(JsonFileQueue::<TestItem>::new(&compact_file).to_vec(None).await.unwrap(), TestItem::many(1..=3));
+        assert_eq!(JsonFileQueue::<TestItem>::new(&pretty_file).with_compact_json().to_vec(None).await.unwrap(), TestItem::many(1..=3));
+
+        let mut cached = CachedJsonFileQueue::<TestItem>::new(&pretty_file, DEFAULT_READ_ATTEMPTS).await.unwrap().with_compact_json();
+        cached.dequeue().await.unwrap();
+        assert!(!std::fs::read_to_string(&pretty_file).unwrap().contains('\n'));
+        assert_eq!(pretty.to_vec(None).await.unwrap(), TestItem::many(2..=3));
+    }
+
+    /// A hand-edited queue file with comments and a trailing comma.
+    const JSON5_FILE: &str = r#"[
+        // Stuck, moved to the front by hand
+        { "id": 2, "priority": 0 },
+        { "id": 1, "priority": 0, }, /* was first */
+    ]"#;
+
+    #[cfg(feature = "json5")]
+    #[tokio::test]
+    async fn json5_file_loads_and_is_written_as_strict_json() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        std::fs::write(&file, JSON5_FILE).unwrap();
+
+        let mut queue = JsonFileQueue::new(&file);
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many([2, 1]));
+        queue.enqueue(TestItem { id: 3, priority: 0 }).await.unwrap();
+        let data = std::fs::read(&file).unwrap();
+        assert_eq!(serde_json::from_slice::<Vec<TestItem>>(&data).unwrap(), TestItem::many([2, 1, 3]));
+    }
+
+    #[cfg(not(feature = "json5"))]
+    #[tokio::test]
+    async fn json5_file_is_rejected_without_the_feature() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        std::fs::write(&file, JSON5_FILE).unwrap();
+        assert!(JsonFileQueue::<TestItem>::new(&file).to_vec(None).await.is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), JSON5_FILE);
     }
 }
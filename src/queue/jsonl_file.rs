@@ -0,0 +1,384 @@
+use super::json_file::{check_writable, read_file, write_atomically};
+use super::{Predicate, QueueBackend, QueueItem, QueueResult, Update, insertion_index, record_insertion};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::path::Path;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tracing::{error, warn};
+
+/// The number of stale lines a file may always contain before it is compacted,
+/// so that small queues are not compacted on almost every operation.
+const MIN_COMPACTION_LINES: usize = 1000;
+
+/// A line of a JSONL queue file.
+#[derive(Debug, Serialize, Deserialize)]
+enum Line<I> {
+    /// An element with the given sequence number, which replaces any earlier element with the same sequence number.
+    Item { seq: u64, item: I },
+    /// A tombstone, which removes the element with the given sequence number.
+    Removed { seq: u64 },
+}
+
+impl<I: Serialize> Line<I> {
+    /// Serializes the line into a JSON string terminated by a newline.
+    /// # Panics
+    /// This function panics if the serialization impl for I fails.
+    fn to_json_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+/// An element of the queue along with the sequence number under which it is stored in the file.
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    seq: u64,
+    item: T,
+}
+
+/// Replays the lines of a JSONL file and returns the elements in queue order, along with the number of lines read.
+/// Elements are ordered by their priority, and by their sequence number within the same priority,
+/// which is the order in which [`insertion_index`] would have inserted them.
+/// Lines which cannot be parsed, such as a partial last line left by a crash mid-append, are skipped.
+/// If the file does not exist, or if it is empty, an empty queue is returned.
+/// Returns an error if the file exists but could not be read within `read_attempts` attempts, see [`read_file`].
+async fn load<T: QueueItem>(file: &Path, read_attempts: u32) -> io::Result<(Vec<Entry<T>>, usize)> {
+    let data = read_file(file, read_attempts).await?.unwrap_or_default();
+    let mut items = BTreeMap::new();
+    let mut lines = 0;
+    for line in String::from_utf8_lossy(&data).lines().filter(|line| !line.trim().is_empty()) {
+        lines += 1;
+        match serde_json::from_str::<Line<T>>(line) {
+            Ok(Line::Item { seq, item }) => {
+                items.insert(seq, item);
+            }
+            Ok(Line::Removed { seq }) => {
+                items.remove(&seq);
+            }
+            Err(err) => warn!("Skipping unreadable line {lines} of {}: {err}", file.display()),
+        }
+    }
+    let mut queue: Vec<_> = items.into_iter().map(|(seq, item)| Entry { seq, item }).collect();
+    // The sort is stable, so elements with the same priority stay in the order of their sequence numbers
+    queue.sort_by_key(|entry| Reverse(entry.item.priority()));
+    Ok((queue, lines))
+}
+
+/// A queue backed by a newline-delimited JSON (JSONL) file with an in-memory cache.
+///
+/// Instead of rewriting the whole file, every operation appends one line per affected element:
+/// enqueueing appends the element along with a sequence number, updating appends the element again under the same
+/// sequence number, and dequeueing or removing appends a tombstone with the sequence number of the removed element.
+/// Removing the head of the queue therefore costs a single append, just like adding to the tail.
+/// When the queue is loaded, the lines are replayed in order to reconstruct the queue.
+///
+/// Since removed and updated elements leave stale lines behind, the file is compacted, i.e. atomically replaced with
+/// one line per remaining element, once it contains more stale lines than elements (and at least [`MIN_COMPACTION_LINES`]).
+/// The file is also compacted when the queue is created, which discards a partial last line left by a crash mid-append,
+/// and after an append failed, since the failed append may have left a partial line behind.
+#[derive(Debug)]
+pub struct JsonlFileQueue<T> {
+    file: Box<Path>,
+    cache: Vec<Entry<T>>,
+    next_seq: u64,
+    stale_lines: usize, // The number of lines in the file which do not hold a current element
+    writer: Option<File>, // The file opened for appending, or None if the file must be compacted before the next append
+}
+
+impl<T> JsonlFileQueue<T>
+where
+    T: QueueItem,
+{
+    /// Creates a new JsonlFileQueue pointing to the given file path.
+    /// The queue is loaded from the file upon creation, after which the file is compacted.
+    /// If the compaction fails, it is retried on the next operation which changes the queue.
+    /// Returns an error if the file exists but could not be read within `read_attempts` attempts,
+    /// in which case it is left as it was.
+    pub async fn new(file: impl AsRef<Path>, read_attempts: u32) -> QueueResult<Self> {
+        let file = Box::from(file.as_ref());
+        let (cache, lines) = load::<T>(&file, read_attempts).await?;
+        let mut queue = Self {
+            next_seq: cache.iter().map(|entry| entry.seq + 1).max().unwrap_or(0),
+            stale_lines: lines - cache.len(),
+            file,
+            cache,
+            writer: None,
+        };
+        let _ = queue.rewrite().await;
+        Ok(queue)
+    }
+
+    /// Atomically replaces the file with one line per element of the cache, and opens it for appending.
+    /// Returns an error if the file could not be replaced, in which case it is left as it was.
+    async fn rewrite(&mut self) -> io::Result<()> {
+        self.writer = None;
+        let contents: String = self.cache.iter()
+            .map(|entry| Line::Item { seq: entry.seq, item: &entry.item }.to_json_line())
+            .collect();
+        write_atomically(&self.file, contents).await?;
+        self.stale_lines = 0;
+        match OpenOptions::new().append(true).open(&self.file).await {
+            Ok(writer) => self.writer = Some(writer),
+            Err(err) => error!("Failed to open {} for appending, compacting again on the next change: {err}", self.file.display()),
+        }
+        Ok(())
+    }
+
+    /// Persists a change which is already reflected in the cache by appending the given lines to the file,
+    /// which make `stale` lines obsolete. If the file contains too many stale lines afterward, or if it
+    /// must be compacted anyway, it is compacted instead. Returns an error if the file could not be written to.
+    async fn persist(&mut self, lines: String, stale: usize) -> io::Result<()> {
+        self.stale_lines += stale;
+        let compaction_due = self.stale_lines > self.cache.len().max(MIN_COMPACTION_LINES);
+        let Some(writer) = self.writer.as_mut().filter(|_| !compaction_due) else {
+            return self.rewrite().await;
+        };
+        let result = match writer.write_all(lines.as_bytes()).await {
+            Ok(()) => writer.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            error!("Failed to append to {}, compacting on the next change: {err}", self.file.display());
+            self.writer = None;
+        }
+        result
+    }
+
+    /// Removes the element at the given index from the cache and appends a tombstone for it.
+    /// If the file cannot be written to, the element is put back into the cache and an error is returned.
+    async fn remove(&mut self, index: usize) -> io::Result<T> {
+        let entry = self.cache.remove(index);
+        // Both the element's line and its tombstone are stale afterward
+        if let Err(err) = self.persist(Line::<T>::Removed { seq: entry.seq }.to_json_line(), 2).await {
+            self.cache.insert(index, entry);
+            return Err(err);
+        }
+        Ok(entry.item)
+    }
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for JsonlFileQueue<T> {
+    /// Removes and returns the first element of the queue, if there is one.
+    /// This operation appends a tombstone to the file.
+    /// If the file cannot be written to, the element is put back into the cache and an error is returned.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        if self.cache.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.remove(0).await?))
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// This operation appends a tombstone to the file if an element was removed.
+    /// If the file cannot be written to, the element is put back into the cache and an error is returned.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let Some(index) = self.cache.iter().position(|entry| matches(&entry.item)) else {
+            return Ok(None);
+        };
+        Ok(Some(self.remove(index).await?))
+    }
+
+    /// Returns a copy of the first element of the queue without removing it, if there is one.
+    /// This operation only reads from the cache, and never fails.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        Ok(self.cache.first().map(|entry| entry.item.clone()))
+    }
+
+    /// Returns the number of elements in the queue.
+    /// This operation only reads from the cache, and never fails.
+    async fn len(&self) -> QueueResult<usize> {
+        Ok(self.cache.len())
+    }
+
+    /// Returns copies of the first `limit` elements of the cache, or of all elements.
+    /// This operation never fails.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        Ok(self.cache.iter().take(limit.unwrap_or(usize::MAX)).map(|entry| entry.item.clone()).collect())
+    }
+
+    /// Returns the position of the first element of the cache which matches, if there is one.
+    /// This operation never fails.
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        Ok(self.cache.iter().position(|entry| matches(&entry.item)).map(|index| index + 1))
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// This operation appends the element to the file.
+    /// If the file cannot be written to, the element is removed from the cache again and an error is returned.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let index = insertion_index(self.cache.iter().map(|entry| &entry.item), item.priority());
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let line = Line::Item { seq, item: &item }.to_json_line();
+        self.cache.insert(index, Entry { seq, item });
+        if let Err(err) = self.persist(line, 0).await {
+            self.cache.remove(index);
+            return Err(err.into());
+        }
+        Ok(index + 1)
+    }
+
+    /// Inserts the elements according to their priorities, and returns their positions in the queue.
+    /// This operation appends all elements to the file at once.
+    /// If the file cannot be written to, the elements are removed from the cache again and an error is returned.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let mut inserted = self.cache.clone();
+        let mut indices = Vec::with_capacity(items.len());
+        let mut lines = String::new();
+        for item in items {
+            let index = insertion_index(inserted.iter().map(|entry| &entry.item), item.priority());
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            lines.push_str(&Line::Item { seq, item: &item }.to_json_line());
+            inserted.insert(index, Entry { seq, item });
+            record_insertion(&mut indices, index);
+        }
+        let previous = mem::replace(&mut self.cache, inserted);
+        if let Err(err) = self.persist(lines, 0).await {
+            self.cache = previous;
+            return Err(err.into());
+        }
+        Ok(indices.into_iter().map(|index| index + 1).collect())
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// This operation appends a tombstone to the file for every removed element.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let (retained, removed): (Vec<_>, Vec<_>) = self.cache.iter().cloned().partition(|entry| keep(&entry.item));
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let lines = removed.iter().map(|entry| Line::<T>::Removed { seq: entry.seq }.to_json_line()).collect();
+        let previous = mem::replace(&mut self.cache, retained);
+        if let Err(err) = self.persist(lines, 2 * removed.len()).await {
+            self.cache = previous;
+            return Err(err.into());
+        }
+        Ok(removed.len())
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// This operation appends every modified element to the file under its existing sequence number.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let mut updated = self.cache.clone();
+        let lines: Vec<String> = updated.iter_mut()
+            .filter_map(|entry| update(&mut entry.item).then(|| Line::Item { seq: entry.seq, item: &entry.item }.to_json_line()))
+            .collect();
+        if lines.is_empty() {
+            return Ok(0);
+        }
+        let previous = mem::replace(&mut self.cache, updated);
+        if let Err(err) = self.persist(lines.concat(), lines.len()).await {
+            self.cache = previous;
+            return Err(err.into());
+        }
+        Ok(lines.len())
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// This operation empties the file by compacting it.
+    /// If the file cannot be written to, the elements are put back into the cache and an error is returned.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let previous = mem::take(&mut self.cache);
+        if let Err(err) = self.rewrite().await {
+            self.cache = previous;
+            return Err(err.into());
+        }
+        Ok(previous.len())
+    }
+
+    /// Verifies that the file can be written to.
+    async fn check(&self) -> QueueResult<()> {
+        Ok(check_writable(&self.file).await?)
+    }
+
+    /// Compacts the file right away, regardless of how many stale lines it contains,
+    /// and returns the number of bytes by which it shrank.
+    async fn compact(&mut self) -> QueueResult<u64> {
+        let before = fs::metadata(&self.file).await.map_or(0, |metadata| metadata.len());
+        self.rewrite().await?;
+        let after = fs::metadata(&self.file).await?.len();
+        Ok(before.saturating_sub(after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{DEFAULT_READ_ATTEMPTS, Identifiable};
+    use super::super::tests::TestItem;
+    use super::*;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    /// Returns the number of lines of the given file.
+    fn line_count(file: &Path) -> usize {
+        std::fs::read_to_string(file).unwrap().lines().count()
+    }
+
+    #[tokio::test]
+    async fn replays_appended_updates_and_removals() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.jsonl");
+        let mut queue = JsonlFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        queue.enqueue_many(TestItem::many(1..=4)).await.unwrap();
+        queue.enqueue(TestItem { id: 5, priority: 1 }).await.unwrap();
+        assert_eq!(queue.dequeue().await.unwrap().map(|item| item.id), Some(5));
+        queue.update(&|item: &mut TestItem| (item.id == 3).then(|| item.id = 30).is_some()).await.unwrap();
+        queue.remove_by_id(TestItem { id: 2, priority: 0 }.id()).await.unwrap();
+        // 5 elements, 1 update and 2 tombstones, all appended
+        assert_eq!(line_count(&file), 8);
+
+        let expected = TestItem::many([1, 30, 4]);
+        assert_eq!(queue.to_vec(None).await.unwrap(), expected);
+        let reloaded = JsonlFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        assert_eq!(reloaded.to_vec(None).await.unwrap(), expected);
+        // Loading compacted the file
+        assert_eq!(line_count(&file), 3);
+    }
+
+    #[tokio::test]
+    async fn skips_partial_last_line() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.jsonl");
+        let mut queue = JsonlFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        queue.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+        drop(queue);
+        // A crash mid-append leaves a partial line without a trailing newline behind
+        let mut appender = std::fs::OpenOptions::new().append(true).open(&file).unwrap();
+        appender.write_all(br#"{"Item":{"seq":2,"item":{"id":3,"prio"#).unwrap();
+
+        let mut queue = JsonlFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(1..=2));
+        queue.enqueue(TestItem { id: 3, priority: 0 }).await.unwrap();
+        let reloaded = JsonlFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        assert_eq!(reloaded.to_vec(None).await.unwrap(), TestItem::many(1..=3));
+    }
+
+    #[tokio::test]
+    async fn compacts_once_stale_lines_exceed_threshold() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.jsonl");
+        let mut queue = JsonlFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        queue.enqueue(TestItem { id: 0, priority: 0 }).await.unwrap();
+        // Every round leaves the dequeued element's line and its tombstone behind as stale lines
+        let rounds = MIN_COMPACTION_LINES as u32 / 2;
+        for id in 1..=rounds {
+            queue.enqueue(TestItem { id, priority: 0 }).await.unwrap();
+            queue.dequeue().await.unwrap();
+        }
+        assert_eq!(line_count(&file), 1 + MIN_COMPACTION_LINES);
+
+        queue.enqueue(TestItem { id: rounds + 1, priority: 0 }).await.unwrap();
+        queue.dequeue().await.unwrap();
+        assert_eq!(line_count(&file), 1);
+        let reloaded = JsonlFileQueue::<TestItem>::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap();
+        assert_eq!(reloaded.to_vec(None).await.unwrap(), vec![TestItem { id: rounds + 1, priority: 0 }]);
+    }
+}
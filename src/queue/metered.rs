@@ -0,0 +1,167 @@
+use super::{Identifiable, Predicate, Queue, QueueBackend, QueueItem, QueueResult, Update};
+use async_trait::async_trait;
+use metrics::{counter, histogram, Label};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// The number of elements enqueued into a queue, labeled by queue and topic. Only recorded with `--queue-metrics`.
+pub const QUEUE_ENQUEUED: &str = "queue_enqueued_total";
+/// The number of elements dequeued from a queue, labeled by queue and topic. Only recorded with `--queue-metrics`.
+pub const QUEUE_DEQUEUED: &str = "queue_dequeued_total";
+/// The time an operation on a queue took, labeled by queue, topic and operation. Only recorded with `--queue-metrics`.
+pub const QUEUE_OPERATION_DURATION: &str = "queue_operation_duration_seconds";
+
+/// A wrapper around another queue which records Prometheus metrics about the operations on it:
+/// the number of enqueued and dequeued elements, and the time each modifying operation took,
+/// labeled with the name of the queue, its topic if it belongs to one, and, for the durations, the operation.
+/// Because it only sees the queue's interface, the metrics are the same for every backend.
+/// All operations are passed through to the wrapped queue.
+#[derive(Debug)]
+pub struct MeteredQueue<T> {
+    inner: Queue<T>,
+    labels: Vec<Label>,
+}
+
+impl<T> MeteredQueue<T> {
+    /// Wraps the given queue, labeling its metrics with the given name.
+    pub fn new(inner: Queue<T>, name: &'static str) -> Self {
+        Self { inner, labels: vec![Label::new("queue", name)] }
+    }
+
+    /// Labels the metrics with the given topic as well, so that the queues of different topics,
+    /// which share their names with the global queues, are told apart.
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.labels.push(Label::new("topic", topic.to_owned()));
+        self
+    }
+
+    /// Records the time an operation took since it started at `start`.
+    fn record_duration(&self, operation: &'static str, start: Instant) {
+        let labels: Vec<Label> = self.labels.iter().cloned().chain([Label::new("operation", operation)]).collect();
+        histogram!(QUEUE_OPERATION_DURATION, labels).record(start.elapsed().as_secs_f64());
+    }
+
+    /// Records that the given number of elements were enqueued.
+    fn record_enqueued(&self, count: usize) {
+        counter!(QUEUE_ENQUEUED, self.labels.iter()).increment(count as u64);
+    }
+
+    /// Records that an element was dequeued if the result contains one.
+    fn record_dequeued(&self, result: &QueueResult<Option<T>>) {
+        if let Ok(Some(_)) = result {
+            counter!(QUEUE_DEQUEUED, self.labels.iter()).increment(1);
+        }
+    }
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for MeteredQueue<T> {
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        let start = Instant::now();
+        let result = self.inner.dequeue().await;
+        self.record_duration("dequeue", start);
+        self.record_dequeued(&result);
+        result
+    }
+
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let start = Instant::now();
+        let result = self.inner.dequeue_matching(matches).await;
+        self.record_duration("dequeue_matching", start);
+        self.record_dequeued(&result);
+        result
+    }
+
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        self.inner.peek().await
+    }
+
+    async fn len(&self) -> QueueResult<usize> {
+        self.inner.len().await
+    }
+
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        self.inner.to_vec(limit).await
+    }
+
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        self.inner.position_of(matches).await
+    }
+
+    async fn find_by_id(&self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        self.inner.find_by_id(id).await
+    }
+
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let start = Instant::now();
+        let result = self.inner.enqueue(item).await;
+        self.record_duration("enqueue", start);
+        if result.is_ok() {
+            self.record_enqueued(1);
+        }
+        result
+    }
+
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let start = Instant::now();
+        let result = self.inner.enqueue_many(items).await;
+        self.record_duration("enqueue_many", start);
+        if let Ok(positions) = &result {
+            self.record_enqueued(positions.len());
+        }
+        result
+    }
+
+    async fn remove_by_id(&mut self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        let start = Instant::now();
+        let result = self.inner.remove_by_id(id).await;
+        self.record_duration("remove_by_id", start);
+        self.record_dequeued(&result);
+        result
+    }
+
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let start = Instant::now();
+        let result = self.inner.retain(keep).await;
+        self.record_duration("retain", start);
+        result
+    }
+
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let start = Instant::now();
+        let result = self.inner.update(update).await;
+        self.record_duration("update", start);
+        result
+    }
+
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let start = Instant::now();
+        let result = self.inner.clear().await;
+        self.record_duration("clear", start);
+        result
+    }
+
+    async fn check(&self) -> QueueResult<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&mut self) -> QueueResult<()> {
+        let start = Instant::now();
+        let result = self.inner.flush().await;
+        self.record_duration("flush", start);
+        result
+    }
+
+    async fn compact(&mut self) -> QueueResult<u64> {
+        let start = Instant::now();
+        let result = self.inner.compact().await;
+        self.record_duration("compact", start);
+        result
+    }
+}
@@ -1,38 +1,461 @@
 //! Implementations for queuing jobs and workers.
 
+mod bounded;
 mod in_memory;
 mod json_file;
+mod jsonl_file;
+mod metered;
+mod postgres;
+mod redis;
+mod sqlite;
 
+pub use bounded::BoundedQueue;
 pub use in_memory::InMemoryQueue;
 pub use json_file::CachedJsonFileQueue;
+pub use json_file::DEFAULT_READ_ATTEMPTS;
+pub use json_file::FileFormat;
 pub use json_file::JsonFileQueue;
+pub use jsonl_file::JsonlFileQueue;
+pub use metered::{MeteredQueue, QUEUE_DEQUEUED, QUEUE_ENQUEUED, QUEUE_OPERATION_DURATION};
+pub use postgres::{postgres_tables, PostgresQueue};
+pub use redis::{redis_keys, RedisQueue};
+pub use sqlite::{sqlite_tables, SqliteQueue};
 
-/// A queue that is backed by one of the available implementations.
-#[derive(Debug, derive_more::From)]
-pub enum Queue<T> {
-    InMemory(InMemoryQueue<T>),
-    JsonFile(JsonFileQueue<T>),
-    CachedJsonFile(CachedJsonFileQueue<T>),
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use derive_more::Display;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::cmp::Reverse;
+use std::error::Error;
+use std::fmt::Debug;
+use std::io;
+use uuid::Uuid;
+
+/// The bounds an element must satisfy to be stored in any of the queue implementations.
+pub trait QueueItem: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// The priority of this element.
+    /// Elements with a higher priority are dequeued before elements with a lower priority;
+    /// elements with the same priority are dequeued in the order in which they were enqueued.
+    fn priority(&self) -> u8 {
+        0
+    }
 }
 
-impl<T> Queue<T>
-where
-    T: serde::Serialize + for<'de> serde::Deserialize<'de>,
-{
-    /// Removes and returns the first element of the queue, if there is one.
-    pub async fn dequeue(&mut self) -> Option<T> {
+/// An element with a stable id, by which it can be found in and removed from a queue
+/// with [`find_by_id`](QueueBackend::find_by_id) and [`remove_by_id`](QueueBackend::remove_by_id).
+pub trait Identifiable: QueueItem {
+    /// The id of this element, which stays the same while the element is queued.
+    fn id(&self) -> Uuid;
+}
+
+/// Returns the index at which an element with the given priority must be inserted into a queue
+/// with the given elements: behind every element with the same or a higher priority.
+fn insertion_index<'a, T: QueueItem>(queue: impl IntoIterator<Item = &'a T>, priority: u8) -> usize {
+    let mut index = 0;
+    for queued in queue {
+        if queued.priority() < priority {
+            break;
+        }
+        index += 1;
+    }
+    index
+}
+
+/// Records that an element was inserted at the given index into a queue into which the elements at the
+/// recorded `indices` were inserted before, shifting the indices of the elements behind it.
+fn record_insertion(indices: &mut Vec<usize>, index: usize) {
+    for recorded in indices.iter_mut().filter(|recorded| **recorded >= index) {
+        *recorded += 1;
+    }
+    indices.push(index);
+}
+
+/// Merges the elements into a queue with the given elements as if they were inserted one after another in the given order,
+/// each at its [`insertion_index`], but in a single pass: the elements are sorted by priority, keeping their order
+/// within each priority, and then merged with the queue. This takes O(n + m log m) time for a queue of n elements
+/// and m new elements, instead of the O(m * (n + m)) of inserting them one by one, see the `queues` benchmark.
+/// Returns the merged queue and the 1-based positions of the elements in it, in the given order of the elements.
+fn merge_by_priority<T: QueueItem>(queue: impl IntoIterator<Item = T>, items: Vec<T>) -> (Vec<T>, Vec<usize>) {
+    let mut queue = queue.into_iter().peekable();
+    let mut merged = Vec::with_capacity(queue.size_hint().0 + items.len());
+    let mut positions = vec![0; items.len()];
+    let mut items: Vec<(usize, T)> = items.into_iter().enumerate().collect();
+    // The sort is stable, so elements with the same priority keep their order
+    items.sort_by_key(|(_, item)| Reverse(item.priority()));
+    for (index, item) in items {
+        let priority = item.priority();
+        while let Some(queued) = queue.next_if(|queued| queued.priority() >= priority) {
+            merged.push(queued);
+        }
+        merged.push(item);
+        positions[index] = merged.len();
+    }
+    merged.extend(queue);
+    (merged, positions)
+}
+
+/// Why an operation on a queue failed.
+#[derive(Debug, Display)]
+pub enum QueueError {
+    /// The queue's file could not be read or written.
+    #[display("I/O error: {_0}")]
+    Io(io::Error),
+    /// An element could not be serialized, or the stored data could not be deserialized.
+    #[display("serialization error: {_0}")]
+    Serialization(String),
+    /// The queue's database or server could not be reached, or failed to execute the operation.
+    #[display("backend unavailable: {_0}")]
+    Unavailable(String),
+    /// The queue is bounded and cannot hold the elements. See [`BoundedQueue`].
+    #[display("queue is full")]
+    Full,
+}
+
+impl QueueError {
+    /// Creates a Serialization error from the given serialization or deserialization error.
+    pub fn serialization(err: impl std::fmt::Display) -> Self {
+        Self::Serialization(err.to_string())
+    }
+
+    /// Creates an Unavailable error from the given database or server error.
+    pub fn unavailable(err: impl std::fmt::Display) -> Self {
+        Self::Unavailable(err.to_string())
+    }
+
+    /// Returns the status with which a handler responds when a queue operation fails with this error:
+    /// 503 Service Unavailable if the backend cannot be reached, 429 Too Many Requests if the queue is full,
+    /// and 500 Internal Server Error otherwise.
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            Self::InMemory(queue) => queue.dequeue(),
-            Self::JsonFile(queue) => queue.dequeue().await,
-            Self::CachedJsonFile(queue) => queue.dequeue().await,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Full => StatusCode::TOO_MANY_REQUESTS,
+            Self::Io(_) | Self::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
-    /// Appends an element to the end of the queue, and returns the new length of the queue.
-    pub async fn enqueue(&mut self, t: T) -> usize {
+}
+
+impl Error for QueueError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            Self::InMemory(queue) => queue.enqueue(t),
-            Self::JsonFile(queue) => queue.enqueue(t).await,
-            Self::CachedJsonFile(queue) => queue.enqueue(t).await,
+            Self::Io(err) => Some(err),
+            _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<io::Error> for QueueError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// The result of an operation on a queue.
+pub type QueueResult<T> = Result<T, QueueError>;
+
+/// A condition on an element of a queue.
+pub type Predicate<'a, T> = dyn Fn(&T) -> bool + Send + Sync + 'a;
+
+/// A modification of an element of a queue, which returns whether the element was modified.
+pub type Update<'a, T> = dyn Fn(&mut T) -> bool + Send + Sync + 'a;
+
+/// The operations supported by every queue implementation.
+/// Implement this trait to make a new kind of queue available to the handlers.
+#[async_trait]
+pub trait QueueBackend<T>: Debug + Send + Sync {
+    /// Removes and returns the first element of the queue, if there is one.
+    /// Returns an error if the change could not be persisted.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>>;
+
+    /// Removes and returns the first element of the queue for which `matches` returns true, if there is one.
+    /// The order of the remaining elements does not change.
+    /// Returns an error if the change could not be persisted.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>>;
+
+    /// Returns a copy of the first element of the queue without removing it, if there is one.
+    /// Returns an error if the queue could not be read.
+    async fn peek(&self) -> QueueResult<Option<T>>;
+
+    /// Returns the number of elements in the queue.
+    /// Returns an error if the queue could not be read.
+    async fn len(&self) -> QueueResult<usize>;
+
+    /// Returns copies of the elements of the queue in the order in which they would be dequeued,
+    /// without removing them. At most `limit` elements are returned, if given.
+    /// Returns an error if the queue could not be read.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>>;
+
+    /// Returns the 1-based position of the first element for which `matches` returns true, if there is one.
+    /// Returns an error if the queue could not be read.
+    /// The default implementation scans a copy of the queue; implementations which can scan it in place should override this.
+    async fn position_of(&self, matches: &Predicate<'_, T>) -> QueueResult<Option<usize>> {
+        Ok(self.to_vec(None).await?.iter().position(matches).map(|index| index + 1))
+    }
+
+    /// Returns a copy of the first element with the given id, if there is one.
+    /// Returns an error if the queue could not be read.
+    /// The default implementation scans a copy of the queue; implementations which can look the id up should override this.
+    async fn find_by_id(&self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        Ok(self.to_vec(None).await?.into_iter().find(|item| item.id() == id))
+    }
+
+    /// Returns true if the queue contains no elements.
+    /// Returns an error if the queue could not be read.
+    async fn is_empty(&self) -> QueueResult<bool> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// Inserts an element behind all elements with the same or a higher priority,
+    /// and returns its 1-based position in the queue.
+    /// Returns an error if the change could not be persisted.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize>;
+
+    /// Inserts several elements like [`enqueue`](Self::enqueue) in the given order, but persists the change only once.
+    /// Returns the 1-based positions of the elements in the queue once all of them were inserted.
+    /// Returns an error if the change could not be persisted, in which case none of the elements were inserted.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>>;
+
+    /// Removes and returns the first element with the given id, if there is one.
+    /// The order of the remaining elements does not change.
+    /// Returns an error if the change could not be persisted.
+    async fn remove_by_id(&mut self, id: Uuid) -> QueueResult<Option<T>>
+    where
+        T: Identifiable,
+    {
+        self.dequeue_matching(&|item: &T| item.id() == id).await
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// Returns an error if the change could not be persisted.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize>;
+
+    /// Calls `update` on every element, which returns whether it modified the element,
+    /// and returns the number of modified elements. The positions of the elements do not change.
+    /// Returns an error if the change could not be persisted.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize>;
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// Returns an error if the change could not be persisted.
+    async fn clear(&mut self) -> QueueResult<usize>;
+
+    /// Verifies that the queue's storage is currently usable, e.g. that its file is writable
+    /// or that its server responds. Returns the error encountered otherwise.
+    async fn check(&self) -> QueueResult<()>;
+
+    /// Writes any changes which have not been persisted yet.
+    /// Implementations which persist every operation immediately do not need to override this.
+    async fn flush(&mut self) -> QueueResult<()> {
+        Ok(())
+    }
+
+    /// Rewrites the queue's storage so that it only holds the current elements, discarding the data left behind
+    /// by removed and updated elements, and returns the number of bytes reclaimed.
+    /// Returns an error if the storage could not be rewritten.
+    /// Implementations whose storage never holds such data, e.g. because they rewrite it on every change, do not need to override this.
+    async fn compact(&mut self) -> QueueResult<u64> {
+        Ok(0)
+    }
+}
+
+/// A queue that is backed by one of the available implementations.
+pub type Queue<T> = Box<dyn QueueBackend<T>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::path::{Path, PathBuf};
+    use tempfile::TempDir;
+
+    /// An element of the queues under test, with a numeric id to compare the order of elements by.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub(super) struct TestItem {
+        pub(super) id: u32,
+        pub(super) priority: u8,
+    }
+
+    impl TestItem {
+        /// Returns elements with the given ids and the lowest priority.
+        pub(super) fn many(ids: impl IntoIterator<Item = u32>) -> Vec<Self> {
+            ids.into_iter().map(|id| Self { id, priority: 0 }).collect()
+        }
+    }
+
+    impl QueueItem for TestItem {
+        fn priority(&self) -> u8 {
+            self.priority
+        }
+    }
+
+    impl Identifiable for TestItem {
+        fn id(&self) -> Uuid {
+            Uuid::from_u128(self.id.into())
+        }
+    }
+
+    /// Returns an empty queue of every implementation which does not need a server, named after the implementation,
+    /// along with the file it is stored in, if any. The files are created in the given directory.
+    async fn backends(dir: &Path) -> Vec<(&'static str, Queue<TestItem>, Option<PathBuf>)> {
+        let json = dir.join("jobs.json");
+        let cached = dir.join("cached.json");
+        let jsonl = dir.join("jobs.jsonl");
+        let sqlite = dir.join("queues.sqlite");
+        vec![
+            ("InMemory", Box::new(InMemoryQueue::new()), None),
+            ("JsonFile", Box::new(JsonFileQueue::new(&json)), Some(json)),
+            ("CachedJsonFile", Box::new(CachedJsonFileQueue::new(&cached, DEFAULT_READ_ATTEMPTS).await.unwrap()), Some(cached)),
+            ("JsonlFile", Box::new(JsonlFileQueue::new(&jsonl, DEFAULT_READ_ATTEMPTS).await.unwrap()), Some(jsonl)),
+            ("Sqlite", Box::new(SqliteQueue::new(&sqlite, "jobs").await.unwrap()), Some(sqlite)),
+        ]
+    }
+
+    /// Returns the ids of the elements of the queue, in order.
+    pub(super) async fn ids(queue: &Queue<TestItem>) -> Vec<u32> {
+        queue.to_vec(None).await.unwrap().iter().map(|item| item.id).collect()
+    }
+
+    /// Checks the operations which every implementation must support alike on the given empty queue:
+    /// ordering by priority and then by submission, the reported positions, and the operations on matching elements.
+    pub(super) async fn check_operations(name: &str, queue: &mut Queue<TestItem>) {
+        assert_eq!(queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap(), 1, "{name}");
+        assert_eq!(queue.enqueue(TestItem { id: 2, priority: 5 }).await.unwrap(), 1, "{name}");
+        assert_eq!(queue.enqueue(TestItem { id: 3, priority: 0 }).await.unwrap(), 3, "{name}");
+        assert_eq!(ids(queue).await, [2, 1, 3], "{name}");
+        assert_eq!(queue.to_vec(Some(2)).await.unwrap().len(), 2, "{name}");
+        assert_eq!(queue.len().await.unwrap(), 3, "{name}");
+        assert_eq!(queue.position_of(&|item: &TestItem| item.id == 3).await.unwrap(), Some(3), "{name}");
+
+        assert_eq!(queue.dequeue_matching(&|item: &TestItem| item.id == 3).await.unwrap().map(|item| item.id), Some(3), "{name}");
+        assert_eq!(queue.dequeue_matching(&|item: &TestItem| item.id == 9).await.unwrap(), None, "{name}");
+        assert_eq!(queue.update(&|item: &mut TestItem| {
+            if item.id != 1 {
+                return false;
+            }
+            item.id = 10;
+            true
+        }).await.unwrap(), 1, "{name}");
+        assert_eq!(ids(queue).await, [2, 10], "{name}");
+        assert_eq!(queue.remove_by_id(Uuid::from_u128(10)).await.unwrap().map(|item| item.id), Some(10), "{name}");
+        assert_eq!(queue.enqueue_many(TestItem::many(4..=5)).await.unwrap(), [2, 3], "{name}");
+        assert_eq!(queue.retain(&|item: &TestItem| item.id != 4).await.unwrap(), 1, "{name}");
+        assert_eq!(ids(queue).await, [2, 5], "{name}");
+
+        assert_eq!(queue.clear().await.unwrap(), 2, "{name}");
+        assert!(queue.is_empty().await.unwrap(), "{name}");
+        assert_eq!(queue.dequeue().await.unwrap(), None, "{name}");
+        queue.check().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_backend_supports_the_queue_operations() {
+        let dir = TempDir::new().unwrap();
+        for (name, mut queue, _) in backends(dir.path()).await {
+            check_operations(name, &mut queue).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_change_the_queue() {
+        let dir = TempDir::new().unwrap();
+        for (name, mut queue, file) in backends(dir.path()).await {
+            queue.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+            let contents = file.as_ref().map(|file| std::fs::read(file).unwrap());
+            for _ in 0..2 {
+                assert_eq!(queue.peek().await.unwrap(), Some(TestItem { id: 1, priority: 0 }), "{name}");
+            }
+            assert_eq!(file.as_ref().map(|file| std::fs::read(file).unwrap()), contents, "{name} changed its file");
+            assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(1..=2), "{name}");
+            assert_eq!(queue.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }), "{name}");
+            assert_eq!(queue.peek().await.unwrap(), Some(TestItem { id: 2, priority: 0 }), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn peek_on_an_empty_queue_returns_none() {
+        let dir = TempDir::new().unwrap();
+        for (name, queue, _) in backends(dir.path()).await {
+            assert_eq!(queue.peek().await.unwrap(), None, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn dequeues_in_submission_order() {
+        let dir = TempDir::new().unwrap();
+        for (name, mut queue, _) in backends(dir.path()).await {
+            for (index, item) in TestItem::many(1..=3).into_iter().enumerate() {
+                assert_eq!(queue.enqueue(item).await.unwrap(), index + 1, "{name}");
+            }
+            for id in 1..=3 {
+                assert_eq!(queue.dequeue().await.unwrap(), Some(TestItem { id, priority: 0 }), "{name}");
+            }
+            assert_eq!(queue.dequeue().await.unwrap(), None, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn io_errors_propagate() {
+        let dir = TempDir::new().unwrap();
+        let queues_dir = dir.path().join("queues");
+        std::fs::create_dir(&queues_dir).unwrap();
+        // The Sqlite queue keeps its database open, so removing its file does not make it fail
+        let mut queues: Vec<_> = backends(&queues_dir).await
+            .into_iter()
+            .filter(|(name, _, file)| file.is_some() && *name != "Sqlite")
+            .collect();
+        for (_, queue, _) in &mut queues {
+            queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap();
+        }
+        std::fs::remove_dir_all(&queues_dir).unwrap();
+
+        for (name, mut queue, _) in queues {
+            assert!(matches!(queue.check().await, Err(QueueError::Io(_))), "{name}");
+            match queue.clear().await {
+                Err(err @ QueueError::Io(_)) => {
+                    assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR, "{name}");
+                    assert!(err.source().is_some(), "{name}");
+                }
+                result => panic!("{name}: unexpected result {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn errors_map_to_statuses() {
+        let io = QueueError::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(matches!(io, QueueError::Io(_)));
+        assert_eq!(io.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(QueueError::serialization("bad").status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(QueueError::unavailable("down").status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(QueueError::Full.status_code(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(QueueError::unavailable("down").to_string(), "backend unavailable: down");
+    }
+
+    #[tokio::test]
+    async fn flush_writes_pending_changes_through_wrappers() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("jobs.json");
+        let on_disk = || async { JsonFileQueue::<TestItem>::new(&file).to_vec(None).await.unwrap() };
+        let cached = CachedJsonFileQueue::new(&file, DEFAULT_READ_ATTEMPTS).await.unwrap()
+            .with_write_debounce(std::time::Duration::from_secs(3600));
+        let mut queue: Queue<TestItem> = Box::new(MeteredQueue::new(Box::new(BoundedQueue::new(Box::new(cached), 10)), "jobs"));
+        for item in TestItem::many(1..=3) {
+            queue.enqueue(item).await.unwrap();
+        }
+        assert_eq!(on_disk().await, vec![]);
+        queue.flush().await.unwrap();
+        assert_eq!(on_disk().await, TestItem::many(1..=3));
+    }
+
+    #[tokio::test]
+    async fn flush_keeps_every_queue_as_it_is() {
+        let dir = TempDir::new().unwrap();
+        for (name, mut queue, _) in backends(dir.path()).await {
+            queue.enqueue_many(TestItem::many(1..=3)).await.unwrap();
+            queue.flush().await.unwrap();
+            assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many(1..=3), "{name}");
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use super::{Predicate, QueueBackend, QueueError, QueueItem, QueueResult, Update};
+use async_trait::async_trait;
+use sqlx::postgres::PgPool;
+use std::marker::PhantomData;
+use tracing::error;
+
+/// A queue backed by a table in a PostgreSQL database.
+/// Each element is stored as a row containing its priority and its JSON serialization as JSONB,
+/// ordered by priority and then by a sequence number.
+/// Because the queue lives outside the process, several service instances can share it:
+/// dequeueing skips rows which are locked by another instance, so no element is ever dequeued twice.
+#[derive(Debug)]
+pub struct PostgresQueue<T> {
+    pool: PgPool,
+    table: String,
+    _phantom: PhantomData<T>, // This field is needed to keep the type parameter T alive
+}
+
+impl<T> PostgresQueue<T>
+where
+    T: QueueItem,
+{
+    /// Creates a new PostgresQueue storing its elements in the given table of the database at the given URL.
+    /// The table and its index are created if they do not exist yet. The creation is guarded by an advisory lock,
+    /// so that several instances starting at the same time do not race to create the same table.
+    pub async fn new(url: &str, table: impl Into<String>) -> Result<Self, sqlx::Error> {
+        let pool = PgPool::connect(url).await?;
+        let table = table.into();
+        let mut transaction = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind(&table)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{table}\" (seq BIGSERIAL PRIMARY KEY, priority SMALLINT NOT NULL, payload JSONB NOT NULL)"
+        ))
+        .execute(&mut *transaction)
+        .await?;
+        sqlx::query(&format!("CREATE INDEX IF NOT EXISTS \"{table}_order\" ON \"{table}\" (priority DESC, seq)"))
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(Self {
+            pool,
+            table,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+/// Returns the names of the tables in the current schema of the database at the given URL which start with the given prefix,
+/// e.g. to find the queues which were created before a restart.
+pub async fn postgres_tables(url: &str, prefix: &str) -> Result<Vec<String>, sqlx::Error> {
+    let pool = PgPool::connect(url).await?;
+    let tables: Vec<String> = sqlx::query_scalar("SELECT table_name::text FROM information_schema.tables WHERE table_schema = current_schema()")
+        .fetch_all(&pool)
+        .await?;
+    pool.close().await;
+    Ok(tables.into_iter().filter(|table| table.starts_with(prefix)).collect())
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for PostgresQueue<T> {
+    /// Removes and returns the first element of the queue, if there is one.
+    /// The row is selected with `FOR UPDATE SKIP LOCKED` and deleted in a single statement,
+    /// so concurrent dispatchers pull different rows without waiting for each other.
+    /// Rows which cannot be deserialized into a `T` are deleted and skipped.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        loop {
+            let payload: Option<String> = sqlx::query_scalar(&format!(
+                "DELETE FROM \"{table}\" WHERE seq = (SELECT seq FROM \"{table}\" ORDER BY priority DESC, seq LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING payload::text"
+            ))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?;
+            let Some(payload) = payload else {
+                return Ok(None);
+            };
+            match serde_json::from_str(&payload) {
+                Ok(item) => return Ok(Some(item)),
+                Err(err) => error!("Skipping malformed row in PostgreSQL table {table}: {err}"),
+            }
+        }
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// Rows which are locked by another dispatcher are skipped. If a matching row is dequeued concurrently,
+    /// the next matching row is tried. Rows which cannot be deserialized into a `T` are skipped.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!(
+            "SELECT seq, payload::text FROM \"{table}\" ORDER BY priority DESC, seq"
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        for (seq, payload) in rows {
+            let Ok(item) = serde_json::from_str::<T>(&payload) else {
+                continue;
+            };
+            if !matches(&item) {
+                continue;
+            }
+            let deleted = sqlx::query(&format!(
+                "DELETE FROM \"{table}\" WHERE seq = (SELECT seq FROM \"{table}\" WHERE seq = $1 FOR UPDATE SKIP LOCKED)"
+            ))
+            .bind(seq)
+            .execute(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?
+            .rows_affected();
+            if deleted > 0 {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the first element of the queue without removing it, if there is one.
+    /// Returns an error if the database cannot be read or the row cannot be deserialized into a `T`.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        let payload = sqlx::query_scalar::<_, String>(&format!("SELECT payload::text FROM \"{table}\" ORDER BY priority DESC, seq LIMIT 1"))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?;
+        payload.map(|payload| serde_json::from_str(&payload).map_err(QueueError::serialization)).transpose()
+    }
+
+    /// Returns the number of elements in the queue.
+    /// Returns an error if the database cannot be read.
+    async fn len(&self) -> QueueResult<usize> {
+        let table = &self.table;
+        sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM \"{table}\""))
+            .fetch_one(&self.pool)
+            .await
+            .map(|count| count as usize)
+            .map_err(QueueError::unavailable)
+    }
+
+    /// Returns the first `limit` elements of the queue, or all elements.
+    /// Rows which cannot be deserialized into a `T` are skipped.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        let table = &self.table;
+        // A NULL limit means no limit in PostgreSQL
+        let limit = limit.and_then(|limit| i64::try_from(limit).ok());
+        let payloads: Vec<String> = sqlx::query_scalar(&format!(
+            "SELECT payload::text FROM \"{table}\" ORDER BY priority DESC, seq LIMIT $1"
+        ))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        Ok(payloads.iter().filter_map(|payload| serde_json::from_str(payload).ok()).collect())
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let table = &self.table;
+        let priority = i16::from(item.priority());
+        let seq: i64 = sqlx::query_scalar(&format!(
+            "INSERT INTO \"{table}\" (priority, payload) VALUES ($1, $2::jsonb) RETURNING seq"
+        ))
+        .bind(priority)
+        .bind(serde_json::to_string(&item).unwrap())
+        .fetch_one(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        let position: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM \"{table}\" WHERE priority > $1 OR (priority = $1 AND seq <= $2)"
+        ))
+        .bind(priority)
+        .bind(seq)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        Ok(position as usize)
+    }
+
+    /// Inserts the elements according to their priorities in a single transaction, and returns their positions in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let mut inserted = Vec::with_capacity(items.len());
+        for item in &items {
+            let priority = i16::from(item.priority());
+            let seq: i64 = sqlx::query_scalar(&format!(
+                "INSERT INTO \"{table}\" (priority, payload) VALUES ($1, $2::jsonb) RETURNING seq"
+            ))
+            .bind(priority)
+            .bind(serde_json::to_string(item).unwrap())
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+            inserted.push((priority, seq));
+        }
+        let mut positions = Vec::with_capacity(inserted.len());
+        for (priority, seq) in inserted {
+            let position: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM \"{table}\" WHERE priority > $1 OR (priority = $1 AND seq <= $2)"
+            ))
+            .bind(priority)
+            .bind(seq)
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+            positions.push(position as usize);
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// The rows are locked until the removal is committed. Rows which cannot be deserialized into a `T` are kept.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!("SELECT seq, payload::text FROM \"{table}\" FOR UPDATE"))
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+        let removed: Vec<i64> = rows
+            .into_iter()
+            .filter(|(_, payload)| serde_json::from_str(payload).is_ok_and(|item| !keep(&item)))
+            .map(|(seq, _)| seq)
+            .collect();
+        if !removed.is_empty() {
+            sqlx::query(&format!("DELETE FROM \"{table}\" WHERE seq = ANY($1)"))
+                .bind(&removed)
+                .execute(&mut *transaction)
+                .await
+                .map_err(QueueError::unavailable)?;
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(removed.len())
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// The rows are locked until the changes are committed. Rows which cannot be deserialized into a `T` are left untouched.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!("SELECT seq, payload::text FROM \"{table}\" FOR UPDATE"))
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+        let mut updated = 0;
+        for (seq, payload) in rows {
+            let Ok(mut item) = serde_json::from_str::<T>(&payload) else {
+                continue;
+            };
+            if update(&mut item) {
+                sqlx::query(&format!("UPDATE \"{table}\" SET priority = $1, payload = $2::jsonb WHERE seq = $3"))
+                    .bind(i16::from(item.priority()))
+                    .bind(serde_json::to_string(&item).unwrap())
+                    .bind(seq)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(QueueError::unavailable)?;
+                updated += 1;
+            }
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(updated)
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let table = &self.table;
+        sqlx::query(&format!("DELETE FROM \"{table}\""))
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() as usize)
+            .map_err(QueueError::unavailable)
+    }
+
+    /// Verifies that the database responds to a query.
+    async fn check(&self) -> QueueResult<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await.map(|_| ()).map_err(QueueError::unavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{check_operations, ids, TestItem};
+    use super::super::Queue;
+    use super::*;
+    use uuid::Uuid;
+
+    /// Returns the URL of the database the ignored tests run against, e.g. `postgres://postgres@127.0.0.1/postgres`.
+    fn test_url() -> String {
+        std::env::var("TEST_POSTGRES_URL").expect("TEST_POSTGRES_URL must be set to run the PostgreSQL tests")
+    }
+
+    /// Returns a table name which no other test uses.
+    fn unique_table() -> String {
+        format!("test_jobs_{}", Uuid::new_v4().simple())
+    }
+
+    /// Drops the given table of the test database.
+    async fn drop_table(url: &str, table: &str) {
+        let pool = PgPool::connect(url).await.unwrap();
+        sqlx::query(&format!("DROP TABLE IF EXISTS \"{table}\"")).execute(&pool).await.unwrap();
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database at TEST_POSTGRES_URL"]
+    async fn supports_the_queue_operations() {
+        let (url, table) = (test_url(), unique_table());
+        let mut queue: Queue<TestItem> = Box::new(PostgresQueue::new(&url, &table).await.unwrap());
+        check_operations("Postgres", &mut queue).await;
+        drop_table(&url, &table).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database at TEST_POSTGRES_URL"]
+    async fn instances_share_the_table() {
+        let (url, table) = (test_url(), unique_table());
+        // Instances starting at the same time create the table only once
+        let (first, second) = tokio::join!(PostgresQueue::new(&url, &table), PostgresQueue::new(&url, &table));
+        let (mut first, mut second): (Queue<TestItem>, Queue<TestItem>) = (Box::new(first.unwrap()), Box::new(second.unwrap()));
+        first.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+        assert_eq!(second.enqueue(TestItem { id: 3, priority: 9 }).await.unwrap(), 1);
+
+        assert_eq!(ids(&first).await, [3, 1, 2]);
+        assert_eq!(second.dequeue().await.unwrap(), Some(TestItem { id: 3, priority: 9 }));
+        assert_eq!(first.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(postgres_tables(&url, &table).await.unwrap(), vec![table.clone()]);
+        drop_table(&url, &table).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database at TEST_POSTGRES_URL"]
+    async fn concurrent_dequeues_never_return_the_same_element() {
+        let (url, table) = (test_url(), unique_table());
+        let mut queue = PostgresQueue::new(&url, &table).await.unwrap();
+        queue.enqueue_many(TestItem::many(1..=40)).await.unwrap();
+
+        // Each task opens its own pool, like service instances sharing the database
+        let mut tasks = Vec::new();
+        for matching in [false, false, true, true] {
+            let (url, table) = (url.clone(), table.clone());
+            tasks.push(tokio::spawn(async move {
+                let mut queue = PostgresQueue::<TestItem>::new(&url, &table).await.unwrap();
+                let mut dequeued = Vec::new();
+                loop {
+                    let item = if matching {
+                        queue.dequeue_matching(&|_| true).await.unwrap()
+                    } else {
+                        queue.dequeue().await.unwrap()
+                    };
+                    let Some(item) = item else {
+                        break dequeued;
+                    };
+                    dequeued.push(item.id);
+                }
+            }));
+        }
+        let mut dequeued = Vec::new();
+        for task in tasks {
+            dequeued.extend(task.await.unwrap());
+        }
+        dequeued.sort();
+        assert_eq!(dequeued, (1..=40).collect::<Vec<_>>());
+        drop_table(&url, &table).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a PostgreSQL database at TEST_POSTGRES_URL"]
+    async fn malformed_rows_are_skipped() {
+        let (url, table) = (test_url(), unique_table());
+        let mut queue = PostgresQueue::new(&url, &table).await.unwrap();
+        sqlx::query(&format!("INSERT INTO \"{table}\" (priority, payload) VALUES (9, '{{\"name\": \"Margarita\"}}')"))
+            .execute(&queue.pool)
+            .await
+            .unwrap();
+        queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap();
+
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many([1]));
+        assert!(matches!(queue.peek().await, Err(QueueError::Serialization(_))));
+        assert_eq!(queue.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(queue.len().await.unwrap(), 0);
+        drop_table(&url, &table).await;
+    }
+}
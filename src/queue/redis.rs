@@ -0,0 +1,295 @@
+use super::{Predicate, QueueBackend, QueueError, QueueItem, QueueResult, Update};
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+use std::marker::PhantomData;
+use tracing::error;
+
+/// A queue backed by a sorted set in a Redis server.
+/// Each element is stored as its JSON serialization, prefixed with a sequence number to keep it unique.
+/// The score of an element combines its priority and its sequence number, so that `ZPOPMIN`
+/// atomically removes the element with the highest priority which was enqueued first.
+/// Because the queue lives outside the process, several service instances can share it.
+pub struct RedisQueue<T> {
+    connection: ConnectionManager,
+    key: String,
+    _phantom: PhantomData<T>, // This field is needed to keep the type parameter T alive
+}
+
+// ConnectionManager does not implement Debug, so we implement it manually.
+impl<T> std::fmt::Debug for RedisQueue<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisQueue").field("key", &self.key).finish_non_exhaustive()
+    }
+}
+
+impl<T> RedisQueue<T>
+where
+    T: QueueItem,
+{
+    /// Creates a new RedisQueue storing its elements in the sorted set at the given key
+    /// on the Redis server at the given URL. The sequence number is stored at `<key>:seq`.
+    pub async fn new(url: &str, key: impl Into<String>) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(Self {
+            connection,
+            key: key.into(),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+/// Returns the keys of the sorted sets on the Redis server at the given URL which start with the given prefix,
+/// e.g. to find the queues which were created before a restart. The sequence numbers stored next to them are skipped.
+pub async fn redis_keys(url: &str, prefix: &str) -> redis::RedisResult<Vec<String>> {
+    let mut connection = redis::Client::open(url)?.get_multiplexed_async_connection().await?;
+    let mut keys = Vec::new();
+    let mut iter = connection.scan_match::<_, String>(format!("{prefix}*")).await?;
+    while let Some(key) = iter.next_item().await {
+        if !key.contains(':') {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Computes the score of an element in the sorted set.
+/// Higher priorities map to lower scores, and within a priority, lower sequence numbers map to lower scores.
+/// The result is exact as long as the sequence number stays below 2^32.
+fn score(priority: u8, seq: u64) -> f64 {
+    f64::from(u8::MAX - priority) * 2f64.powi(32) + seq as f64
+}
+
+/// Strips the sequence number prefix from a member of the sorted set and deserializes the rest.
+fn parse_member<T: QueueItem>(member: &str) -> serde_json::Result<T> {
+    let payload = member.split_once(':').map_or(member, |(_, payload)| payload);
+    serde_json::from_str(payload)
+}
+
+/// Returns the sequence number prefix of a member of the sorted set.
+fn member_seq(member: &str) -> Option<u64> {
+    member.split_once(':')?.0.parse().ok()
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for RedisQueue<T> {
+    /// Removes and returns the first element of the queue, if there is one.
+    /// Elements which cannot be deserialized into a `T` are removed and skipped.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        loop {
+            let popped: Vec<(String, f64)> = self.connection.zpopmin(&self.key, 1).await.map_err(QueueError::unavailable)?;
+            let Some((member, _)) = popped.into_iter().next() else {
+                return Ok(None);
+            };
+            match parse_member(&member) {
+                Ok(item) => return Ok(Some(item)),
+                Err(err) => error!("Skipping malformed element in Redis sorted set {}: {err}", self.key),
+            }
+        }
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// If a matching element is dequeued concurrently, the next matching element is tried.
+    /// Elements which cannot be deserialized into a `T` are skipped.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let members: Vec<String> = self.connection.zrange(&self.key, 0, -1).await.map_err(QueueError::unavailable)?;
+        for member in members {
+            let Ok(item) = parse_member::<T>(&member) else {
+                continue;
+            };
+            if !matches(&item) {
+                continue;
+            }
+            let removed: usize = self.connection.zrem(&self.key, &member).await.map_err(QueueError::unavailable)?;
+            if removed > 0 {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the first element of the queue without removing it, if there is one.
+    /// Returns an error if the server cannot be reached or the element cannot be deserialized into a `T`.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        let members: Vec<String> = self.connection.clone().zrange(&self.key, 0, 0).await.map_err(QueueError::unavailable)?;
+        members.first().map(|member| parse_member(member).map_err(QueueError::serialization)).transpose()
+    }
+
+    /// Returns the number of elements in the queue.
+    /// Returns an error if the server cannot be reached.
+    async fn len(&self) -> QueueResult<usize> {
+        self.connection.clone().zcard(&self.key).await.map_err(QueueError::unavailable)
+    }
+
+    /// Returns the first `limit` elements of the queue, or all elements.
+    /// Elements which cannot be deserialized into a `T` are skipped.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        let stop = match limit {
+            Some(0) => return Ok(Vec::new()),
+            Some(limit) => isize::try_from(limit - 1).unwrap_or(-1),
+            None => -1,
+        };
+        let members: Vec<String> = self.connection.clone().zrange(&self.key, 0, stop).await.map_err(QueueError::unavailable)?;
+        Ok(members.iter().filter_map(|member| parse_member(member).ok()).collect())
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let seq: u64 = self.connection.incr(format!("{}:seq", self.key), 1).await.map_err(QueueError::unavailable)?;
+        let member = format!("{seq}:{}", serde_json::to_string(&item).unwrap());
+        let () = self.connection
+            .zadd(&self.key, &member, score(item.priority(), seq))
+            .await
+            .map_err(QueueError::unavailable)?;
+        let rank: Option<usize> = self.connection.zrank(&self.key, &member).await.map_err(QueueError::unavailable)?;
+        // The element may already have been dequeued by another instance, in which case it was first in line.
+        Ok(rank.map_or(1, |rank| rank + 1))
+    }
+
+    /// Inserts the elements according to their priorities with a single `ZADD`, and returns their positions in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        if items.is_empty() {
+            return Ok(Vec::new());
+        }
+        let last_seq: u64 = self.connection.incr(format!("{}:seq", self.key), items.len()).await.map_err(QueueError::unavailable)?;
+        let first_seq = last_seq + 1 - items.len() as u64;
+        let members: Vec<(f64, String)> = items.iter()
+            .zip(first_seq..)
+            .map(|(item, seq)| (score(item.priority(), seq), format!("{seq}:{}", serde_json::to_string(item).unwrap())))
+            .collect();
+        let () = self.connection.zadd_multiple(&self.key, &members).await.map_err(QueueError::unavailable)?;
+        let mut positions = Vec::with_capacity(members.len());
+        for (_, member) in &members {
+            let rank: Option<usize> = self.connection.zrank(&self.key, member).await.map_err(QueueError::unavailable)?;
+            positions.push(rank.map_or(1, |rank| rank + 1));
+        }
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// Elements which cannot be deserialized into a `T` are kept.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let members: Vec<String> = self.connection.zrange(&self.key, 0, -1).await.map_err(QueueError::unavailable)?;
+        let removed: Vec<String> = members
+            .into_iter()
+            .filter(|member| parse_member(member).is_ok_and(|item| !keep(&item)))
+            .collect();
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        self.connection.zrem(&self.key, removed).await.map_err(QueueError::unavailable)
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// Each modified element replaces the original one, unless it was dequeued in the meantime.
+    /// Elements which cannot be deserialized into a `T` are left untouched.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let members: Vec<String> = self.connection.zrange(&self.key, 0, -1).await.map_err(QueueError::unavailable)?;
+        let mut updated = 0;
+        for member in members {
+            let (Some(seq), Ok(mut item)) = (member_seq(&member), parse_member::<T>(&member)) else {
+                continue;
+            };
+            if !update(&mut item) {
+                continue;
+            }
+            let removed: usize = self.connection.zrem(&self.key, &member).await.map_err(QueueError::unavailable)?;
+            if removed == 0 {
+                continue;
+            }
+            let replacement = format!("{seq}:{}", serde_json::to_string(&item).unwrap());
+            let () = self.connection
+                .zadd(&self.key, replacement, score(item.priority(), seq))
+                .await
+                .map_err(QueueError::unavailable)?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    /// The elements are counted and removed in a single transaction. The sequence number is kept.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let (len, _): (usize, usize) = redis::pipe()
+            .atomic()
+            .zcard(&self.key)
+            .del(&self.key)
+            .query_async(&mut self.connection)
+            .await
+            .map_err(QueueError::unavailable)?;
+        Ok(len)
+    }
+
+    /// Verifies that the server responds to a `PING`.
+    async fn check(&self) -> QueueResult<()> {
+        self.connection.clone().ping::<String>().await.map(|_| ()).map_err(QueueError::unavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{check_operations, ids, TestItem};
+    use super::super::Queue;
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn scores_order_by_priority_and_then_by_sequence_number() {
+        assert!(score(9, 100) < score(0, 1));
+        assert!(score(5, 1) < score(5, 2));
+        assert!(score(u8::MAX, u64::from(u32::MAX)) < score(u8::MAX - 1, 0));
+        // Sequence numbers below 2^32 are represented exactly
+        assert_ne!(score(0, u64::from(u32::MAX) - 1), score(0, u64::from(u32::MAX)));
+    }
+
+    #[test]
+    fn members_are_split_at_the_first_colon() {
+        assert_eq!(member_seq(r#"42:{"callback_url":"http://localhost:9000/"}"#), Some(42));
+        assert_eq!(parse_member::<TestItem>(r#"7:{"id":1,"priority":0}"#).unwrap(), TestItem { id: 1, priority: 0 });
+        assert_eq!(member_seq("not-a-member"), None);
+        assert!(parse_member::<TestItem>("1:not json").is_err());
+    }
+
+    /// Returns the URL of the Redis server the ignored tests run against, e.g. `redis://127.0.0.1/`.
+    fn test_url() -> String {
+        std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL must be set to run the Redis tests")
+    }
+
+    /// Removes the sorted set and the sequence number of the given key.
+    async fn remove(url: &str, key: &str) {
+        let mut connection = redis::Client::open(url).unwrap().get_multiplexed_async_connection().await.unwrap();
+        let () = connection.del(&[key.to_owned(), format!("{key}:seq")]).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn supports_the_queue_operations() {
+        let (url, key) = (test_url(), format!("test_jobs_{}", Uuid::new_v4().simple()));
+        let mut queue: Queue<TestItem> = Box::new(RedisQueue::new(&url, &key).await.unwrap());
+        check_operations("Redis", &mut queue).await;
+        remove(&url, &key).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at TEST_REDIS_URL"]
+    async fn instances_share_the_queue() {
+        let (url, key) = (test_url(), format!("test_jobs_{}", Uuid::new_v4().simple()));
+        let mut first: Queue<TestItem> = Box::new(RedisQueue::new(&url, &key).await.unwrap());
+        let mut second: Queue<TestItem> = Box::new(RedisQueue::new(&url, &key).await.unwrap());
+        first.enqueue_many(TestItem::many(1..=2)).await.unwrap();
+        assert_eq!(second.enqueue(TestItem { id: 3, priority: 9 }).await.unwrap(), 1);
+
+        assert_eq!(ids(&first).await, [3, 1, 2]);
+        assert_eq!(second.dequeue().await.unwrap(), Some(TestItem { id: 3, priority: 9 }));
+        assert_eq!(first.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(redis_keys(&url, &key).await.unwrap(), vec![key.clone()]);
+        remove(&url, &key).await;
+    }
+}
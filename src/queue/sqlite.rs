@@ -0,0 +1,365 @@
+use super::{Predicate, QueueBackend, QueueError, QueueItem, QueueResult, Update};
+use async_trait::async_trait;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+use std::marker::PhantomData;
+use std::path::Path;
+use tracing::error;
+
+/// A queue backed by a table in a SQLite database.
+/// Each element is stored as a row containing its priority and its JSON serialization,
+/// ordered by priority and then by an autoincrementing sequence number.
+/// Unlike the JSON file queues, operations only touch the affected row instead of rewriting the whole queue.
+#[derive(Debug)]
+pub struct SqliteQueue<T> {
+    pool: SqlitePool,
+    table: String,
+    _phantom: PhantomData<T>, // This field is needed to keep the type parameter T alive
+}
+
+impl<T> SqliteQueue<T>
+where
+    T: QueueItem,
+{
+    /// Creates a new SqliteQueue storing its elements in the given table of the given database file.
+    /// The database file and the table are created if they do not exist yet.
+    pub async fn new(file: impl AsRef<Path>, table: impl Into<String>) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new().filename(file).create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        let table = table.into();
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS \"{table}\" (seq INTEGER PRIMARY KEY AUTOINCREMENT, priority INTEGER NOT NULL, payload TEXT NOT NULL)"
+        ))
+        .execute(&pool)
+        .await?;
+        Ok(Self {
+            pool,
+            table,
+            _phantom: PhantomData,
+        })
+    }
+}
+
+/// Returns the names of the tables in the given database file which start with the given prefix,
+/// e.g. to find the queues which were created before a restart. Returns no names if the database file does not exist.
+pub async fn sqlite_tables(file: impl AsRef<Path>, prefix: &str) -> Result<Vec<String>, sqlx::Error> {
+    if !file.as_ref().exists() {
+        return Ok(Vec::new());
+    }
+    let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(file)).await?;
+    let tables: Vec<String> = sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table'")
+        .fetch_all(&pool)
+        .await?;
+    pool.close().await;
+    Ok(tables.into_iter().filter(|table| table.starts_with(prefix)).collect())
+}
+
+#[async_trait]
+impl<T: QueueItem> QueueBackend<T> for SqliteQueue<T> {
+    /// Removes and returns the first element of the queue, if there is one.
+    /// The row is selected and deleted in a single statement, so concurrent dispatchers
+    /// can never receive the same element.
+    /// Rows which cannot be deserialized into a `T` are deleted and skipped.
+    async fn dequeue(&mut self) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        loop {
+            let payload: Option<String> = sqlx::query_scalar(&format!(
+                "DELETE FROM \"{table}\" WHERE seq = (SELECT seq FROM \"{table}\" ORDER BY priority DESC, seq LIMIT 1) RETURNING payload"
+            ))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?;
+            let Some(payload) = payload else {
+                return Ok(None);
+            };
+            match serde_json::from_str(&payload) {
+                Ok(item) => return Ok(Some(item)),
+                Err(err) => error!("Skipping malformed row in SQLite table {table}: {err}"),
+            }
+        }
+    }
+
+    /// Removes and returns the first element of the queue which matches, if there is one.
+    /// If a matching row is dequeued concurrently, the next matching row is tried.
+    /// Rows which cannot be deserialized into a `T` are skipped.
+    async fn dequeue_matching(&mut self, matches: &Predicate<'_, T>) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!(
+            "SELECT seq, payload FROM \"{table}\" ORDER BY priority DESC, seq"
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        for (seq, payload) in rows {
+            let Ok(item) = serde_json::from_str::<T>(&payload) else {
+                continue;
+            };
+            if !matches(&item) {
+                continue;
+            }
+            let deleted = sqlx::query(&format!("DELETE FROM \"{table}\" WHERE seq = ?"))
+                .bind(seq)
+                .execute(&self.pool)
+                .await
+                .map_err(QueueError::unavailable)?
+                .rows_affected();
+            if deleted > 0 {
+                return Ok(Some(item));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the first element of the queue without removing it, if there is one.
+    /// Returns an error if the database cannot be read or the row cannot be deserialized into a `T`.
+    async fn peek(&self) -> QueueResult<Option<T>> {
+        let table = &self.table;
+        let payload = sqlx::query_scalar::<_, String>(&format!("SELECT payload FROM \"{table}\" ORDER BY priority DESC, seq LIMIT 1"))
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?;
+        payload.map(|payload| serde_json::from_str(&payload).map_err(QueueError::serialization)).transpose()
+    }
+
+    /// Returns the number of elements in the queue.
+    /// Returns an error if the database cannot be read.
+    async fn len(&self) -> QueueResult<usize> {
+        let table = &self.table;
+        sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM \"{table}\""))
+            .fetch_one(&self.pool)
+            .await
+            .map(|count| count as usize)
+            .map_err(QueueError::unavailable)
+    }
+
+    /// Returns the first `limit` elements of the queue, or all elements.
+    /// Rows which cannot be deserialized into a `T` are skipped.
+    async fn to_vec(&self, limit: Option<usize>) -> QueueResult<Vec<T>> {
+        let table = &self.table;
+        // A negative limit means no limit in SQLite
+        let limit = limit.and_then(|limit| i64::try_from(limit).ok()).unwrap_or(-1);
+        let payloads: Vec<String> = sqlx::query_scalar(&format!(
+            "SELECT payload FROM \"{table}\" ORDER BY priority DESC, seq LIMIT ?"
+        ))
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        Ok(payloads.iter().filter_map(|payload| serde_json::from_str(payload).ok()).collect())
+    }
+
+    /// Inserts an element according to its priority, and returns its position in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue(&mut self, item: T) -> QueueResult<usize> {
+        let table = &self.table;
+        let priority = item.priority();
+        let seq = sqlx::query(&format!("INSERT INTO \"{table}\" (priority, payload) VALUES (?, ?)"))
+            .bind(priority)
+            .bind(serde_json::to_string(&item).unwrap())
+            .execute(&self.pool)
+            .await
+            .map_err(QueueError::unavailable)?
+            .last_insert_rowid();
+        let position: i64 = sqlx::query_scalar(&format!(
+            "SELECT COUNT(*) FROM \"{table}\" WHERE priority > ? OR (priority = ? AND seq <= ?)"
+        ))
+        .bind(priority)
+        .bind(priority)
+        .bind(seq)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(QueueError::unavailable)?;
+        Ok(position as usize)
+    }
+
+    /// Inserts the elements according to their priorities in a single transaction, and returns their positions in the queue.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn enqueue_many(&mut self, items: Vec<T>) -> QueueResult<Vec<usize>> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let mut inserted = Vec::with_capacity(items.len());
+        for item in &items {
+            let seq = sqlx::query(&format!("INSERT INTO \"{table}\" (priority, payload) VALUES (?, ?)"))
+                .bind(item.priority())
+                .bind(serde_json::to_string(item).unwrap())
+                .execute(&mut *transaction)
+                .await
+                .map_err(QueueError::unavailable)?
+                .last_insert_rowid();
+            inserted.push((item.priority(), seq));
+        }
+        let mut positions = Vec::with_capacity(inserted.len());
+        for (priority, seq) in inserted {
+            let position: i64 = sqlx::query_scalar(&format!(
+                "SELECT COUNT(*) FROM \"{table}\" WHERE priority > ? OR (priority = ? AND seq <= ?)"
+            ))
+            .bind(priority)
+            .bind(priority)
+            .bind(seq)
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+            positions.push(position as usize);
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(positions)
+    }
+
+    /// Removes every element for which `keep` returns false, and returns the number of removed elements.
+    /// Rows which cannot be deserialized into a `T` are kept.
+    async fn retain(&mut self, keep: &Predicate<'_, T>) -> QueueResult<usize> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!("SELECT seq, payload FROM \"{table}\""))
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+        let mut removed = 0;
+        for (seq, payload) in rows {
+            if serde_json::from_str(&payload).is_ok_and(|item| !keep(&item)) {
+                sqlx::query(&format!("DELETE FROM \"{table}\" WHERE seq = ?"))
+                    .bind(seq)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(QueueError::unavailable)?;
+                removed += 1;
+            }
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(removed)
+    }
+
+    /// Calls `update` on every element, and returns the number of modified elements.
+    /// Rows which cannot be deserialized into a `T` are left untouched.
+    /// # Panics
+    /// This function panics if the serialization impl for T fails.
+    async fn update(&mut self, update: &Update<'_, T>) -> QueueResult<usize> {
+        let table = &self.table;
+        let mut transaction = self.pool.begin().await.map_err(QueueError::unavailable)?;
+        let rows: Vec<(i64, String)> = sqlx::query_as(&format!("SELECT seq, payload FROM \"{table}\""))
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(QueueError::unavailable)?;
+        let mut updated = 0;
+        for (seq, payload) in rows {
+            let Ok(mut item) = serde_json::from_str::<T>(&payload) else {
+                continue;
+            };
+            if update(&mut item) {
+                sqlx::query(&format!("UPDATE \"{table}\" SET priority = ?, payload = ? WHERE seq = ?"))
+                    .bind(item.priority())
+                    .bind(serde_json::to_string(&item).unwrap())
+                    .bind(seq)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(QueueError::unavailable)?;
+                updated += 1;
+            }
+        }
+        transaction.commit().await.map_err(QueueError::unavailable)?;
+        Ok(updated)
+    }
+
+    /// Removes all elements, and returns the number of removed elements.
+    async fn clear(&mut self) -> QueueResult<usize> {
+        let table = &self.table;
+        sqlx::query(&format!("DELETE FROM \"{table}\""))
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() as usize)
+            .map_err(QueueError::unavailable)
+    }
+
+    /// Verifies that the database responds to a query.
+    async fn check(&self) -> QueueResult<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await.map(|_| ()).map_err(QueueError::unavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{check_operations, ids, TestItem};
+    use super::super::Queue;
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn supports_the_queue_operations() {
+        let dir = TempDir::new().unwrap();
+        let mut queue: Queue<TestItem> = Box::new(SqliteQueue::new(dir.path().join("queues.sqlite"), "jobs").await.unwrap());
+        check_operations("Sqlite", &mut queue).await;
+    }
+
+    #[tokio::test]
+    async fn elements_survive_reopening_the_database() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("queues.sqlite");
+        let mut queue = SqliteQueue::new(&file, "jobs").await.unwrap();
+        queue.enqueue_many(vec![TestItem { id: 1, priority: 0 }, TestItem { id: 2, priority: 9 }]).await.unwrap();
+        drop(queue);
+
+        let reopened: Queue<TestItem> = Box::new(SqliteQueue::new(&file, "jobs").await.unwrap());
+        assert_eq!(ids(&reopened).await, [2, 1]);
+    }
+
+    #[tokio::test]
+    async fn tables_of_one_database_are_separate_queues() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("queues.sqlite");
+        let mut jobs = SqliteQueue::new(&file, "jobs").await.unwrap();
+        let mut topic_jobs = SqliteQueue::new(&file, "jobs_cocktails").await.unwrap();
+        SqliteQueue::<TestItem>::new(&file, "workers").await.unwrap();
+        jobs.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap();
+        topic_jobs.enqueue(TestItem { id: 2, priority: 0 }).await.unwrap();
+
+        assert_eq!(jobs.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(jobs.dequeue().await.unwrap(), None);
+        assert_eq!(topic_jobs.len().await.unwrap(), 1);
+        let mut tables = sqlite_tables(&file, "jobs").await.unwrap();
+        tables.sort();
+        assert_eq!(tables, ["jobs", "jobs_cocktails"]);
+        assert!(sqlite_tables(dir.path().join("missing.sqlite"), "jobs").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_dequeues_never_return_the_same_element() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("queues.sqlite");
+        let mut queue = SqliteQueue::new(&file, "jobs").await.unwrap();
+        queue.enqueue_many(TestItem::many(1..=20)).await.unwrap();
+
+        // Each task opens its own pool, like service instances sharing the database file
+        let mut tasks = Vec::new();
+        for _ in 0..4 {
+            let file = file.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut queue = SqliteQueue::<TestItem>::new(&file, "jobs").await.unwrap();
+                let mut dequeued = Vec::new();
+                while let Some(item) = queue.dequeue().await.unwrap() {
+                    dequeued.push(item.id);
+                }
+                dequeued
+            }));
+        }
+        let mut dequeued = Vec::new();
+        for task in tasks {
+            dequeued.extend(task.await.unwrap());
+        }
+        dequeued.sort();
+        assert_eq!(dequeued, (1..=20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn malformed_rows_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        let mut queue = SqliteQueue::new(dir.path().join("queues.sqlite"), "jobs").await.unwrap();
+        sqlx::query("INSERT INTO \"jobs\" (priority, payload) VALUES (9, 'not json')").execute(&queue.pool).await.unwrap();
+        queue.enqueue(TestItem { id: 1, priority: 0 }).await.unwrap();
+
+        assert_eq!(queue.to_vec(None).await.unwrap(), TestItem::many([1]));
+        assert!(matches!(queue.peek().await, Err(QueueError::Serialization(_))));
+        assert_eq!(queue.dequeue().await.unwrap(), Some(TestItem { id: 1, priority: 0 }));
+        assert_eq!(queue.len().await.unwrap(), 0);
+    }
+}
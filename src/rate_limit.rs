@@ -0,0 +1,136 @@
+//! Per-client rate limiting of job submissions.
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+use crate::error::{ApiError, ErrorCode};
+
+/// The number of clients above which buckets which are full again are discarded, to bound memory usage.
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+/// The token bucket of a single client.
+#[derive(Debug)]
+struct Bucket {
+    /// The number of requests the client can currently make.
+    tokens: f64,
+    /// The time at which `tokens` was last refilled.
+    refilled_at: Instant,
+}
+
+/// A token bucket rate limiter keyed by client.
+/// Every client can make `burst` requests at once, and one more request every `1 / rate` seconds.
+/// The buckets are shared by all threads serving requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a new RateLimiter allowing `rate` requests per second, with bursts of up to `burst` requests.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the client's bucket.
+    /// Returns the time after which the client can try again if the bucket is empty.
+    fn acquire(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(client.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+        });
+        if self.refill(bucket, now) < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Adds the tokens earned since the bucket was last refilled, up to the burst size,
+    /// and returns the resulting number of tokens.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let earned = now.duration_since(bucket.refilled_at).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + earned).min(self.burst);
+        bucket.refilled_at = now;
+        bucket.tokens
+    }
+}
+
+/// Middleware which limits the rate of requests per client.
+/// Clients are identified by the bearer token they authenticated with, or by their IP address if they sent none.
+/// Requests exceeding the limit are rejected with 429 Too Many Requests and "RateLimited",
+/// along with a Retry-After header containing the number of seconds to wait.
+pub async fn limit_rate(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = request.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map_or_else(|| format!("ip:{}", addr.ip()), |key| format!("key:{key}"));
+    if let Err(retry_after) = limiter.acquire(&client) {
+        let retry_after = retry_after.as_secs_f64().ceil() as u64;
+        info!("Rate limit exceeded by {}, rejecting request...", addr.ip());
+        let message = format!("Too many requests, retry after {retry_after} second(s)");
+        let err = ApiError::new(StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited, message);
+        return ([(header::RETRY_AFTER, retry_after.to_string())], err).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::serve;
+    use axum::{middleware, routing::post, Router};
+
+    #[tokio::test]
+    async fn requests_beyond_the_burst_are_rejected_with_retry_after() {
+        let limiter = Arc::new(RateLimiter::new(0.5, 2));
+        let routes = Router::new()
+            .route("/submit-job", post(|| async { StatusCode::ACCEPTED }))
+            .layer(middleware::from_fn_with_state(limiter, limit_rate));
+        let url = format!("{}submit-job", serve(routes).await);
+        let client = reqwest::Client::new();
+
+        for _ in 0..2 {
+            assert_eq!(client.post(&url).send().await.unwrap().status(), StatusCode::ACCEPTED);
+        }
+        let response = client.post(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "RateLimited");
+        // Clients with an API key have their own bucket, even if they share an IP address
+        assert_eq!(client.post(&url).bearer_auth("key").send().await.unwrap().status(), StatusCode::ACCEPTED);
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let limiter = RateLimiter::new(20.0, 1);
+        assert!(limiter.acquire("ip:127.0.0.1").is_ok());
+        assert!(limiter.acquire("ip:127.0.0.1").unwrap_err() <= Duration::from_millis(50));
+        assert!(limiter.acquire("ip:127.0.0.2").is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.acquire("ip:127.0.0.1").is_ok());
+    }
+}
@@ -0,0 +1,25 @@
+//! The read-only mode, in which an instance serves the listing, status and stats endpoints of queues
+//! shared with other instances, e.g. for dashboards, but does not change them.
+
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::info;
+use crate::AppState;
+use crate::error::{ApiError, ErrorCode};
+
+/// Middleware which rejects requests to the endpoints changing the queues with 405 Method Not Allowed and "ReadOnly"
+/// if the service runs with `--read-only`, and passes them on otherwise.
+pub async fn reject_if_read_only(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.read_only {
+        info!("Rejecting {} {} in read-only mode", request.method(), request.uri());
+        let message = "This instance is read-only and cannot change the queues";
+        return ApiError::new(StatusCode::METHOD_NOT_ALLOWED, ErrorCode::ReadOnly, message).into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,184 @@
+//! Prometheus metrics, a JSON summary of the most important ones, and the spans in which requests are logged.
+
+use axum::extract::{Request, State};
+use axum::Json;
+use metrics::{describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{error, field, info_span, Span};
+use uuid::Uuid;
+use crate::{AppState, QueueMode};
+use crate::error::ApiError;
+use crate::queue::{QueueError, QUEUE_DEQUEUED, QUEUE_ENQUEUED, QUEUE_OPERATION_DURATION};
+
+/// The header in which the id of a request is accepted from clients and returned to them.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The number of submitted jobs.
+pub const JOBS_SUBMITTED: &str = "jobs_submitted_total";
+/// The number of jobs assigned to a worker, either on submission or on worker registration.
+pub const JOBS_ASSIGNED: &str = "jobs_assigned_total";
+/// The number of jobs which were queued because no worker was available.
+pub const JOBS_QUEUED: &str = "jobs_queued_total";
+/// The number of worker registrations.
+pub const WORKER_REGISTRATIONS: &str = "worker_registrations_total";
+/// The number of jobs moved to the dead-letter queue because too many workers failed to accept them.
+pub const JOBS_DEAD_LETTERED: &str = "jobs_dead_lettered_total";
+/// The number of queued jobs which were discarded because they waited longer than their time-to-live.
+pub const JOBS_EXPIRED: &str = "jobs_expired_total";
+/// The number of failed attempts to send a job to a worker's callback URL.
+pub const CALLBACK_FAILURES: &str = "callback_failures_total";
+/// The time a job spent in the job queue before it was assigned to a worker.
+pub const JOB_QUEUE_TIME: &str = "job_queue_time_seconds";
+/// The time a worker spent in the worker queue before it was assigned a job.
+pub const WORKER_QUEUE_TIME: &str = "worker_queue_time_seconds";
+/// The current number of jobs in the job queue.
+pub const JOB_QUEUE_DEPTH: &str = "job_queue_depth";
+/// The current number of workers in the worker queue.
+pub const WORKER_QUEUE_DEPTH: &str = "worker_queue_depth";
+
+/// The histogram buckets for queue times, in seconds.
+const QUEUE_TIME_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// The histogram buckets for the durations of queue operations, in seconds.
+const QUEUE_OPERATION_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// Installs the global Prometheus recorder and describes the metrics.
+/// Returns a handle with which the recorded metrics can be rendered.
+/// # Panics
+/// This function panics if a global recorder has already been installed.
+pub fn install() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(QUEUE_TIME_BUCKETS)
+        .expect("Queue time buckets are not empty")
+        .set_buckets_for_metric(Matcher::Full(QUEUE_OPERATION_DURATION.to_string()), QUEUE_OPERATION_BUCKETS)
+        .expect("Queue operation buckets are not empty")
+        .install_recorder()
+        .expect("Failed to install Prometheus recorder");
+    describe_counter!(JOBS_SUBMITTED, "The number of submitted jobs");
+    describe_counter!(JOBS_ASSIGNED, "The number of jobs assigned to a worker");
+    describe_counter!(JOBS_QUEUED, "The number of jobs queued because no worker was available");
+    describe_counter!(WORKER_REGISTRATIONS, "The number of worker registrations");
+    describe_counter!(JOBS_DEAD_LETTERED, "The number of jobs moved to the dead-letter queue");
+    describe_counter!(JOBS_EXPIRED, "The number of queued jobs discarded because they waited longer than their time-to-live");
+    describe_counter!(CALLBACK_FAILURES, "The number of failed attempts to send a job to a worker");
+    describe_histogram!(JOB_QUEUE_TIME, Unit::Seconds, "The time a job spent queued before it was assigned");
+    describe_histogram!(WORKER_QUEUE_TIME, Unit::Seconds, "The time a worker spent queued before it was assigned a job");
+    describe_gauge!(JOB_QUEUE_DEPTH, "The current number of queued jobs");
+    describe_gauge!(WORKER_QUEUE_DEPTH, "The current number of queued workers");
+    describe_counter!(QUEUE_ENQUEUED, "The number of elements enqueued into a queue");
+    describe_counter!(QUEUE_DEQUEUED, "The number of elements dequeued from a queue");
+    describe_histogram!(QUEUE_OPERATION_DURATION, Unit::Seconds, "The time an operation on a queue took");
+    handle
+}
+
+/// The counters summarized by [`stats`], counted since the service started.
+/// They are kept alongside the Prometheus metrics, which cannot be read back.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// The number of submitted jobs.
+    pub jobs_submitted: AtomicU64,
+    /// The number of jobs assigned to a worker, either on submission or on worker registration.
+    pub jobs_assigned: AtomicU64,
+    /// The number of failed attempts to send a job to a worker.
+    pub callback_failures: AtomicU64,
+    /// The total time in milliseconds which the jobs assigned from the job queue spent queued.
+    job_queue_time_millis: AtomicU64,
+    /// The number of jobs assigned from the job queue.
+    jobs_dequeued: AtomicU64,
+}
+
+impl Stats {
+    /// Adds the given number of occurrences to the given counter.
+    pub fn count(counter: &AtomicU64, occurrences: u64) {
+        counter.fetch_add(occurrences, Ordering::Relaxed);
+    }
+
+    /// Records the time a job spent in the job queue before it was assigned to a worker.
+    pub fn record_job_queue_time(&self, millis: u64) {
+        self.job_queue_time_millis.fetch_add(millis, Ordering::Relaxed);
+        self.jobs_dequeued.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The response to a stats request.
+#[derive(Debug, Serialize)]
+pub struct StatsResponse {
+    pub job_queue_depth: usize,
+    pub worker_queue_depth: usize,
+    pub jobs_submitted: u64,
+    pub jobs_assigned: u64,
+    pub callback_failures: u64,
+    /// The average time in seconds which the jobs assigned from the job queue spent queued,
+    /// or None if no job has been assigned from the job queue yet.
+    pub average_job_queue_time_secs: Option<f64>,
+    /// The implementation of the job queue, e.g. `CachedJsonFile`, regardless of its capacity and metrics.
+    pub job_queue_mode: QueueMode,
+    /// The implementation of the worker queue.
+    pub worker_queue_mode: QueueMode,
+}
+
+/// GET /stats
+/// Summarizes the health of the queues as JSON, for users who do not want to scrape the Prometheus metrics:
+/// the current depths of both queues, the numbers of submitted jobs, assigned jobs and failed callbacks
+/// since the service started, the average time jobs spent in the job queue, and the modes of both queues.
+/// If either queue could not be read, this endpoint responds with the status of the error, see [`QueueError::status_code`].
+pub async fn stats(State(state): State<AppState>) -> Result<Json<StatsResponse>, ApiError> {
+    let stats = &state.stats;
+    let jobs_dequeued = stats.jobs_dequeued.load(Ordering::Relaxed);
+    let average_job_queue_time_secs = (jobs_dequeued > 0)
+        .then(|| stats.job_queue_time_millis.load(Ordering::Relaxed) as f64 / jobs_dequeued as f64 / 1000.0);
+    let read_depth = |err: QueueError| {
+        error!("Failed to read queue depth: '{err}'");
+        ApiError::from(err)
+    };
+    Ok(Json(StatsResponse {
+        job_queue_depth: state.job_queue.lock().await.len().await.map_err(read_depth)?,
+        worker_queue_depth: state.worker_queue.lock().await.len().await.map_err(read_depth)?,
+        jobs_submitted: stats.jobs_submitted.load(Ordering::Relaxed),
+        jobs_assigned: stats.jobs_assigned.load(Ordering::Relaxed),
+        callback_failures: stats.callback_failures.load(Ordering::Relaxed),
+        average_job_queue_time_secs,
+        job_queue_mode: state.job_queue_mode,
+        worker_queue_mode: state.worker_queue_mode,
+    }))
+}
+
+/// GET /metrics
+/// Renders all metrics in the Prometheus text exposition format.
+/// The queue depths are read from the queues at the time of the request,
+/// so they are accurate even if the queues are shared with other service instances.
+/// If a queue could not be read, its depth keeps the value which was read last.
+pub async fn metrics(State(state): State<AppState>) -> String {
+    match state.job_queue.lock().await.len().await {
+        Ok(depth) => gauge!(JOB_QUEUE_DEPTH).set(depth as f64),
+        Err(err) => error!("Failed to read job queue depth: '{err}'"),
+    }
+    match state.worker_queue.lock().await.len().await {
+        Ok(depth) => gauge!(WORKER_QUEUE_DEPTH).set(depth as f64),
+        Err(err) => error!("Failed to read worker queue depth: '{err}'"),
+    }
+    state.metrics.render()
+}
+
+/// Creates the span in which a request is handled, so that every log line emitted while handling it
+/// carries the request's method, URI and id. The id is taken from the X-Request-Id header, which is
+/// generated for requests without one. Handlers record the id of the job a request is about with [`record_job_id`].
+/// With the `otel` feature, the span continues the trace given in the request's `traceparent` header, if any.
+pub fn request_span(request: &Request) -> Span {
+    let request_id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+    let span = info_span!("request", method = %request.method(), uri = %request.uri(), request_id = %request_id, job_id = field::Empty);
+    #[cfg(feature = "otel")]
+    crate::otel::set_parent(&span, request.headers());
+    span
+}
+
+/// Records the id of the job which the current request is about in the request's span,
+/// so that all log lines about the job can be found by its id, including those which do not mention it.
+pub fn record_job_id(id: Uuid) {
+    Span::current().record("job_id", field::display(id));
+}
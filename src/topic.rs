@@ -0,0 +1,376 @@
+//! Named topics, each with its own job and worker queue, so that jobs of different kinds are routed separately.
+
+use axum::extract::{Path, Query, Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path as FilePath;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use crate::job::{self, Job, SubmitQuery};
+use crate::queue::{self, Queue};
+use crate::worker::{self, RegisterWorkerQuery, Worker};
+use crate::{create_queue, existing_queue_names, metered, AppState, Args, ListQuery};
+use crate::error::{ApiError, ErrorCode};
+
+/// The maximum length of a topic name.
+const MAX_TOPIC_LENGTH: usize = 64;
+
+/// The job and worker queue of a topic.
+#[derive(Debug, Clone)]
+struct TopicQueues {
+    job_queue: Arc<Mutex<Queue<Job>>>,
+    worker_queue: Arc<Mutex<Queue<Worker>>>,
+}
+
+/// The queues of all topics which were used since the service started or which already had queues, keyed by topic name.
+/// The queues of a topic are created on first use, with the same implementation, capacity and metrics as the global queues.
+#[derive(Debug)]
+pub struct Topics {
+    args: Args,
+    queues: Mutex<HashMap<String, TopicQueues>>,
+}
+
+impl Topics {
+    /// Creates the registry of topics, whose queues are created according to the given arguments.
+    pub fn new(args: Args) -> Self {
+        Self { args, queues: Mutex::default() }
+    }
+
+    /// Returns the queues of the given topic, creating them if the topic was not used before:
+    /// the files `jobs_<topic>` and `workers_<topic>` next to the global queue files in the file modes,
+    /// or the tables or sorted sets of the same names.
+    async fn queues(&self, topic: &str) -> TopicQueues {
+        let mut queues = self.queues.lock().await;
+        if let Some(topic_queues) = queues.get(topic) {
+            return topic_queues.clone();
+        }
+        info!(topic, "Creating queues for topic...");
+        let args = &self.args;
+        let job_queue_mode = args.job_queue_mode.unwrap_or(args.mode);
+        let job_queue_dir = args.job_queue_path.as_deref().and_then(FilePath::parent).unwrap_or(FilePath::new(""));
+        let mut job_queue: Queue<Job> = create_queue(job_queue_mode, &format!("jobs_{topic}"), job_queue_dir, None, args).await;
+        if let Some(capacity) = args.job_queue_capacity {
+            job_queue = Box::new(queue::BoundedQueue::new(job_queue, capacity));
+        }
+        let worker_queue_mode = args.worker_queue_mode.unwrap_or(args.mode);
+        let worker_queue_dir = args.worker_queue_path.as_deref().and_then(FilePath::parent).unwrap_or(FilePath::new(""));
+        let mut worker_queue: Queue<Worker> = create_queue(worker_queue_mode, &format!("workers_{topic}"), worker_queue_dir, None, args).await;
+        if let Some(capacity) = args.worker_queue_capacity {
+            worker_queue = Box::new(queue::BoundedQueue::new(worker_queue, capacity));
+        }
+        let topic_queues = TopicQueues {
+            job_queue: Arc::new(Mutex::new(metered(job_queue, "jobs", Some(topic), args))),
+            worker_queue: Arc::new(Mutex::new(metered(worker_queue, "workers", Some(topic), args))),
+        };
+        queues.insert(topic.to_owned(), topic_queues.clone());
+        topic_queues
+    }
+
+    /// Creates the queues of all topics which already have a job or worker queue, e.g. because their jobs were persisted
+    /// before a restart, so that they are dispatched, listed, flushed and evicted without waiting for the topic to be used again.
+    pub async fn load_existing(&self) {
+        let args = &self.args;
+        let job_queue_mode = args.job_queue_mode.unwrap_or(args.mode);
+        let job_queue_dir = args.job_queue_path.as_deref().and_then(FilePath::parent).unwrap_or(FilePath::new(""));
+        let worker_queue_mode = args.worker_queue_mode.unwrap_or(args.mode);
+        let worker_queue_dir = args.worker_queue_path.as_deref().and_then(FilePath::parent).unwrap_or(FilePath::new(""));
+        let mut topics = BTreeSet::new();
+        for name in existing_queue_names(job_queue_mode, "jobs_", job_queue_dir, args).await {
+            topics.extend(name.strip_prefix("jobs_").map(str::to_owned));
+        }
+        for name in existing_queue_names(worker_queue_mode, "workers_", worker_queue_dir, args).await {
+            topics.extend(name.strip_prefix("workers_").map(str::to_owned));
+        }
+        topics.retain(|topic| is_valid_topic(topic));
+        for topic in &topics {
+            self.queues(topic).await;
+        }
+        if !topics.is_empty() {
+            info!("Loaded the queues of {} existing topic(s)", topics.len());
+        }
+    }
+
+    /// Returns the queues of the given topic if they were created, without creating them,
+    /// so that requests which only read the queues do not create topics.
+    async fn existing_queues(&self, topic: &str) -> Option<TopicQueues> {
+        self.queues.lock().await.get(topic).cloned()
+    }
+
+    /// Returns the names of the topics whose queues were created.
+    async fn names(&self) -> Vec<String> {
+        self.queues.lock().await.keys().cloned().collect()
+    }
+}
+
+impl AppState {
+    /// Returns a copy of the state whose job and worker queues are those of the given topic, creating them on first use.
+    /// All other queues, such as the assigned and scheduled jobs, are shared by all topics.
+    pub async fn for_topic(&self, topic: &str) -> AppState {
+        let queues = self.topics.queues(topic).await;
+        AppState {
+            job_queue: queues.job_queue,
+            worker_queue: queues.worker_queue,
+            topic: Some(topic.into()),
+            ..self.clone()
+        }
+    }
+
+    /// Returns a copy of the state whose job and worker queues are those of the given topic like [`AppState::for_topic`],
+    /// or None if the topic was never used and has no queues yet.
+    pub async fn for_existing_topic(&self, topic: &str) -> Option<AppState> {
+        let queues = self.topics.existing_queues(topic).await?;
+        Some(AppState {
+            job_queue: queues.job_queue,
+            worker_queue: queues.worker_queue,
+            topic: Some(topic.into()),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a copy of the global state whose job and worker queues are those of the topic the given job was submitted to,
+    /// or the global state itself if the job was submitted without a topic.
+    pub async fn for_job(&self, job: &Job) -> AppState {
+        match &job.topic {
+            Some(topic) => self.for_topic(topic).await,
+            None => self.clone(),
+        }
+    }
+
+    /// Returns the states whose queues the given worker takes jobs from and is queued in, in the order of its preference:
+    /// those of the topics it listed in its CPEE-TOPICS header, or a copy of this state if it listed none.
+    pub async fn for_worker(&self, worker: &Worker) -> Vec<AppState> {
+        if worker.topics.is_empty() {
+            return vec![self.clone()];
+        }
+        let mut states = Vec::with_capacity(worker.topics.len());
+        for topic in &worker.topics {
+            states.push(self.for_topic(topic).await);
+        }
+        states
+    }
+
+    /// Returns the states of all topics whose queues were created, e.g. to flush their queues.
+    pub async fn topic_states(&self) -> Vec<AppState> {
+        let mut states = Vec::new();
+        for topic in self.topics.names().await {
+            states.extend(self.for_existing_topic(&topic).await);
+        }
+        states
+    }
+}
+
+/// Returns true if the given topic name is valid. Since queue files are named after their topic,
+/// only ASCII letters, digits, `-` and `_` are allowed, and the name must not be empty or longer than 64 characters.
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty()
+        && topic.len() <= MAX_TOPIC_LENGTH
+        && topic.chars().all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+}
+
+/// Returns the state of the given topic for the endpoints which list or clear its queues, which do not create topics.
+/// Returns 400 Bad Request and "InvalidTopic" if the topic name is invalid,
+/// or 404 Not Found and "NotFound" if the topic was never used and has no queues.
+async fn existing_topic_state(state: &AppState, topic: &str) -> Result<AppState, ApiError> {
+    if !is_valid_topic(topic) {
+        return Err(invalid_topic(topic));
+    }
+    state.for_existing_topic(topic).await.ok_or_else(|| {
+        info!(topic, "Request for a topic which has no queues");
+        ApiError::not_found(format!("No topic named '{topic}' has queues"))
+    })
+}
+
+/// Returns the error rejecting a request naming the given invalid topic with 400 Bad Request and "InvalidTopic".
+pub fn invalid_topic(topic: &str) -> ApiError {
+    error!("Invalid request: '{topic}' is not a valid topic name");
+    let message = format!("'{topic}' is not a valid topic name: it must consist of 1 to {MAX_TOPIC_LENGTH} ASCII letters, digits, '-' and '_'");
+    ApiError::new(StatusCode::BAD_REQUEST, ErrorCode::InvalidTopic, message)
+}
+
+/// POST /submit-job/{topic}
+/// Submits a job like [`job::submit_job`], but to the job queue of the given topic, and only dispatches it
+/// to workers which registered for the topic. The queues of a topic are created on first use.
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+#[rustfmt::skip]
+pub async fn submit_job(
+    Path(topic): Path<String>,
+    State(state): State<AppState>,
+    query: Query<SubmitQuery>,
+    data: Json<Value>
+) -> Response {
+    if !is_valid_topic(&topic) {
+        return invalid_topic(&topic).into_response();
+    }
+    job::submit_job(State(state.for_topic(&topic).await), query, data).await.into_response()
+}
+
+/// POST /register-worker/{topic}
+/// Registers a worker like [`worker::register_worker`], but for the given topic: the worker only receives jobs
+/// submitted to the topic, and is queued in the worker queue of the topic. The queues of a topic are created on first use.
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+#[rustfmt::skip]
+pub async fn register_worker(
+    Path(topic): Path<String>,
+    State(state): State<AppState>,
+    query: Query<RegisterWorkerQuery>,
+    request: Request
+) -> Response {
+    if !is_valid_topic(&topic) {
+        return invalid_topic(&topic).into_response();
+    }
+    worker::register_worker(State(state.for_topic(&topic).await), query, request).await.into_response()
+}
+
+/// GET /jobs/{topic}
+/// Lists the queued jobs of the given topic like [`job::list_jobs`].
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+/// If the topic was never used, this endpoint responds with 404 Not Found and "NotFound", without creating its queues.
+#[rustfmt::skip]
+pub async fn list_jobs(
+    Path(topic): Path<String>,
+    State(state): State<AppState>,
+    query: Query<ListQuery>
+) -> Response {
+    match existing_topic_state(&state, &topic).await {
+        Ok(topic_state) => job::list_jobs(State(topic_state), query).await.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// DELETE /jobs/{topic}
+/// Removes all jobs from the job queue of the given topic like [`job::clear_jobs`].
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+/// If the topic was never used, this endpoint responds with 404 Not Found and "NotFound", without creating its queues.
+#[rustfmt::skip]
+pub async fn clear_jobs(
+    Path(topic): Path<String>,
+    State(state): State<AppState>
+) -> Response {
+    match existing_topic_state(&state, &topic).await {
+        Ok(topic_state) => job::clear_jobs(State(topic_state)).await.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// GET /workers/{topic}
+/// Lists the queued workers of the given topic like [`worker::list_workers`].
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+/// If the topic was never used, this endpoint responds with 404 Not Found and "NotFound", without creating its queues.
+#[rustfmt::skip]
+pub async fn list_workers(
+    Path(topic): Path<String>,
+    State(state): State<AppState>,
+    query: Query<ListQuery>
+) -> Response {
+    match existing_topic_state(&state, &topic).await {
+        Ok(topic_state) => worker::list_workers(State(topic_state), query).await.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// DELETE /workers/{topic}
+/// Removes all workers from the worker queue of the given topic like [`worker::clear_workers`].
+/// Workers which registered for several topics stay queued in the worker queues of their other topics.
+/// If the topic name is invalid, this endpoint responds with 400 Bad Request and "InvalidTopic".
+/// If the topic was never used, this endpoint responds with 404 Not Found and "NotFound", without creating its queues.
+#[rustfmt::skip]
+pub async fn clear_workers(
+    Path(topic): Path<String>,
+    State(state): State<AppState>
+) -> Response {
+    match existing_topic_state(&state, &topic).await {
+        Ok(topic_state) => worker::clear_workers(State(topic_state)).await.into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Takes a slot of a worker which registered for several topics, and was just dequeued from the worker queue of the
+/// given state's topic, from the worker queues of its other topics, since a slot can only be assigned a single job.
+/// A worker whose last slot was taken is removed from them, so that it is not assigned more jobs than it has slots.
+/// Failures are only logged, since the worker has already been dequeued.
+pub async fn release_other_topics(state: &AppState, worker: &Worker) {
+    let callback_url = &worker.callback_url;
+    let other_topics = worker.topics.iter().filter(|&topic| Some(topic.as_str()) != state.topic.as_deref());
+    for topic in other_topics {
+        let topic_state = state.for_topic(topic).await;
+        let mut worker_queue = topic_state.worker_queue.lock().await;
+        let released = match worker_queue.retain(&|queued: &Worker| queued.callback_url != *callback_url || queued.slots > 1).await {
+            Ok(removed) => worker_queue.update(&|queued: &mut Worker| {
+                if queued.callback_url != *callback_url {
+                    return false;
+                }
+                queued.slots -= 1;
+                true
+            }).await.map(|updated| removed + updated),
+            Err(err) => Err(err),
+        };
+        match released {
+            Ok(0) => {},
+            Ok(_) => info!(%callback_url, topic, "Released a slot of the worker in another of its topics"),
+            Err(err) => error!(%callback_url, topic, "Failed to release the worker from the worker queue of another of its topics: '{err}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{CancelJobResponse, JobState};
+    use serde_json::json;
+
+    /// Queues a new job in the job queue of the given state, recording it as queued.
+    async fn queued_job(state: &AppState) -> Job {
+        let job = Job::new(json!({ "drink": "mojito" }));
+        state.job_queue.lock().await.enqueue(job.clone()).await.unwrap();
+        job.record_state(state, JobState::Queued).await;
+        job
+    }
+
+    #[tokio::test]
+    async fn listing_an_unknown_topic_does_not_create_it() {
+        let state = crate::tests::state();
+        let response = list_workers(Path("never-used".into()), State(state.clone()), Query(ListQuery { limit: None })).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = list_jobs(Path("never-used".into()), State(state.clone()), Query(ListQuery { limit: None })).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = clear_jobs(Path("never-used".into()), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(state.topics.names().await.is_empty());
+        assert!(state.for_existing_topic("never-used").await.is_none());
+
+        state.for_topic("cocktails").await;
+        let response = list_workers(Path("cocktails".into()), State(state.clone()), Query(ListQuery { limit: None })).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.topics.names().await, ["cocktails"]);
+    }
+
+    #[tokio::test]
+    async fn jobs_of_topics_are_cancelled_by_id() {
+        let state = crate::tests::state();
+        let job = queued_job(&state.for_topic("cocktails").await).await;
+
+        let (status, Json(response)) = job::cancel_job(State(state.clone()), Path(job.id)).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(response, CancelJobResponse::Cancelled));
+        assert!(state.for_topic("cocktails").await.job_queue.lock().await.is_empty().await.unwrap());
+        assert_eq!(state.job_statuses.lock().await[&job.id].state, JobState::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn clearing_the_jobs_of_a_topic_only_cancels_those() {
+        let state = crate::tests::state();
+        let global_job = queued_job(&state).await;
+        let topic_job = queued_job(&state.for_topic("cocktails").await).await;
+
+        let response = clear_jobs(Path("cocktails".into()), State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.job_queue.lock().await.len().await.unwrap(), 1);
+        let job_statuses = state.job_statuses.lock().await;
+        assert_eq!(job_statuses[&topic_job.id].state, JobState::Cancelled);
+        assert_eq!(job_statuses[&global_job.id].state, JobState::Queued);
+    }
+}
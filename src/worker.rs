@@ -1,44 +1,189 @@
 //! Worker registration and job assignment
 
-use axum::extract::{Request, State};
-use axum::http::StatusCode;
+use axum::body::to_bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, Request, State};
+use axum::http::{HeaderMap, StatusCode};
 use axum::Json;
 use axum::response::{IntoResponse, Response};
 use chrono::{DateTime, Utc};
+use metrics::{counter, histogram};
 use reqwest::Url;
+use derive_more::Display;
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
-use crate::AppState;
-use crate::job::Job;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::mem;
+use std::pin::pin;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::{AppState, ClearQueueResponse, ListQuery};
+use crate::error::{ApiError, ErrorCode};
+use crate::job::{self, AsynchronousWorkerResponse, Job, JobState};
+use crate::queue::{Identifiable, QueueError, QueueItem, QueueResult};
+use crate::events::{self, JobEvent};
+use crate::topic;
+use crate::telemetry::{self, Stats};
+
+/// The prefix of the callback URLs under which workers connected via WebSocket are queued,
+/// followed by the id of their connection.
+const WEBSOCKET_CALLBACK_PREFIX: &str = "websocket:";
+
+/// The callback URL under which a worker which waits for a job without a callback URL is reported, e.g. in job events.
+const LONG_POLL_CALLBACK_URL: &str = "long-poll";
+
+/// The longest time a worker registration may wait for a job, see [`register_worker`].
+const MAX_REGISTER_WAIT: Duration = Duration::from_secs(60);
+
+/// The largest request body which is read when looking for a callback URL in it, see [`extract_callback_url`].
+const MAX_CALLBACK_BODY_SIZE: usize = 64 * 1024;
 
 /// A worker that can process jobs.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Worker {
     /// The URL to which a job should be sent.
     pub callback_url: String,
     /// The time at which the worker was registered.
     pub registered_at: DateTime<Utc>,
+    /// The time at which the worker was last known to be alive, i.e. registered or sent a heartbeat.
+    #[serde(default = "Utc::now")]
+    pub last_seen: DateTime<Utc>,
+    /// The capabilities of the worker. Only jobs whose required tags are all present are assigned to it.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The number of jobs the worker can still take on concurrently. The worker stays queued until all of them are assigned.
+    #[serde(default = "one_slot")]
+    pub slots: u32,
+    /// The size in bytes of the largest job the worker accepts, see [`Job::size`], or None if it accepts jobs of any size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payload: Option<u64>,
+    /// The topics the worker accepts jobs of, in the order of its preference, or none if it registered for a single topic
+    /// or for the global queues. Such a worker is queued in the worker queues of all of these topics.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub topics: Vec<String>,
+}
+
+/// The number of slots of a worker which did not declare any.
+fn one_slot() -> u32 {
+    1
 }
 
 impl Worker {
-    /// Creates a new worker with the given callback URL.
-    pub fn new(callback_url: impl Into<String>) -> Self {
+    /// Creates a new worker with the given callback URL and tags, which can take on a single job.
+    pub fn new(callback_url: impl Into<String>, tags: Vec<String>) -> Self {
+        let now = Utc::now();
         Self {
             callback_url: callback_url.into(),
-            registered_at: Utc::now(),
+            registered_at: now,
+            last_seen: now,
+            tags,
+            slots: one_slot(),
+            max_payload: None,
+            topics: Vec::new(),
         }
     }
+
+    /// Sets the number of jobs the worker can take on concurrently.
+    pub fn with_slots(mut self, slots: u32) -> Self {
+        self.slots = slots;
+        self
+    }
+
+    /// Limits the size of the jobs the worker accepts to the given number of bytes.
+    pub fn with_max_payload(mut self, max_payload: Option<u64>) -> Self {
+        self.max_payload = max_payload;
+        self
+    }
+
+    /// Sets the topics the worker accepts jobs of, in the order of its preference.
+    pub fn with_topics(mut self, topics: Vec<String>) -> Self {
+        self.topics = topics;
+        self
+    }
+
+    /// Returns true if the worker has all the tags required by the given job, and accepts jobs of its size.
+    /// The job is only serialized to determine its size if the worker limits it.
+    pub fn can_process(&self, job: &Job) -> bool {
+        job.required_tags.iter().all(|tag| self.tags.contains(tag))
+            && self.max_payload.is_none_or(|max_payload| job.size() <= max_payload)
+    }
+
+    /// Returns true if the worker has not been seen for longer than the given time-to-live.
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        Utc::now().signed_duration_since(self.last_seen).to_std().is_ok_and(|age| age > ttl)
+    }
+
+    /// Returns the id of the worker's WebSocket connection, or None if the worker receives jobs at a callback URL.
+    pub fn websocket_connection(&self) -> Option<Uuid> {
+        self.callback_url.strip_prefix(WEBSOCKET_CALLBACK_PREFIX)?.parse().ok()
+    }
 }
 
-/// An error that can occur when registering a worker.
-#[derive(Debug, Serialize)]
+/// A submitted job offered to a worker connected via WebSocket.
+/// The connection reports on `ack` once the worker acknowledged the job, or drops it if the job could not be sent.
+#[derive(Debug)]
+pub struct JobOffer {
+    pub job: Job,
+    pub ack: oneshot::Sender<()>,
+}
+
+/// The workers connected via WebSocket, keyed by the id of their connection.
+pub type WebSocketWorkers = HashMap<Uuid, mpsc::Sender<JobOffer>>;
+
+/// A message sent by a worker connected via WebSocket. See [`worker_websocket`].
+#[derive(Debug, Deserialize)]
+enum WebSocketWorkerMessage {
+    /// The worker is ready to receive a job.
+    Ready,
+    /// The worker accepted the job with the given id.
+    Ack(Uuid),
+}
+
+impl QueueItem for Worker {}
+
+/// A worker is identified by its callback URL, from which a UUID is derived,
+/// so that the same URL always yields the same id.
+impl Identifiable for Worker {
+    fn id(&self) -> Uuid {
+        Uuid::new_v5(&Uuid::NAMESPACE_URL, self.callback_url.as_bytes())
+    }
+}
+
+/// An error that can occur when registering a worker, or when checking the result callback URL of a submitted job.
+/// It is reported as the code of an [`ApiError`], and its description as the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Display)]
 pub enum CallbackHeaderError {
-    /// The CPEE-CALLBACK header was missing from the request.
+    /// The CPEE-CALLBACK header was missing from the request, and the body has no `callback_url` field.
+    #[display("The CPEE-CALLBACK header is missing, and the body has no callback_url field")]
     Missing,
-    /// The CPEE-CALLBACK header was not a valid string (HTTP headers can technically be any bytes).
+    /// The CPEE-CALLBACK header was not a valid string (HTTP headers can technically be any bytes),
+    /// or the `callback_url` field of the body was not a string.
+    #[display("The callback URL is not a string")]
     NotAString,
-    /// The CPEE-CALLBACK header was not a valid URL.
+    /// The callback URL was not a valid URL.
+    #[display("The callback URL is not a valid URL")]
     NotAUrl,
+    /// The scheme of the callback URL is not one of the allowed callback schemes.
+    #[display("The scheme of the callback URL is not allowed")]
+    UnsupportedScheme,
+    /// The host of the callback URL is denied, or not among the allowed callback hosts.
+    #[display("The host of the callback URL is not allowed")]
+    HostNotAllowed,
+    /// The callback URL points at the service itself, which would make the service send jobs to its own endpoints.
+    #[display("The callback URL points at the service itself")]
+    PointsToService,
+    /// The callback URL is longer than the maximum length, see `--max-callback-url-length`.
+    #[display("The callback URL is too long")]
+    TooLong,
+    /// The CPEE-SLOTS header was not a positive integer.
+    #[display("The CPEE-SLOTS header is not a positive integer")]
+    InvalidSlots,
+    /// The CPEE-MAX-PAYLOAD header was not a non-negative integer.
+    #[display("The CPEE-MAX-PAYLOAD header is not a non-negative integer")]
+    InvalidMaxPayload,
 }
 
 /// The response to a worker registration request.
@@ -46,59 +191,918 @@ pub enum CallbackHeaderError {
 pub enum RegisterWorkerResponse {
     /// No jobs were available and the worker was queued.
     Queued,
-    /// A queued job was immediately available and returned.
-    Job(Job),
-    /// An error occurred when processing the request.
-    Error(CallbackHeaderError),
-}
-
-/// Attempts to extract the callback URL from the request headers.
-fn extract_callback_header(request: &Request) -> Result<Url, CallbackHeaderError> {
-    request.headers()
-        .get("cpee-callback").ok_or_else(|| {
-            error!("Worker registration failed: CPEE-CALLBACK header was missing");
-            CallbackHeaderError::Missing
+    /// A queued job was immediately available, or was queued while the worker waited, and is returned.
+    Job(Box<Job>),
+    /// The worker waited for a job without a callback URL, and no job was queued in the meantime.
+    /// The worker was not queued, and should register again.
+    NoJobAvailable,
+}
+
+/// The query parameters of a worker registration. See [`register_worker`].
+#[derive(Debug, Deserialize)]
+pub struct RegisterWorkerQuery {
+    /// The number of seconds to wait for a job to be queued if no job is immediately available.
+    pub wait: Option<u64>,
+}
+
+/// Extracts the worker's tags from the comma-separated CPEE-TAGS header.
+/// If the header is missing or not a valid string, the worker has no tags.
+fn extract_tags_header(headers: &HeaderMap) -> Vec<String> {
+    extract_list_header(headers, "cpee-tags")
+}
+
+/// Extracts the topics the worker accepts jobs of from the comma-separated CPEE-TOPICS header, in the order of its preference.
+/// Topics listed more than once only count at their first position. If the header is missing, the worker lists no topics.
+/// Returns an error naming the first invalid topic, if any.
+fn extract_topics_header(headers: &HeaderMap) -> Result<Vec<String>, ApiError> {
+    let mut topics: Vec<String> = Vec::new();
+    for topic in extract_list_header(headers, "cpee-topics") {
+        if !topic::is_valid_topic(&topic) {
+            return Err(topic::invalid_topic(&topic));
+        }
+        if !topics.contains(&topic) {
+            topics.push(topic);
+        }
+    }
+    Ok(topics)
+}
+
+/// Returns the non-empty elements of the comma-separated header with the given name.
+/// If the header is missing or not a valid string, no elements are returned.
+fn extract_list_header(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get(name)
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.split(',').map(str::trim).filter(|element| !element.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// The response to a worker heartbeat request.
+#[derive(Debug, Serialize)]
+pub enum WorkerHeartbeatResponse {
+    /// The queued worker was found and its last seen time was refreshed.
+    Refreshed,
+}
+
+/// The response to a worker deregistration request.
+#[derive(Debug, Serialize)]
+pub enum DeregisterWorkerResponse {
+    /// The worker was removed from the worker queue, and the jobs in-flight at the worker were dispatched again.
+    Deregistered,
+}
+
+/// Extracts the number of jobs the worker can take on concurrently from the CPEE-SLOTS header.
+/// If the header is missing, the worker has a single slot.
+fn extract_slots_header(headers: &HeaderMap) -> Result<u32, CallbackHeaderError> {
+    let Some(header) = headers.get("cpee-slots") else {
+        return Ok(one_slot());
+    };
+    header.to_str().ok()
+        .and_then(|header| header.trim().parse().ok())
+        .filter(|&slots| slots > 0)
+        .ok_or_else(|| {
+            error!("Invalid worker request: CPEE-SLOTS header was not a positive integer");
+            CallbackHeaderError::InvalidSlots
+        })
+}
+
+/// Extracts the size in bytes of the largest job the worker accepts from the CPEE-MAX-PAYLOAD header.
+/// If the header is missing, the worker accepts jobs of any size.
+fn extract_max_payload_header(headers: &HeaderMap) -> Result<Option<u64>, CallbackHeaderError> {
+    let Some(header) = headers.get("cpee-max-payload") else {
+        return Ok(None);
+    };
+    header.to_str().ok()
+        .and_then(|header| header.trim().parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            error!("Invalid worker request: CPEE-MAX-PAYLOAD header was not a non-negative integer");
+            CallbackHeaderError::InvalidMaxPayload
         })
-        .and_then(|header| header.to_str().map_err(|err| {
-            error!("Worker registration failed: CPEE-CALLBACK header was not a valid string: {err}");
+}
+
+/// Attempts to extract the callback URL from the CPEE-CALLBACK header of the request, or if the header is missing,
+/// from the `callback_url` field of a JSON object in the request body, for clients which cannot easily set headers.
+/// The header takes precedence over the body, which is only read if the header is missing.
+/// The URL is not checked yet, see [`check_callback_url`].
+async fn extract_callback_url(request: &mut Request) -> Result<String, CallbackHeaderError> {
+    match request.headers().get("cpee-callback") {
+        Some(header) => header.to_str().map(str::to_owned).map_err(|err| {
+            error!("Invalid worker request: CPEE-CALLBACK header was not a valid string: {err}");
             CallbackHeaderError::NotAString
-        }))
-        .and_then(|header| Url::parse(header).map_err(|err| {
-            error!("Worker registration failed: CPEE-CALLBACK header was not a valid URL: {err}");
-            CallbackHeaderError::NotAUrl
-        }))
+        }),
+        None => extract_callback_body(request).await,
+    }
+}
+
+/// Parses a URL to which the service would send requests, i.e. the callback URL of a worker or the result callback URL of a job.
+/// The URL must be at most `max_length` bytes long, be a valid URL, and use one of the given schemes.
+/// Its host is not checked, see [`check_callback_url`].
+fn parse_callback_url(callback_url: &str, schemes: &[String], max_length: usize) -> Result<Url, CallbackHeaderError> {
+    // Checked before parsing, since the URL is persisted with every change to the queue holding it
+    if callback_url.len() > max_length {
+        error!("Invalid callback URL: it is {} bytes long, longer than the maximum of {max_length} bytes", callback_url.len());
+        return Err(CallbackHeaderError::TooLong);
+    }
+    let url = Url::parse(callback_url).map_err(|err| {
+        error!("Invalid callback URL: {err}");
+        CallbackHeaderError::NotAUrl
+    })?;
+    if schemes.iter().any(|scheme| scheme.eq_ignore_ascii_case(url.scheme())) {
+        return Ok(url);
+    }
+    error!("Invalid callback URL: the scheme '{}' is not allowed", url.scheme());
+    Err(CallbackHeaderError::UnsupportedScheme)
+}
+
+/// Checks a URL to which the service would send requests like [`parse_callback_url`] with `--callback-schemes`
+/// and `--max-callback-url-length`. In addition, its host must be allowed by the [`CallbackFilter`](crate::callback_filter::CallbackFilter),
+/// and it must not point at the service itself, since requests sent to it would end up at the service's own endpoints.
+/// Returns the parsed URL, or the reason why it was rejected.
+pub async fn check_callback_url(state: &AppState, callback_url: &str) -> Result<Url, CallbackHeaderError> {
+    let url = parse_callback_url(callback_url, &state.callback_schemes, state.max_callback_url_length)?;
+    if !state.callback_filter.allows(&url).await {
+        error!(callback_url, "Invalid callback URL: the host is not allowed");
+        return Err(CallbackHeaderError::HostNotAllowed);
+    }
+    if state.callback_filter.points_to_service(&url).await {
+        warn!(callback_url, "Invalid callback URL: it points at the service itself");
+        return Err(CallbackHeaderError::PointsToService);
+    }
+    Ok(url)
+}
+
+/// Reads the `callback_url` field of the JSON object in the request body, see [`extract_callback_url`].
+/// A body which is empty, larger than [`MAX_CALLBACK_BODY_SIZE`], or not a JSON object with that field counts as missing.
+async fn extract_callback_body(request: &mut Request) -> Result<String, CallbackHeaderError> {
+    let body = to_bytes(mem::take(request.body_mut()), MAX_CALLBACK_BODY_SIZE).await.unwrap_or_default();
+    let field = serde_json::from_slice::<Value>(&body).ok()
+        .and_then(|mut body| body.get_mut("callback_url").map(Value::take));
+    match field {
+        Some(Value::String(callback_url)) => Ok(callback_url),
+        Some(_) => {
+            error!("Invalid worker request: the callback_url field of the body was not a string");
+            Err(CallbackHeaderError::NotAString)
+        },
+        None => {
+            error!("Invalid worker request: CPEE-CALLBACK header was missing, and the body has no callback_url field");
+            Err(CallbackHeaderError::Missing)
+        },
+    }
 }
 
 /// POST /register-worker
 /// Tells the server that a worker is ready to receive a job.
 ///
 /// The worker must provide a CPEE-CALLBACK header with a valid URL in case there are no jobs
-/// immediately available. If the header is missing, not a string, or not a valid URL,
+/// immediately available. Clients which cannot easily set headers may instead send a JSON object with a `callback_url` field
+/// as the body; the header takes precedence. If the URL is missing, not a string, longer than `--max-callback-url-length`,
+/// not a valid URL, or a URL whose scheme is not allowed (only `http` and `https` by default),
 /// the request is rejected with a 400 Bad Request status and an error message.
+/// The same applies if the host of the URL is denied or not among the allowed hosts, see [`CallbackFilter`](crate::callback_filter::CallbackFilter),
+/// and if the URL points at the service itself, since jobs sent to it would be submitted to the service's own endpoints.
+///
+/// The worker may provide a comma-separated list of tags in a CPEE-TAGS header.
+/// Only jobs whose required tags are all among the worker's tags are assigned to it.
 ///
-/// If a queued job is immediately available, it is returned with a 200 OK status.
+/// The worker may provide the number of jobs it can process concurrently in a CPEE-SLOTS header (default: 1).
+/// The worker then stays queued until a job was assigned to each of its slots; jobs for all but the first slot
+/// are sent to the callback URL. If the header is not a positive integer, the request is rejected with 400 Bad Request.
+///
+/// Instead of registering for a single topic, the worker may provide a comma-separated list of topics in a CPEE-TOPICS header,
+/// ordered by preference. The job queues of these topics are then looked through in that order, and the first suitable job is assigned.
+/// If there is none, the worker is queued in the worker queues of all of these topics; once one of them assigns it a job,
+/// it is removed from the others (see [`topic::release_other_topics`]). Such a worker does not take jobs submitted without a topic.
+/// If a listed topic name is invalid, the request is rejected with 400 Bad Request and "InvalidTopic".
+/// The header is ignored if the worker registers for a topic given in the path.
+///
+/// If a suitable queued job is immediately available, it is returned with a 200 OK status.
 /// If no jobs are immediately available, the worker is queued and a 202 Accepted status is returned
 /// with the CPEE-CALLBACK header set to true. This indicates that the job will be sent to the worker
-/// at a later time using the provided callback URL.
+/// at a later time using the provided callback URL. A worker whose callback URL is already queued
+/// is not queued again; instead, its registration time is refreshed.
+///
+/// If the `wait=<seconds>` query parameter is given (at most 60), the request is held open for up to that long
+/// if no suitable job is immediately available, and the first suitable job which is queued in the meantime is returned
+/// with a 200 OK status. Only once the time has elapsed is the worker queued as described above.
+/// In this mode, the callback URL may be omitted by workers which cannot receive jobs at a callback URL;
+/// such a worker is never queued, and receives a 202 Accepted status with "NoJobAvailable" once the time has elapsed.
+/// While waiting, the worker is not queued, so submitted jobs are only queued for it if no queued worker accepts them.
+///
+/// If the worker would have to be queued but the worker queue is full, a 503 Service Unavailable status
+/// is returned with "QueueFull", and the worker is not queued.
+/// If the job or worker queue could not be persisted, a 500 Internal Server Error status is returned.
 #[rustfmt::skip]
 pub async fn register_worker(
     State(state): State<AppState>,
-    request: Request
-) -> Response {
-    let callback_url = match extract_callback_header(&request) {
-        Ok(callback_url) => callback_url,
+    Query(query): Query<RegisterWorkerQuery>,
+    mut request: Request
+) -> Result<Response, ApiError> {
+    let callback_url = match extract_callback_url(&mut request).await {
+        Ok(callback_url) => Some(check_callback_url(&state, &callback_url).await?),
+        // A worker which waits for a job receives it in the response, so it does not need a callback URL
+        Err(CallbackHeaderError::Missing) if query.wait.is_some() => None,
+        Err(err) => return Err(err.into()),
+    };
+    let slots = extract_slots_header(request.headers())?;
+    let max_payload = extract_max_payload_header(request.headers())?;
+    // A worker registering for a topic in the path only takes jobs of that topic
+    let topics = match state.topic {
+        Some(_) => Vec::new(),
+        None => extract_topics_header(request.headers())?,
+    };
+    let callback_url = callback_url.as_ref().map_or(LONG_POLL_CALLBACK_URL, Url::as_str);
+    let worker = Worker::new(callback_url, extract_tags_header(request.headers()))
+        .with_slots(slots)
+        .with_max_payload(max_payload)
+        .with_topics(topics);
+    counter!(telemetry::WORKER_REGISTRATIONS).increment(1);
+    info!(%callback_url, slots, max_payload, topics = ?worker.topics, wait = query.wait, "Worker registration received");
+    let states = state.for_worker(&worker).await;
+    let assigned = match query.wait {
+        Some(wait) => wait_for_job(&state, &states, &worker, Duration::from_secs(wait).min(MAX_REGISTER_WAIT)).await,
+        None => assign_preferred_job(&states, &worker).await,
+    };
+    let assigned = assigned?;
+    if callback_url == LONG_POLL_CALLBACK_URL {
+        return Ok(match assigned {
+            Some(job) => (StatusCode::OK, Json(RegisterWorkerResponse::Job(Box::new(job)))).into_response(),
+            None => {
+                info!("No job was queued while the worker waited, and it has no callback URL to be queued with");
+                (StatusCode::ACCEPTED, Json(RegisterWorkerResponse::NoJobAvailable)).into_response()
+            },
+        });
+    }
+    // The slot which receives the assigned job is no longer available
+    let remaining = if assigned.is_some() { slots - 1 } else { slots };
+    if remaining > 0 {
+        match queue_worker_in_all(&states, worker.clone().with_slots(remaining)).await {
+            Ok(()) => {},
+            // A worker with a job to process does not need to learn that its other slots could not be queued
+            Err(_) if assigned.is_some() => {},
+            Err(QueueError::Full) => {
+                return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, ErrorCode::QueueFull, "The worker queue is full"));
+            },
+            Err(err) => return Err(err.into()),
+        }
+        // Further queued jobs which the other slots could take on right away are dispatched to them in the background
+        if assigned.is_some() {
+            tokio::spawn(job::dispatch_queued_jobs(states, worker, remaining));
+        }
+    }
+    Ok(match assigned {
+        Some(job) => (StatusCode::OK, Json(RegisterWorkerResponse::Job(Box::new(job)))).into_response(),
+        // Set the cpee-callback header to true to indicate that the job will be returned asynchronously
+        None => (StatusCode::ACCEPTED, [("cpee-callback", "true")], Json(RegisterWorkerResponse::Queued)).into_response(),
+    })
+}
+
+/// Removes the first queued job which the worker can process from the job queue and assigns it to the worker.
+/// Returns the job, or None if there is no such job.
+/// Returns an error if the job queue could not be read, or if the job could not be tracked as assigned,
+/// in which case the job is put back into the job queue.
+async fn assign_queued_job(state: &AppState, worker: &Worker) -> QueueResult<Option<Job>> {
+    let callback_url = &worker.callback_url;
+    let mut job = match job::dequeue_for_worker(state, worker).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return Ok(None),
         Err(err) => {
-            return (StatusCode::BAD_REQUEST, Json(RegisterWorkerResponse::Error(err))).into_response();
+            error!(%callback_url, "Failed to dequeue from job queue: '{err}'");
+            return Err(err);
+        },
+    };
+    if let Err(err) = job.track_assignment(state, callback_url).await {
+        error!(job_id = %job.id, %callback_url, "Failed to persist job to assigned jobs: '{err}'");
+        return_queued_job(state, job).await;
+        return Err(err);
+    }
+    let queue_time = job.age();
+    histogram!(telemetry::JOB_QUEUE_TIME).record(queue_time.num_milliseconds() as f64 / 1000.0);
+    counter!(telemetry::JOBS_ASSIGNED).increment(1);
+    state.stats.record_job_queue_time(queue_time.num_milliseconds().max(0) as u64);
+    Stats::count(&state.stats.jobs_assigned, 1);
+    events::publish(state, JobEvent::Assigned { id: job.id, callback_url: callback_url.clone() });
+    job.record_state(state, JobState::Assigned).await;
+    let queue_time = queue_time.num_seconds();
+    info!(job_id = %job.id, %callback_url, queue_time_secs = queue_time, "Assigning queued job to worker...");
+    Ok(Some(job))
+}
+
+/// Puts a job which was dequeued for a worker but could not be assigned to it back into the job queue, so that it is not lost.
+/// If the job queue cannot take it, the job is scheduled instead, so that it is dispatched again once the scheduled jobs are checked.
+/// If neither can take it, the job is lost, which is logged along with its id and recorded as its state.
+pub async fn return_queued_job(state: &AppState, mut job: Job) {
+    let job_id = job.id;
+    job.assigned_at = None;
+    job.assigned_to = None;
+    let err = match state.job_queue.lock().await.enqueue(job.clone()).await {
+        Ok(_) => return job.record_state(state, JobState::Queued).await,
+        Err(err) => err,
+    };
+    error!(%job_id, "Failed to put job back into the job queue: '{err}', scheduling it instead...");
+    match state.scheduled_jobs.lock().await.enqueue(job.clone()).await {
+        Ok(_) => job.record_state(state, JobState::Scheduled).await,
+        Err(err) => {
+            error!(%job_id, "Failed to persist job to scheduled jobs: '{err}', the job is lost");
+            job.record_state(state, JobState::Failed).await;
+        },
+    }
+}
+
+/// Assigns the first suitable job in the job queues of the given states to the worker like [`assign_queued_job`],
+/// looking through the queues in the given order, i.e. that of the worker's preferred topics.
+/// Returns None if no queue holds such a job.
+async fn assign_preferred_job(states: &[AppState], worker: &Worker) -> QueueResult<Option<Job>> {
+    for state in states {
+        if let Some(job) = assign_queued_job(state, worker).await? {
+            return Ok(Some(job));
+        }
+    }
+    Ok(None)
+}
+
+/// Assigns the first suitable queued job to the worker like [`assign_preferred_job`], and if there is none, tries again
+/// whenever a job is queued, until a job is found or the given time has elapsed.
+/// Returns None if no suitable job was queued in time.
+async fn wait_for_job(state: &AppState, states: &[AppState], worker: &Worker, wait: Duration) -> QueueResult<Option<Job>> {
+    let deadline = Instant::now() + wait;
+    loop {
+        // Listen before looking for a job, so that a job which is queued in between is not missed
+        let mut job_queued = pin!(state.job_queued.notified());
+        job_queued.as_mut().enable();
+        if let Some(job) = assign_preferred_job(states, worker).await? {
+            return Ok(Some(job));
+        }
+        if timeout_at(state.next_poll(deadline), job_queued).await.is_err() && Instant::now() >= deadline {
+            info!(callback_url = %worker.callback_url, "No job was queued within {wait:?}");
+            return Ok(None);
+        }
+    }
+}
+
+/// Queues a worker for which no job is available in the worker queues of all given states, like [`queue_worker`].
+/// If it cannot be queued in one of them, it is removed from those it was already queued in, and the error is returned.
+async fn queue_worker_in_all(states: &[AppState], worker: Worker) -> QueueResult<()> {
+    for (index, state) in states.iter().enumerate() {
+        let Err(err) = queue_worker(state, worker.clone()).await else {
+            continue;
+        };
+        for state in &states[..index] {
+            let removed = state.worker_queue.lock().await.retain(&|queued: &Worker| queued.callback_url != worker.callback_url).await;
+            if let Err(err) = removed {
+                error!(callback_url = %worker.callback_url, "Failed to remove worker from worker queue: '{err}'");
+            }
         }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Queues a worker for which no job is available.
+/// If the worker is already queued (e.g. it retried after a timeout), it is refreshed instead of being queued twice.
+/// Returns an error if the worker queue could not be persisted.
+async fn queue_worker(state: &AppState, worker: Worker) -> QueueResult<()> {
+    let callback_url = worker.callback_url.clone();
+    let mut worker_queue = state.worker_queue.lock().await;
+    let refreshed = worker_queue.update(&|queued: &mut Worker| {
+        if queued.callback_url != worker.callback_url {
+            return false;
+        }
+        queued.registered_at = worker.registered_at;
+        queued.last_seen = worker.last_seen;
+        queued.tags.clone_from(&worker.tags);
+        queued.slots = worker.slots;
+        queued.max_payload = worker.max_payload;
+        queued.topics.clone_from(&worker.topics);
+        true
+    }).await;
+    let persisted = match refreshed {
+        Ok(0) => {
+            info!(%callback_url, "No jobs available, queueing worker...");
+            worker_queue.enqueue(worker).await.map(|_| ())
+        },
+        Ok(_) => {
+            info!(%callback_url, "No jobs available, worker is already queued");
+            Ok(())
+        },
+        Err(err) => Err(err),
     };
-    if let Some(job) = state.job_queue.lock().await.dequeue().await {
-        let queue_time = Utc::now().signed_duration_since(job.submitted_at).num_seconds();
-        info!("Worker registration received ({callback_url}). Assigning job... (was queued for {queue_time}s)");
-        (StatusCode::OK, Json(RegisterWorkerResponse::Job(job))).into_response()
-    } else {
-        // Set the cpee-callback header to true to indicate that the job will be returned asynchronously
-        info!("Worker registration received ({callback_url}). No jobs available, queuing...");
-        state.worker_queue.lock().await.enqueue(Worker::new(callback_url)).await;
-        (StatusCode::ACCEPTED, [("cpee-callback", "true")], Json(RegisterWorkerResponse::Queued)).into_response()
+    drop(worker_queue);
+    // Wake the submissions which are waiting for a worker, see [`job::submit_job`]
+    if persisted.is_ok() {
+        state.worker_queued.notify_waiters();
+    }
+    persisted.inspect_err(|err| match err {
+        QueueError::Full => info!(%callback_url, "Worker queue is full, rejecting worker..."),
+        _ => error!(%callback_url, "Failed to persist worker to worker queue: '{err}'"),
+    })
+}
+
+/// GET /worker-ws
+/// Connects a worker via WebSocket, so that it receives jobs as messages instead of at a callback URL.
+///
+/// The worker may provide a comma-separated list of tags in a CPEE-TAGS header, like in [`register_worker`].
+/// Upon connecting, the worker is ready to receive a job: a suitable queued job is sent to it right away,
+/// or the worker is queued like a worker with a callback URL, and participates in the dispatch of submitted jobs.
+/// Every job is sent as `{"Job": <job>}`, and the worker must acknowledge it with `{"Ack": "<job id>"}`.
+/// A submitted job which is not acknowledged within the callback timeout is offered to the next worker.
+/// After processing a job, the worker sends `"Ready"` to receive the next one.
+///
+/// While the worker is queued, WebSocket pings refresh its last seen time, like heartbeats.
+/// When the connection closes, the worker is removed from the worker queue.
+#[rustfmt::skip]
+pub async fn worker_websocket(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade
+) -> Response {
+    let tags = extract_tags_header(&headers);
+    upgrade.on_upgrade(move |socket| serve_websocket_worker(state, socket, tags))
+}
+
+/// Serves a worker connected via WebSocket until the connection closes. See [`worker_websocket`].
+async fn serve_websocket_worker(state: AppState, mut socket: WebSocket, tags: Vec<String>) {
+    let connection = Uuid::new_v4();
+    let callback_url = format!("{WEBSOCKET_CALLBACK_PREFIX}{connection}");
+    let (sender, mut offers) = mpsc::channel(1);
+    state.websocket_workers.lock().await.insert(connection, sender);
+    counter!(telemetry::WORKER_REGISTRATIONS).increment(1);
+    info!(%callback_url, "Worker connected via WebSocket");
+    // The offer of a submitted job which was sent to the worker, but not acknowledged yet
+    let mut pending: Option<JobOffer> = None;
+    let mut ready = true;
+    loop {
+        if ready {
+            ready = false;
+            let worker = Worker::new(callback_url.clone(), tags.clone());
+            match assign_queued_job(&state, &worker).await {
+                Ok(Some(mut job)) => {
+                    if let Err(err) = send_job_message(&mut socket, &job).await {
+                        error!(job_id = %job.id, %callback_url, "Failed to send job to worker: '{err}', queueing job again...");
+                        job.untrack_assignment(&state).await;
+                        if state.job_queue.lock().await.enqueue(job.clone()).await.is_ok() {
+                            job.record_state(&state, JobState::Queued).await;
+                        }
+                        break;
+                    }
+                },
+                Ok(None) => {
+                    if queue_worker(&state, worker).await.is_err() {
+                        break;
+                    }
+                },
+                Err(_) => break,
+            }
+        }
+        tokio::select! {
+            Some(offer) = offers.recv() => {
+                if let Err(err) = send_job_message(&mut socket, &offer.job).await {
+                    // Dropping the offer tells the dispatcher that the worker did not accept the job
+                    error!(job_id = %offer.job.id, %callback_url, "Failed to send job to worker: '{err}'");
+                    break;
+                }
+                pending = Some(offer);
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(WebSocketWorkerMessage::Ready) => ready = true,
+                    Ok(WebSocketWorkerMessage::Ack(id)) => {
+                        if let Some(offer) = pending.take_if(|offer| offer.job.id == id) {
+                            let _ = offer.ack.send(());
+                        }
+                    },
+                    Err(err) => warn!(%callback_url, "Ignoring unreadable message from worker: {err}"),
+                },
+                Some(Ok(Message::Ping(_))) => {
+                    let now = Utc::now();
+                    let refreshed = state.worker_queue.lock().await.update(&|worker: &mut Worker| {
+                        if worker.callback_url != callback_url {
+                            return false;
+                        }
+                        worker.last_seen = now;
+                        true
+                    }).await;
+                    if let Err(err) = refreshed {
+                        error!(%callback_url, "Failed to persist worker heartbeat: '{err}'");
+                    }
+                },
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+    state.websocket_workers.lock().await.remove(&connection);
+    if let Err(err) = state.worker_queue.lock().await.retain(&|worker: &Worker| worker.callback_url != callback_url).await {
+        error!(%callback_url, "Failed to remove disconnected worker from worker queue: '{err}'");
+    }
+    info!(%callback_url, "Worker disconnected from WebSocket");
+}
+
+/// Sends a job to a worker connected via WebSocket, wrapped like the jobs sent to callback URLs.
+/// # Panics
+/// This function panics if the serialization impl for Job fails.
+async fn send_job_message(socket: &mut WebSocket, job: &Job) -> Result<(), axum::Error> {
+    let message = serde_json::to_string(&AsynchronousWorkerResponse::Job(job)).unwrap();
+    socket.send(Message::text(message)).await
+}
+
+/// POST /worker-heartbeat
+/// Tells the server that a queued worker is still alive, so that it is not evicted.
+///
+/// The worker is identified by the callback URL it registered with, given in the CPEE-CALLBACK header
+/// or in the `callback_url` field of a JSON body like for [`register_worker`].
+/// If the URL is missing, not a string, not a valid URL, or a URL whose scheme is not allowed,
+/// the request is rejected with a 400 Bad Request status and an error message.
+///
+/// If a queued worker with the given callback URL exists, its last seen time is refreshed and a 200 OK status is returned.
+/// The worker is looked for in the worker queues of all topics as well, so that workers which registered for one or several topics
+/// are refreshed in each of them. Otherwise, a 404 Not Found status is returned, and the worker should register again.
+/// If a worker queue could not be persisted, a 500 Internal Server Error status is returned.
+#[rustfmt::skip]
+pub async fn worker_heartbeat(
+    State(state): State<AppState>,
+    mut request: Request
+) -> Result<(StatusCode, Json<WorkerHeartbeatResponse>), ApiError> {
+    let callback_url = extract_callback_url(&mut request).await?;
+    let callback_url = parse_callback_url(&callback_url, &state.callback_schemes, state.max_callback_url_length)?.to_string();
+    let now = Utc::now();
+    let mut refreshed = 0;
+    for state in std::iter::once(state.clone()).chain(state.topic_states().await) {
+        let updated = state.worker_queue.lock().await.update(&|worker: &mut Worker| {
+            if worker.callback_url != callback_url {
+                return false;
+            }
+            worker.last_seen = now;
+            true
+        }).await;
+        match updated {
+            Ok(updated) => refreshed += updated,
+            Err(err) => {
+                error!(topic = state.topic.as_deref(), "Failed to persist worker heartbeat: '{err}'");
+                return Err(err.into());
+            },
+        }
+    }
+    if refreshed == 0 {
+        info!("Heartbeat received from unknown worker ({callback_url})");
+        return Err(ApiError::not_found("No worker with the callback URL is queued"));
+    }
+    Ok((StatusCode::OK, Json(WorkerHeartbeatResponse::Refreshed)))
+}
+
+/// POST /deregister-worker
+/// Tells the server that a queued worker is shutting down, so that no further jobs are dispatched to it.
+///
+/// The worker is identified by the callback URL it registered with, given in the CPEE-CALLBACK header
+/// or in the `callback_url` field of a JSON body like for [`register_worker`].
+/// If the URL is missing, not a string, not a valid URL, or a URL whose scheme is not allowed,
+/// the request is rejected with a 400 Bad Request status and an error message.
+///
+/// If a queued worker with the given callback URL exists, it is removed from the worker queue along with all of its slots,
+/// and from the worker queues of all topics, i.e. of the topic it registered for or of every topic it listed in its CPEE-TOPICS header.
+/// The jobs which were assigned to the worker and whose results it has not reported yet are dispatched again right away,
+/// instead of after the visibility timeout, i.e. they are assigned to another worker or queued.
+/// If the worker was queued or had jobs in-flight, a 200 OK status is returned. Otherwise, a 404 Not Found status is returned.
+/// If a worker queue or the assigned jobs could not be persisted, a 500 Internal Server Error status is returned.
+#[rustfmt::skip]
+pub async fn deregister_worker(
+    State(state): State<AppState>,
+    mut request: Request
+) -> Result<(StatusCode, Json<DeregisterWorkerResponse>), ApiError> {
+    let callback_url = extract_callback_url(&mut request).await?;
+    let callback_url = parse_callback_url(&callback_url, &state.callback_schemes, state.max_callback_url_length)?.to_string();
+    // The worker is removed first, so that its in-flight jobs are not dispatched to it again
+    let mut removed = 0;
+    for state in std::iter::once(state.clone()).chain(state.topic_states().await) {
+        match state.worker_queue.lock().await.retain(&|worker: &Worker| worker.callback_url != callback_url).await {
+            Ok(count) => removed += count,
+            Err(err) => {
+                error!(%callback_url, topic = state.topic.as_deref(), "Failed to remove worker from worker queue: '{err}'");
+                return Err(err.into());
+            },
+        }
+    }
+    let in_flight = match job::requeue_jobs_of_worker(&state, &callback_url).await {
+        Ok(in_flight) => in_flight,
+        Err(err) => {
+            error!(%callback_url, "Failed to read assigned jobs: '{err}'");
+            return Err(err.into());
+        },
+    };
+    if removed == 0 && in_flight == 0 {
+        info!("Deregistration received from unknown worker ({callback_url})");
+        return Err(ApiError::not_found("No worker with the callback URL is queued or has jobs in-flight"));
+    }
+    info!(%callback_url, in_flight_jobs = in_flight, "Worker deregistered");
+    Ok((StatusCode::OK, Json(DeregisterWorkerResponse::Deregistered)))
+}
+
+/// GET /workers
+/// Lists the queued workers in the order in which they will be assigned jobs.
+/// The optional `limit` query parameter caps the number of returned workers.
+/// If the worker queue could not be read, this endpoint responds with 500 Internal Server Error.
+#[rustfmt::skip]
+pub async fn list_workers(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>
+) -> Result<Json<Vec<Worker>>, ApiError> {
+    state.worker_queue.lock().await.to_vec(query.limit).await.map(Json).map_err(|err| {
+        error!("Failed to read worker queue: '{err}'");
+        err.into()
+    })
+}
+
+/// DELETE /workers
+/// Removes all workers from the worker queue. They must register again to be assigned jobs.
+/// Responds with 200 OK and "Cleared" along with the number of removed workers,
+/// or with 500 Internal Server Error and "PersistenceFailed" if the worker queue could not be persisted.
+pub async fn clear_workers(State(state): State<AppState>) -> Result<(StatusCode, Json<ClearQueueResponse>), ApiError> {
+    match state.worker_queue.lock().await.clear().await {
+        Ok(removed) => {
+            info!("Worker queue cleared, removed {removed} worker(s)");
+            Ok((StatusCode::OK, Json(ClearQueueResponse::Cleared { removed })))
+        },
+        Err(err) => {
+            error!("Failed to clear worker queue: '{err}'");
+            Err(err.into())
+        },
+    }
+}
+
+/// Removes all workers from the worker queues of all topics which have not been seen for longer than the given time-to-live.
+/// Runs forever, checking once per time-to-live.
+pub async fn evict_stale_workers(state: AppState, ttl: Duration) {
+    let mut ticker = tokio::time::interval(ttl);
+    loop {
+        ticker.tick().await;
+        for state in std::iter::once(state.clone()).chain(state.topic_states().await) {
+            match state.worker_queue.lock().await.retain(&|worker: &Worker| !worker.is_expired(ttl)).await {
+                Ok(0) => {},
+                Ok(evicted) => info!(topic = state.topic.as_deref(), "Evicted {evicted} stale worker(s) which were not seen for longer than {ttl:?}"),
+                Err(err) => error!(topic = state.topic.as_deref(), "Failed to evict stale workers: '{err}'"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callback_filter::CallbackFilter;
+    use crate::queue::{BoundedQueue, InMemoryQueue, JsonFileQueue, Queue};
+    use axum::body::Body;
+    use chrono::TimeDelta;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio::sync::Mutex;
+
+    #[test]
+    fn worker_id_is_derived_from_the_callback_url() {
+        let worker = Worker::new("http://localhost:9000/jobs", vec![]);
+        let registered_again = Worker::new("http://localhost:9000/jobs", vec!["gpu".into()]);
+        assert_eq!(worker.id(), registered_again.id());
+        assert_ne!(worker.id(), Worker::new("http://localhost:9001/jobs", vec![]).id());
+    }
+
+    #[tokio::test]
+    async fn finds_and_removes_workers_by_id() {
+        let dir = TempDir::new().unwrap();
+        let queues: [Queue<Worker>; 2] = [Box::new(InMemoryQueue::new()), Box::new(JsonFileQueue::new(dir.path().join("workers.json")))];
+        for mut queue in queues {
+            let urls = ["http://localhost:9000/", "http://localhost:9001/", "http://localhost:9002/"];
+            queue.enqueue_many(urls.iter().map(|url| Worker::new(*url, vec![])).collect()).await.unwrap();
+
+            let id = Worker::new(urls[1], vec![]).id();
+            assert_eq!(queue.find_by_id(id).await.unwrap().unwrap().callback_url, urls[1]);
+            assert_eq!(queue.remove_by_id(id).await.unwrap().unwrap().callback_url, urls[1]);
+            assert!(queue.find_by_id(id).await.unwrap().is_none());
+            assert!(queue.remove_by_id(id).await.unwrap().is_none());
+            assert_eq!(queue.len().await.unwrap(), 2);
+        }
+    }
+
+    /// Returns a queue which is always full, so that every enqueue fails.
+    fn full_queue<T: QueueItem>() -> Arc<Mutex<Queue<T>>> {
+        Arc::new(Mutex::new(Box::new(BoundedQueue::new(Box::new(InMemoryQueue::new()), 0))))
+    }
+
+    /// Returns a job as it was dequeued and tracked for the given worker, before tracking it failed.
+    fn assigned_job(worker: &Worker) -> Job {
+        let mut job = Job::new(serde_json::json!({ "drink": "mojito" }));
+        job.assigned_at = Some(Utc::now());
+        job.assigned_to = Some(worker.callback_url.clone());
+        job
+    }
+
+    /// Returns the recorded state of the given job.
+    async fn job_state(state: &AppState, job: &Job) -> Option<JobState> {
+        state.job_statuses.lock().await.get(&job.id).map(|status| status.state)
+    }
+
+    #[tokio::test]
+    async fn queued_job_is_kept_if_its_assignment_cannot_be_tracked() {
+        let mut state = crate::tests::state();
+        state.assigned_jobs = full_queue();
+        let worker = Worker::new("http://localhost:9000/", vec![]);
+        let job = Job::new(serde_json::json!({ "drink": "mojito" }));
+        state.job_queue.lock().await.enqueue(job.clone()).await.unwrap();
+
+        let result = assign_queued_job(&state, &worker).await;
+        assert!(matches!(result, Err(QueueError::Full)), "unexpected result {result:?}");
+        let queued = state.job_queue.lock().await.to_vec(None).await.unwrap();
+        assert_eq!(queued.iter().map(|job| job.id).collect::<Vec<_>>(), [job.id]);
+        assert!(queued[0].assigned_at.is_none() && queued[0].assigned_to.is_none());
+    }
+
+    #[tokio::test]
+    async fn unassignable_job_is_scheduled_if_the_job_queue_fails() {
+        let mut state = crate::tests::state();
+        state.job_queue = full_queue();
+        let job = assigned_job(&Worker::new("http://localhost:9000/", vec![]));
+        return_queued_job(&state, job.clone()).await;
+        let scheduled = state.scheduled_jobs.lock().await.to_vec(None).await.unwrap();
+        assert_eq!(scheduled.iter().map(|job| job.id).collect::<Vec<_>>(), [job.id]);
+        assert!(scheduled[0].is_ready() && scheduled[0].assigned_at.is_none());
+        assert_eq!(job_state(&state, &job).await, Some(JobState::Scheduled));
+    }
+
+    #[tokio::test]
+    async fn unassignable_job_is_reported_failed_if_it_cannot_be_kept() {
+        let mut state = crate::tests::state();
+        state.job_queue = full_queue();
+        state.scheduled_jobs = full_queue();
+        let job = assigned_job(&Worker::new("http://localhost:9000/", vec![]));
+        return_queued_job(&state, job.clone()).await;
+        assert_eq!(job_state(&state, &job).await, Some(JobState::Failed));
+    }
+
+    /// Returns a worker request with the given callback URL in the CPEE-CALLBACK header, and the given further headers.
+    fn worker_request(callback_url: &str, headers: &[(&str, &str)]) -> Request {
+        let mut request = Request::builder().header("cpee-callback", callback_url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn heartbeats_keep_workers_from_being_evicted() {
+        let state = crate::tests::state();
+        let long_ago = Utc::now() - TimeDelta::minutes(2);
+        let workers = ["http://localhost:9000/", "http://localhost:9001/"].map(|url| Worker { last_seen: long_ago, ..Worker::new(url, vec![]) });
+        state.worker_queue.lock().await.enqueue_many(workers.into()).await.unwrap();
+
+        let (status, _) = worker_heartbeat(State(state.clone()), worker_request("http://localhost:9000/", &[])).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let err = worker_heartbeat(State(state.clone()), worker_request("http://localhost:9002/", &[])).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        // The first check happens right away
+        let evictor = tokio::spawn(evict_stale_workers(state.clone(), Duration::from_secs(60)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        evictor.abort();
+        let queued = state.worker_queue.lock().await.to_vec(None).await.unwrap();
+        assert_eq!(queued.iter().map(|worker| worker.callback_url.as_str()).collect::<Vec<_>>(), ["http://localhost:9000/"]);
+        assert!(!queued[0].is_expired(Duration::from_secs(60)));
+    }
+
+    /// Returns the query of a registration which does not wait for a job.
+    fn register_query() -> Query<RegisterWorkerQuery> {
+        Query(RegisterWorkerQuery { wait: None })
+    }
+
+    #[tokio::test]
+    async fn workers_registering_again_are_refreshed_instead_of_queued_twice() {
+        let state = crate::tests::state();
+        let response = register_worker(State(state.clone()), register_query(), worker_request("http://localhost:9000/", &[])).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let registered_at = state.worker_queue.lock().await.peek().await.unwrap().unwrap().registered_at;
+
+        let request = worker_request("http://localhost:9000/", &[("cpee-tags", "gpu"), ("cpee-slots", "2")]);
+        let response = register_worker(State(state.clone()), register_query(), request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let queued = state.worker_queue.lock().await.to_vec(None).await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert!(queued[0].registered_at > registered_at);
+        assert_eq!(queued[0].tags, ["gpu"]);
+        assert_eq!(queued[0].slots, 2);
+
+        register_worker(State(state.clone()), register_query(), worker_request("http://localhost:9001/", &[])).await.unwrap();
+        assert_eq!(state.worker_queue.lock().await.len().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn workers_are_assigned_the_queued_jobs_they_have_the_tags_for() {
+        let state = crate::tests::state();
+        let gpu_job = Job::new(serde_json::json!({ "drink": "mojito", "required_tags": ["gpu"] }));
+        let plain_job = Job::new(serde_json::json!({ "drink": "mojito" }));
+        state.job_queue.lock().await.enqueue_many(vec![gpu_job.clone(), plain_job.clone()]).await.unwrap();
+
+        for (callback_url, tags, job) in [("http://localhost:9000/", "cpu", &plain_job), ("http://localhost:9001/", "cpu,gpu", &gpu_job)] {
+            let request = worker_request(callback_url, &[("cpee-tags", tags)]);
+            let response = register_worker(State(state.clone()), register_query(), request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+            assert_eq!(body["Job"]["id"], job.id.to_string());
+        }
+        // No further job is queued for a worker with the tags
+        let request = worker_request("http://localhost:9002/", &[("cpee-tags", "gpu")]);
+        let response = register_worker(State(state.clone()), register_query(), request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+    }
+
+    /// Registers a worker with the given callback URL, and returns the code of the error it was rejected with, if any.
+    /// A rejected worker must not have been queued.
+    async fn register_error(state: &AppState, callback_url: &str) -> Option<CallbackHeaderError> {
+        let response = register_worker(State(state.clone()), register_query(), worker_request(callback_url, &[])).await;
+        let err = response.err()?;
+        assert_eq!(err.clone().into_response().status(), StatusCode::BAD_REQUEST, "{callback_url}");
+        assert!(state.worker_queue.lock().await.find_by_id(Worker::new(callback_url, vec![]).id()).await.unwrap().is_none());
+        match err.code() {
+            ErrorCode::Callback(err) => Some(err),
+            code => panic!("unexpected error {code:?} for {callback_url}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_callback_urls_with_allowed_schemes_are_accepted() {
+        let mut state = crate::tests::state();
+        for callback_url in ["file:///etc/passwd", "ftp://localhost/jobs", "gopher://localhost:70/", "mailto:worker@localhost"] {
+            assert_eq!(register_error(&state, callback_url).await, Some(CallbackHeaderError::UnsupportedScheme));
+        }
+        assert_eq!(register_error(&state, "localhost:9000/jobs").await, Some(CallbackHeaderError::UnsupportedScheme));
+        assert_eq!(register_error(&state, "not a url").await, Some(CallbackHeaderError::NotAUrl));
+        for callback_url in ["http://localhost:9000/jobs", "https://localhost:9001/jobs", "HTTP://localhost:9002/jobs"] {
+            assert_eq!(register_error(&state, callback_url).await, None);
+        }
+
+        state.callback_schemes = ["https".to_owned()].into();
+        assert_eq!(register_error(&state, "http://localhost:9003/jobs").await, Some(CallbackHeaderError::UnsupportedScheme));
+        assert_eq!(register_error(&state, "https://localhost:9003/jobs").await, None);
+    }
+
+    #[tokio::test]
+    async fn callback_urls_with_denied_hosts_are_rejected() {
+        let mut state = crate::tests::state();
+        let deny = ["169.254.0.0/16", "10.0.0.0/8", "192.168.0.0/16", "internal.example"].map(|pattern| pattern.parse().unwrap());
+        state.callback_filter = Arc::new(CallbackFilter::new(Vec::new(), deny.into()));
+        for callback_url in ["http://169.254.169.254/latest/meta-data/", "http://10.0.0.1:9000/", "http://192.168.1.1/", "http://admin.internal.example/"] {
+            assert_eq!(register_error(&state, callback_url).await, Some(CallbackHeaderError::HostNotAllowed));
+        }
+        assert_eq!(register_error(&state, "http://192.0.2.1:9000/jobs").await, None);
+    }
+
+    #[tokio::test]
+    async fn callback_urls_longer_than_the_maximum_are_rejected() {
+        let mut state = crate::tests::state();
+        state.max_callback_url_length = 64;
+        let url_of_length = |length: usize| format!("http://localhost:9000/{}", "a".repeat(length - "http://localhost:9000/".len()));
+        assert_eq!(register_error(&state, &url_of_length(64)).await, None);
+        assert_eq!(register_error(&state, &url_of_length(65)).await, Some(CallbackHeaderError::TooLong));
+        // Heartbeats and deregistrations are checked as well
+        let err = worker_heartbeat(State(state.clone()), worker_request(&url_of_length(65), &[])).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Callback(CallbackHeaderError::TooLong));
+        let err = deregister_worker(State(state.clone()), worker_request(&url_of_length(65), &[])).await.unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Callback(CallbackHeaderError::TooLong));
+    }
+
+    #[tokio::test]
+    async fn waiting_workers_receive_jobs_submitted_in_the_meantime() {
+        let state = crate::tests::state();
+        // A worker without a callback URL receives the job in the response
+        let waiting = tokio::spawn(register_worker(State(state.clone()), Query(RegisterWorkerQuery { wait: Some(5) }), Request::new(Body::empty())));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        let query = Query(job::SubmitQuery { mode: job::SubmitMode::Queue, wait: None });
+        let (_, Json(submitted)) = job::submit_job(State(state.clone()), query, Json(serde_json::json!({ "drink": "mojito" }))).await.unwrap();
+        let job::SubmitJobResponse::Queued { id, .. } = submitted else { panic!("{submitted:?}") };
+
+        let response = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["Job"]["id"], id.to_string());
+        assert!(state.job_queue.lock().await.is_empty().await.unwrap());
+        assert_eq!(state.job_statuses.lock().await[&id].state, JobState::Assigned);
+        assert!(state.worker_queue.lock().await.is_empty().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn waiting_workers_are_queued_once_the_wait_elapsed() {
+        let state = crate::tests::state();
+        let started = Instant::now();
+        let request = worker_request("http://localhost:9000/", &[]);
+        let response = register_worker(State(state.clone()), Query(RegisterWorkerQuery { wait: Some(1) }), request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(state.worker_queue.lock().await.len().await.unwrap(), 1);
+
+        // A worker without a callback URL cannot be queued
+        let response = register_worker(State(state.clone()), Query(RegisterWorkerQuery { wait: Some(1) }), Request::new(Body::empty())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body: Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, "NoJobAvailable");
+        assert_eq!(state.worker_queue.lock().await.len().await.unwrap(), 1);
     }
 }